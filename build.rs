@@ -1,23 +1,58 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
 fn main() {
     // If you’re using static_vcruntime for Windows, keep it:
     static_vcruntime::metabuild();
 
-    // Emit some environment variables for your crate to read at runtime if desired
-    println!("cargo:rustc-env=CARGO_PKG_METADATA_PRECOMPILED_MODE=bind");
-    println!("cargo:rustc-env=CARGO_PKG_METADATA_PRECOMPILED_ADDRESS=127.0.0.1:8080");
-
-    // If you ALWAYS want to build in full-PTY mode, keep the line below:
-    println!("cargo:rustc-env=CARGO_PKG_METADATA_PRECOMPILED_FULLPTY=TRUE");
-
-    // If you want to conditionally set "FULLPTY" or not, you can read an env var here:
-    // e.g. read from your local environment:
-    let full_pty_str = std::env::var("CARGO_PKG_METADATA_PRECOMPILED_FULLPTY")
-        .unwrap_or_else(|_| "FALSE".to_string());
+    // Defaults baked into the binaries (see net_utils::cli): whether
+    // net_shell and net_mini_shell listen (`bind`, the default) or connect
+    // out (`connect`), and the address they connect to. Set either
+    // variable when building to change it.
+    let mode = env::var("CARGO_PKG_METADATA_PRECOMPILED_MODE").unwrap_or_else(|_| "bind".to_string());
+    if mode != "bind" && mode != "connect" {
+        panic!("CARGO_PKG_METADATA_PRECOMPILED_MODE must be bind or connect, not {}", mode);
+    }
+    let address = env::var("CARGO_PKG_METADATA_PRECOMPILED_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    println!("cargo:rustc-env=CARGO_PKG_METADATA_PRECOMPILED_MODE={}", mode);
+    println!("cargo:rustc-env=CARGO_PKG_METADATA_PRECOMPILED_ADDRESS={}", address);
 
-    // If the var is "TRUE", enable "full_pty" feature; otherwise "partial_pty".
-    if full_pty_str.eq_ignore_ascii_case("TRUE") {
+    // full_pty gives every net_shell connection the system login shell in a
+    // PTY; partial_pty (the default) runs net_shell's own shell and uses a
+    // PTY only for interactive commands. Pick it with `--features full_pty`
+    // or CARGO_PKG_METADATA_PRECOMPILED_FULLPTY=TRUE.
+    let full_pty = env::var_os("CARGO_FEATURE_FULL_PTY").is_some()
+        || env::var("CARGO_PKG_METADATA_PRECOMPILED_FULLPTY")
+            .map(|v| v.eq_ignore_ascii_case("TRUE"))
+            .unwrap_or(false);
+    println!(
+        "cargo:rustc-env=CARGO_PKG_METADATA_PRECOMPILED_FULLPTY={}",
+        if full_pty { "TRUE" } else { "FALSE" }
+    );
+    if full_pty {
         println!("cargo:rustc-cfg=feature=\"full_pty\"");
     } else {
         println!("cargo:rustc-cfg=feature=\"partial_pty\"");
     }
+
+    // Optional net_shell startup file baked into the binary. Point
+    // CARGO_PKG_METADATA_PRECOMPILED_RCFILE at a script to embed it;
+    // otherwise an empty file is embedded and nothing runs.
+    let rc_contents = match env::var("CARGO_PKG_METADATA_PRECOMPILED_RCFILE") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("cannot read rc file {}: {}", path, e))
+        }
+        Err(_) => String::new(),
+    };
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("net_shell_rc.sh"), rc_contents).unwrap();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=CARGO_PKG_METADATA_PRECOMPILED_MODE");
+    println!("cargo:rerun-if-env-changed=CARGO_PKG_METADATA_PRECOMPILED_ADDRESS");
+    println!("cargo:rerun-if-env-changed=CARGO_PKG_METADATA_PRECOMPILED_FULLPTY");
+    println!("cargo:rerun-if-env-changed=CARGO_PKG_METADATA_PRECOMPILED_RCFILE");
 }
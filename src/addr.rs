@@ -0,0 +1,299 @@
+// src/addr.rs
+//! Addresses as every binary takes them: `host:port`, where the host is a
+//! name, an IPv4 address or a bracketed IPv6 address (`[::1]:8080`).
+//! Connects try everything a name resolves to in happy-eyeballs order
+//! (RFC 8305), each attempt with its own timeout. Binding to `::`, `*` or
+//! an empty host (`:8080`) listens on IPv4 and IPv6 at once.
+
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+
+/// How long one connect attempt runs alone before the next one starts.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Splits `address` into its host, without brackets, and its port, if it
+/// has one. A bare IPv6 address (`::1`) is all host.
+pub fn split_host_port(address: &str) -> (&str, Option<&str>) {
+    let address = address.trim();
+    if let Some(rest) = address.strip_prefix('[') {
+        if let Some((host, after)) = rest.split_once(']') {
+            return (host, after.strip_prefix(':'));
+        }
+    }
+    match address.rsplit_once(':') {
+        Some((host, _)) if host.contains(':') => (address, None),
+        Some((host, port)) => (host, Some(port)),
+        None => (address, None),
+    }
+}
+
+/// `host:port`, with the host bracketed if it is an IPv6 address.
+pub fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Host and port of `address`, or an error naming what is missing.
+fn host_and_port(address: &str) -> io::Result<(&str, u16)> {
+    let (host, port) = split_host_port(address);
+    let port = port.ok_or_else(|| invalid(address, "missing port"))?;
+    let port = port.parse().map_err(|_| invalid(address, &format!("invalid port '{}'", port)))?;
+    Ok((host, port))
+}
+
+fn invalid(address: &str, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", address, what))
+}
+
+/// Every socket address `address` resolves to, in resolver order.
+pub fn resolve(address: &str) -> io::Result<Vec<SocketAddr>> {
+    let (host, port) = host_and_port(address)?;
+    if host.is_empty() {
+        return Err(invalid(address, "missing host"));
+    }
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", address, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no addresses found", address)));
+    }
+    Ok(addrs)
+}
+
+/// Interleaves the address families, starting with the resolver's first
+/// choice, so one unreachable family cannot hold up the other.
+pub fn happy_eyeballs_order(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first().copied() else {
+        return addrs;
+    };
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6() == first.is_ipv6());
+    preferred.reverse();
+    other.reverse();
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop());
+        ordered.extend(other.pop());
+    }
+    ordered
+}
+
+/// Connects to `address`. Its addresses are tried in happy-eyeballs
+/// order: a new attempt starts when the previous one fails or has run for
+/// `ATTEMPT_DELAY`, and the first to connect wins. Each attempt gives up
+/// after `timeout`.
+pub fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let addrs = happy_eyeballs_order(resolve(address)?);
+    if let [addr] = addrs[..] {
+        return TcpStream::connect_timeout(&addr, timeout);
+    }
+
+    let (results, attempts) = mpsc::channel();
+    let mut waiting = addrs.into_iter();
+    let mut running = 0;
+    let mut last_err = None;
+    loop {
+        if let Some(addr) = waiting.next() {
+            debug!("Trying {}", addr);
+            let results = results.clone();
+            // Attempts that lose the race finish on their own and are dropped
+            thread::spawn(move || {
+                let _ = results.send((addr, TcpStream::connect_timeout(&addr, timeout)));
+            });
+            running += 1;
+        }
+        let result = if waiting.len() > 0 {
+            match attempts.recv_timeout(ATTEMPT_DELAY) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else if running > 0 {
+            match attempts.recv() {
+                Ok(result) => result,
+                Err(_) => break,
+            }
+        } else {
+            break;
+        };
+        running -= 1;
+        match result {
+            (addr, Ok(stream)) => {
+                debug!("Connected to {}", addr);
+                return Ok(stream);
+            }
+            (addr, Err(e)) => {
+                debug!("{}: {}", addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}: no addresses found", address))))
+}
+
+/// Host that means every address of both families.
+fn is_any_host(host: &str) -> bool {
+    matches!(host, "" | "*" | "::")
+}
+
+/// Listens on `address`; see the module docs for dual-stack binds. A
+/// name is bound at the first of its addresses that works.
+pub fn bind(address: &str) -> io::Result<TcpListener> {
+    let (host, port) = host_and_port(address)?;
+    if is_any_host(host) {
+        #[cfg(unix)]
+        let dual = dual_stack(port, SocketKind::Stream).map(TcpListener::from);
+        #[cfg(not(unix))]
+        let dual = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port));
+        return dual.or_else(|e| {
+            debug!("No dual-stack socket ({}); listening on IPv4 only", e);
+            TcpListener::bind(("0.0.0.0", port))
+        });
+    }
+    TcpListener::bind(&resolve(address)?[..])
+}
+
+/// `listener.accept()`, with IPv4 clients of a dual-stack listener shown
+/// as IPv4 rather than as IPv4-mapped IPv6 addresses.
+pub fn accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+    let (stream, peer) = listener.accept()?;
+    Ok((stream, SocketAddr::new(peer.ip().to_canonical(), peer.port())))
+}
+
+/// `bind` for UDP.
+pub fn bind_udp(address: &str) -> io::Result<UdpSocket> {
+    let (host, port) = host_and_port(address)?;
+    if is_any_host(host) {
+        #[cfg(unix)]
+        let dual = dual_stack(port, SocketKind::Datagram).map(UdpSocket::from);
+        #[cfg(not(unix))]
+        let dual = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port));
+        return dual.or_else(|e| {
+            debug!("No dual-stack socket ({}); listening on IPv4 only", e);
+            UdpSocket::bind(("0.0.0.0", port))
+        });
+    }
+    UdpSocket::bind(&resolve(address)?[..])
+}
+
+/// The address of `address` that `socket` can send to: one of its own
+/// family, or on an IPv6 socket an IPv4 address in mapped form, the way
+/// a dual-stack socket reports IPv4 peers.
+pub fn udp_peer(socket: &UdpSocket, address: &str) -> io::Result<SocketAddr> {
+    let addrs = resolve(address)?;
+    let unreachable = || io::Error::new(io::ErrorKind::AddrNotAvailable, format!("{}: no address of the listening socket's family", address));
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => addrs.into_iter().find(SocketAddr::is_ipv4).ok_or_else(unreachable),
+        SocketAddr::V6(_) => Ok(match addrs[0] {
+            SocketAddr::V4(v4) => SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0)),
+            v6 => v6,
+        }),
+    }
+}
+
+#[cfg(unix)]
+#[derive(Clone, Copy, PartialEq)]
+enum SocketKind {
+    Stream,
+    Datagram,
+}
+
+/// An IPv6 socket bound to every address with `IPV6_V6ONLY` off, so it
+/// also takes IPv4, and listening if it is a stream socket. Elsewhere `::`
+/// is bound with the system's default, which may be IPv6 only.
+#[cfg(unix)]
+fn dual_stack(port: u16, kind: SocketKind) -> io::Result<std::os::fd::OwnedFd> {
+    use nix::libc;
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let ty = match kind {
+        SocketKind::Stream => libc::SOCK_STREAM,
+        SocketKind::Datagram => libc::SOCK_DGRAM,
+    };
+    // Close-on-exec from the start, so a fork and exec elsewhere in the
+    // process cannot inherit the socket; set afterwards where unsupported.
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let ty = ty | libc::SOCK_CLOEXEC;
+    let check = |rc: libc::c_int| if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(rc) };
+    let fd = unsafe { OwnedFd::from_raw_fd(check(libc::socket(libc::AF_INET6, ty, 0))?) };
+    let set = |level, name, value: libc::c_int| {
+        let size = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        check(unsafe { libc::setsockopt(fd.as_raw_fd(), level, name, &value as *const libc::c_int as *const libc::c_void, size) })
+    };
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    check(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    set(libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 0)?;
+    if kind == SocketKind::Stream {
+        // As std does for its listeners
+        set(libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    }
+
+    let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    addr.sin6_port = port.to_be();
+    addr.sin6_addr.s6_addr = Ipv6Addr::UNSPECIFIED.octets();
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+    {
+        addr.sin6_len = mem::size_of::<libc::sockaddr_in6>() as u8;
+    }
+    let size = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    check(unsafe { libc::bind(fd.as_raw_fd(), &addr as *const libc::sockaddr_in6 as *const libc::sockaddr, size) })?;
+    if kind == SocketKind::Stream {
+        check(unsafe { libc::listen(fd.as_raw_fd(), 128) })?;
+    }
+    Ok(fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_hosts_and_ports() {
+        assert_eq!(split_host_port("example.com:80"), ("example.com", Some("80")));
+        assert_eq!(split_host_port(" 10.0.0.1:8080 "), ("10.0.0.1", Some("8080")));
+        assert_eq!(split_host_port("[::1]:22"), ("::1", Some("22")));
+        assert_eq!(split_host_port("[fe80::1%eth0]:22"), ("fe80::1%eth0", Some("22")));
+        // missing port
+        assert_eq!(split_host_port("[::1]"), ("::1", None));
+        assert_eq!(split_host_port("example.com:"), ("example.com", Some("")));
+        // bare hosts, IPv6 ones included
+        assert_eq!(split_host_port("example.com"), ("example.com", None));
+        assert_eq!(split_host_port("::1"), ("::1", None));
+        assert_eq!(split_host_port("2001:db8::5"), ("2001:db8::5", None));
+
+        assert_eq!(join_host_port("::1", 22), "[::1]:22");
+        assert_eq!(join_host_port("example.com", 80), "example.com:80");
+        assert!(host_and_port("example.com").is_err());
+        assert!(host_and_port("example.com:http").is_err());
+    }
+
+    #[test]
+    fn happy_eyeballs_alternates_families_from_the_first() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = happy_eyeballs_order(addrs.clone()).iter().map(ToString::to_string).collect();
+        assert_eq!(ordered, ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]);
+
+        // the resolver's first family leads
+        let mut v4_first = addrs;
+        v4_first.rotate_left(3);
+        let ordered: Vec<String> = happy_eyeballs_order(v4_first).iter().map(ToString::to_string).collect();
+        assert_eq!(ordered, ["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "[::2]:1", "[::3]:1"]);
+
+        let single: Vec<SocketAddr> = vec!["127.0.0.1:1".parse().unwrap()];
+        assert_eq!(happy_eyeballs_order(single.clone()), single);
+        assert!(happy_eyeballs_order(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,305 @@
+// src/audit.rs
+//! Structured audit log for the shell side (net_shell, net_mini): one JSON
+//! object per line for every command run and every session event, with the
+//! time, peer, argv, cwd, exit status, duration and bytes transferred.
+//! The log is rotated by size: `audit.log` becomes `audit.log.1`, which
+//! becomes `audit.log.2`, and so on up to `AUDIT_LOG_KEEP` files. Several
+//! processes may share one log (net_shell forks per connection in bind
+//! mode); on Unix they take turns through an advisory lock on the file.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::common::{json_string, utc_timestamp};
+
+/// File to append audit records to; unset for no audit log.
+pub const AUDIT_LOG_ENV_VAR: &str = "AUDIT_LOG";
+/// Size in bytes at which the log is rotated.
+pub const AUDIT_LOG_MAX_BYTES_ENV_VAR: &str = "AUDIT_LOG_MAX_BYTES";
+/// Rotated files to keep besides the current one.
+pub const AUDIT_LOG_KEEP_ENV_VAR: &str = "AUDIT_LOG_KEEP";
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
+
+/// One line of the audit log. Fields left as `None` (or empty) are omitted.
+#[derive(Debug, Clone, Default)]
+pub struct AuditRecord {
+    /// `command`, `session_start`, `attach`, `detach` or `session_end`.
+    pub event: &'static str,
+    /// The program writing the record, such as `net_shell`.
+    pub tool: &'static str,
+    /// Address of the other end of the connection.
+    pub peer: Option<String>,
+    /// Session token, where sessions have one.
+    pub session: Option<String>,
+    /// The command run: one argv per pipeline stage.
+    pub argv: Vec<Vec<String>>,
+    pub cwd: Option<PathBuf>,
+    /// Exit status; 128 + n for a command killed by signal n.
+    pub status: Option<i32>,
+    pub duration: Option<Duration>,
+    /// Bytes received from and sent to the peer.
+    pub bytes_in: Option<u64>,
+    pub bytes_out: Option<u64>,
+}
+
+impl AuditRecord {
+    /// A record of `event` by `tool`, to be filled in.
+    pub fn new(tool: &'static str, event: &'static str) -> Self {
+        AuditRecord { tool, event, ..AuditRecord::default() }
+    }
+
+    /// The record as a single line of JSON, without the newline.
+    pub fn to_json(&self) -> String {
+        let mut fields = vec![
+            format!("\"time\": {}", json_string(&utc_timestamp())),
+            format!("\"tool\": {}", json_string(self.tool)),
+            format!("\"pid\": {}", std::process::id()),
+            format!("\"event\": {}", json_string(self.event)),
+        ];
+        if let Some(peer) = &self.peer {
+            fields.push(format!("\"peer\": {}", json_string(peer)));
+        }
+        if let Some(session) = &self.session {
+            fields.push(format!("\"session\": {}", json_string(session)));
+        }
+        if !self.argv.is_empty() {
+            let stages: Vec<String> = self
+                .argv
+                .iter()
+                .map(|argv| format!("[{}]", argv.iter().map(|a| json_string(a)).collect::<Vec<_>>().join(", ")))
+                .collect();
+            fields.push(format!("\"argv\": [{}]", stages.join(", ")));
+        }
+        if let Some(cwd) = &self.cwd {
+            fields.push(format!("\"cwd\": {}", json_string(&cwd.to_string_lossy())));
+        }
+        if let Some(status) = self.status {
+            fields.push(format!("\"status\": {}", status));
+        }
+        if let Some(duration) = self.duration {
+            fields.push(format!("\"duration\": {:.3}", duration.as_secs_f64()));
+        }
+        if let Some(bytes) = self.bytes_in {
+            fields.push(format!("\"bytes_in\": {}", bytes));
+        }
+        if let Some(bytes) = self.bytes_out {
+            fields.push(format!("\"bytes_out\": {}", bytes));
+        }
+        format!("{{{}}}", fields.join(", "))
+    }
+}
+
+/// An append-only JSON-lines file, rotated once it reaches `max_bytes`.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    /// The file as last opened; another process may have rotated it away.
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the log at `path` for appending.
+    pub fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { path: path.to_path_buf(), max_bytes, keep, file: Mutex::new(file) })
+    }
+
+    /// The log named by `AUDIT_LOG`, sized by `AUDIT_LOG_MAX_BYTES` and
+    /// `AUDIT_LOG_KEEP`. Failures are reported and logging skipped.
+    pub fn from_env() -> Option<AuditLog> {
+        let path = PathBuf::from(env::var_os(AUDIT_LOG_ENV_VAR)?);
+        let max_bytes = env_number(AUDIT_LOG_MAX_BYTES_ENV_VAR).filter(|&n| n > 0).unwrap_or(DEFAULT_MAX_BYTES);
+        let keep = env_number(AUDIT_LOG_KEEP_ENV_VAR).map_or(DEFAULT_KEEP, |n| n as usize);
+        match AuditLog::open(&path, max_bytes, keep) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                log::error!("Cannot open audit log {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Appends `record`, rotating first if it would take the file past the
+    /// size limit. The size is read from the file each time, as other
+    /// processes append to it too.
+    pub fn write(&self, record: &AuditRecord) -> io::Result<()> {
+        let line = format!("{}\n", record.to_json());
+        let mut file = self.file.lock().unwrap();
+        let mut lock = self.lock_current(&mut file)?;
+        let size = file.metadata()?.len();
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            // writers waiting on the old file find it moved once it is unlocked
+            *file = self.rotate()?;
+            lock = lock_file(&file)?;
+        }
+        file.write_all(line.as_bytes())?;
+        drop(lock);
+        Ok(())
+    }
+
+    /// Locks the file at `path`, first reopening `file` if another
+    /// process has rotated the one it had open.
+    fn lock_current(&self, file: &mut File) -> io::Result<FileLock> {
+        loop {
+            let lock = lock_file(file)?;
+            if is_same_file(file, &self.path) {
+                return Ok(lock);
+            }
+            *file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+    }
+
+    /// Shifts `path.N` to `path.N+1` (dropping the oldest), moves the
+    /// current file to `path.1` and returns a fresh one.
+    fn rotate(&self) -> io::Result<File> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        OpenOptions::new().create(true).append(true).open(&self.path)
+    }
+}
+
+/// An exclusive advisory lock on the log file, released when dropped.
+#[cfg(unix)]
+type FileLock = nix::fcntl::Flock<File>;
+#[cfg(not(unix))]
+type FileLock = ();
+
+#[cfg(unix)]
+fn lock_file(file: &File) -> io::Result<FileLock> {
+    nix::fcntl::Flock::lock(file.try_clone()?, nix::fcntl::FlockArg::LockExclusive).map_err(|(_, e)| io::Error::from(e))
+}
+
+#[cfg(not(unix))]
+fn lock_file(_file: &File) -> io::Result<FileLock> {
+    Ok(())
+}
+
+/// Whether `file` is still the file at `path`.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+static GLOBAL: OnceLock<Option<AuditLog>> = OnceLock::new();
+
+/// The process-wide log from `AUDIT_LOG`, opened on first use.
+pub fn global() -> Option<&'static AuditLog> {
+    GLOBAL.get_or_init(AuditLog::from_env).as_ref()
+}
+
+/// Writes `record` to the process-wide log, if there is one. A failed
+/// write is reported but never ends the session being audited.
+pub fn record(record: AuditRecord) {
+    if let Some(log) = global() {
+        if let Err(e) = log.write(&record) {
+            log::error!("Cannot write audit log {}: {}", log.path.display(), e);
+        }
+    }
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    env::var(name).ok().and_then(|v| v.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_log(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("audit_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("audit.log")
+    }
+
+    fn command(n: usize) -> AuditRecord {
+        AuditRecord { argv: vec![vec!["echo".to_string(), n.to_string()]], ..AuditRecord::new("test", "command") }
+    }
+
+    #[test]
+    fn record_is_one_json_line() {
+        let record = AuditRecord {
+            peer: Some("127.0.0.1:9".to_string()),
+            argv: vec![vec!["grep".to_string(), "a\"b".to_string()], vec!["wc".to_string()]],
+            status: Some(1),
+            bytes_in: Some(0),
+            bytes_out: Some(12),
+            ..AuditRecord::new("net_shell", "command")
+        };
+        let json = record.to_json();
+        assert!(!json.contains('\n'));
+        assert!(json.contains(r#""event": "command""#));
+        assert!(json.contains(r#""argv": [["grep", "a\"b"], ["wc"]]"#));
+        assert!(json.contains(r#""status": 1, "bytes_in": 0, "bytes_out": 12"#));
+        assert!(!json.contains("cwd"));
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let path = scratch_log("rotate");
+        let log = AuditLog::open(&path, 400, 2).unwrap();
+        for n in 0..30 {
+            log.write(&command(n)).unwrap();
+        }
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".3");
+        assert!(!Path::new(&rotated).exists());
+        for file in [path.clone(), path.with_extension("log.1"), path.with_extension("log.2")] {
+            let size = fs::metadata(&file).unwrap().len();
+            assert!(size > 0 && size <= 400, "{}: {} bytes", file.display(), size);
+        }
+    }
+
+    #[test]
+    fn logs_shared_by_processes_lose_nothing() {
+        // two handles on one path, as two forked processes would have
+        let path = scratch_log("shared");
+        let a = AuditLog::open(&path, 500, 100).unwrap();
+        let b = AuditLog::open(&path, 500, 100).unwrap();
+        for n in 0..200 {
+            let log = if n % 3 == 0 { &a } else { &b };
+            log.write(&command(n)).unwrap();
+        }
+
+        let mut lines = Vec::new();
+        for entry in fs::read_dir(path.parent().unwrap()).unwrap() {
+            let file = entry.unwrap().path();
+            let text = fs::read_to_string(&file).unwrap();
+            assert!(text.len() <= 500, "{}: {} bytes", file.display(), text.len());
+            lines.extend(text.lines().map(str::to_string));
+        }
+        assert_eq!(lines.len(), 200);
+        for n in 0..200 {
+            assert!(lines.iter().any(|l| l.contains(&format!(r#"["echo", "{}"]"#, n))), "record {} lost", n);
+        }
+    }
+}
@@ -0,0 +1,553 @@
+// src/cli.rs
+//! Command-line parsing shared by the binaries, with help text and an
+//! optional config file. Every option can come from (highest first) the
+//! command line, its environment variable, the config file or its
+//! built-in default.
+//!
+//! The config file (`--config` or `NET_UTILS_CONFIG`) is a small subset
+//! of TOML: `key = value` lines where keys are long option names, values
+//! are strings, numbers or booleans, and `[<program>]` sections hold
+//! settings for one program that win over the top-level ones:
+//!
+//! ```toml
+//! log-level = "debug"
+//!
+//! [net_shell]
+//! address = "10.0.0.5:8080"
+//! connect-timeout = 5000
+//! ```
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use log::LevelFilter;
+
+use crate::addr::{join_host_port, split_host_port};
+use crate::logging::{self, LogOptions, LOG_FILE_ENV_VAR, LOG_LEVEL_ENV_VAR, LOG_TIMESTAMPS_ENV_VAR};
+
+/// Config file to read when `--config` is not given.
+pub const CONFIG_ENV_VAR: &str = "NET_UTILS_CONFIG";
+/// Overrides the built-in mode of the binaries that have one.
+pub const MODE_ENV_VAR: &str = "NET_UTILS_MODE";
+
+/// `bind` or `connect`, fixed when building (see build.rs).
+pub const PRECOMPILED_MODE: &str = env!("CARGO_PKG_METADATA_PRECOMPILED_MODE");
+/// Default address to connect to, fixed when building.
+pub const PRECOMPILED_ADDRESS: &str = env!("CARGO_PKG_METADATA_PRECOMPILED_ADDRESS");
+/// Default address to listen on: every interface, so the other side can
+/// reach it.
+pub const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:8080";
+
+/// Listen for the other side, or connect out to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Bind,
+    Connect,
+}
+
+impl Mode {
+    /// Where a binary that can do either listens or connects by default.
+    pub fn default_address(self) -> &'static str {
+        match self {
+            Mode::Bind => DEFAULT_LISTEN_ADDRESS,
+            Mode::Connect => PRECOMPILED_ADDRESS,
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "bind" | "listen" => Ok(Mode::Bind),
+            "connect" => Ok(Mode::Connect),
+            other => Err(format!("unknown mode {}", other)),
+        }
+    }
+}
+
+/// `--mode`, defaulting to the mode built in.
+pub const MODE_OPT: Opt = Opt::value("mode", "MODE", "bind: listen for connections; connect: connect out")
+    .short('m')
+    .env(MODE_ENV_VAR)
+    .default(PRECOMPILED_MODE);
+
+/// One option: `--long`, maybe `-s`, either a switch or taking a value.
+#[derive(Debug, Clone, Copy)]
+pub struct Opt {
+    pub long: &'static str,
+    pub short: Option<char>,
+    /// Placeholder for the value in help (`ADDR`); `None` for a switch.
+    pub value: Option<&'static str>,
+    pub env: Option<&'static str>,
+    pub default: Option<&'static str>,
+    pub help: &'static str,
+}
+
+impl Opt {
+    /// A switch, such as `--one-shot`.
+    pub const fn switch(long: &'static str, help: &'static str) -> Opt {
+        Opt { long, short: None, value: None, env: None, default: None, help }
+    }
+
+    /// An option taking a value, such as `--address <ADDR>`.
+    pub const fn value(long: &'static str, value: &'static str, help: &'static str) -> Opt {
+        Opt { long, short: None, value: Some(value), env: None, default: None, help }
+    }
+
+    pub const fn short(self, short: char) -> Opt {
+        Opt { short: Some(short), ..self }
+    }
+
+    pub const fn env(self, var: &'static str) -> Opt {
+        Opt { env: Some(var), ..self }
+    }
+
+    pub const fn default(self, default: &'static str) -> Opt {
+        Opt { default: Some(default), ..self }
+    }
+}
+
+/// Logging options every binary takes.
+pub const LOGGING_OPTS: &[Opt] = &[
+    Opt::switch("verbose", "More detail; repeat for more (-vv)").short('v'),
+    Opt::switch("quiet", "Less detail; repeat for less (-qq)").short('q'),
+    Opt::value("log-level", "LEVEL", "off, error, warn, info, debug or trace").env(LOG_LEVEL_ENV_VAR).default("info"),
+    Opt::value("log-file", "FILE", "Append log lines to FILE instead of stderr").env(LOG_FILE_ENV_VAR),
+    Opt::value("log-timestamps", "BOOL", "Start log lines with the time (default: only in a log file)")
+        .env(LOG_TIMESTAMPS_ENV_VAR),
+];
+
+const HELP_OPT: Opt = Opt::switch("help", "Print this help").short('h');
+const CONFIG_OPT: Opt = Opt::value("config", "FILE", "Read settings from a TOML file").short('c').env(CONFIG_ENV_VAR);
+
+/// A program's options and help text.
+#[derive(Debug, Clone)]
+pub struct Cli {
+    name: &'static str,
+    about: &'static str,
+    /// Positional arguments, as shown in the usage line.
+    positional: &'static str,
+    opts: Vec<Opt>,
+}
+
+impl Cli {
+    /// A program called `name` with the help, config and logging options.
+    pub fn new(name: &'static str, about: &'static str) -> Self {
+        let mut opts = vec![HELP_OPT, CONFIG_OPT];
+        opts.extend_from_slice(LOGGING_OPTS);
+        Cli { name, about, positional: "", opts }
+    }
+
+    /// Shows `usage` (such as `<file.cast>`) for positional arguments and
+    /// accepts them.
+    pub fn positional(mut self, usage: &'static str) -> Self {
+        self.positional = usage;
+        self
+    }
+
+    /// Adds program options, listed in help in this order.
+    pub fn opts(mut self, opts: &[Opt]) -> Self {
+        self.opts.extend_from_slice(opts);
+        self
+    }
+
+    pub fn usage(&self) -> String {
+        let mut usage = format!("Usage: {} [OPTIONS]", self.name);
+        if !self.positional.is_empty() {
+            usage.push(' ');
+            usage.push_str(self.positional);
+        }
+        usage
+    }
+
+    pub fn help(&self) -> String {
+        let mut help = format!("{} - {}\n\n{}\n\nOptions:\n", self.name, self.about, self.usage());
+        let names: Vec<String> = self
+            .opts
+            .iter()
+            .map(|opt| {
+                let short = opt.short.map_or("    ".to_string(), |c| format!("-{}, ", c));
+                let value = opt.value.map_or(String::new(), |v| format!(" <{}>", v));
+                format!("{}--{}{}", short, opt.long, value)
+            })
+            .collect();
+        let width = names.iter().map(String::len).max().unwrap_or(0);
+        for (opt, name) in self.opts.iter().zip(&names) {
+            help.push_str(&format!("  {:<width$}  {}", name, opt.help, width = width));
+            if let Some(var) = opt.env {
+                help.push_str(&format!(" [env: {}]", var));
+            }
+            if let Some(default) = opt.default {
+                help.push_str(&format!(" [default: {}]", default));
+            }
+            help.push('\n');
+        }
+        help.push_str(&format!(
+            "\nPrecedence: options, then environment, then the config file ([{}] section first), then defaults.\n",
+            self.name
+        ));
+        help
+    }
+
+    /// Parses `args` (without the program name) and reads the config file.
+    pub fn parse<I: IntoIterator<Item = String>>(&self, args: I) -> Result<Matches, String> {
+        let mut given: HashMap<&'static str, Vec<String>> = HashMap::new();
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positional.extend(args.by_ref());
+                break;
+            }
+            if let Some(long) = arg.strip_prefix("--") {
+                let (name, inline) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (long, None),
+                };
+                let opt = self.find(|o| o.long == name).ok_or(format!("unknown option --{}", name))?;
+                let value = match (opt.value, inline) {
+                    (None, None) => String::new(),
+                    (None, Some(_)) => return Err(format!("--{} takes no value", name)),
+                    (Some(_), Some(value)) => value,
+                    (Some(_), None) => args.next().ok_or(format!("--{} needs a value", name))?,
+                };
+                given.entry(opt.long).or_default().push(value);
+            } else if arg.len() > 1 && arg.starts_with('-') {
+                // Clustered short options: -vv, -qa ADDR, -aADDR
+                for (i, c) in arg[1..].char_indices() {
+                    let opt = self.find(|o| o.short == Some(c)).ok_or(format!("unknown option -{}", c))?;
+                    if opt.value.is_none() {
+                        given.entry(opt.long).or_default().push(String::new());
+                        continue;
+                    }
+                    let rest = &arg[1 + i + c.len_utf8()..];
+                    let value = if rest.is_empty() { args.next().ok_or(format!("-{} needs a value", c))? } else { rest.to_string() };
+                    given.entry(opt.long).or_default().push(value);
+                    break;
+                }
+            } else if self.positional.is_empty() {
+                return Err(format!("unexpected argument: {}", arg));
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        let mut matches = Matches { opts: self.opts.clone(), given, config: HashMap::new(), positional };
+        if let Some(path) = matches.value("config") {
+            matches.config = load_config(Path::new(&path), self.name)?;
+        }
+        Ok(matches)
+    }
+
+    /// Parses the process arguments. Prints help and exits for `--help`;
+    /// prints the error and usage and exits with status 2 on bad usage.
+    pub fn parse_env_or_exit(&self) -> Matches {
+        match self.parse(env::args().skip(1)) {
+            Ok(matches) if matches.is_set("help") => {
+                print!("{}", self.help());
+                process::exit(0);
+            }
+            Ok(matches) => matches,
+            Err(e) => {
+                eprintln!("{}: {}", self.name, e);
+                eprintln!("{}", self.usage());
+                eprintln!("Try '{} --help' for more information.", self.name);
+                process::exit(2);
+            }
+        }
+    }
+
+    fn find<P: Fn(&Opt) -> bool>(&self, pred: P) -> Option<&Opt> {
+        self.opts.iter().find(|o| pred(o))
+    }
+}
+
+/// Parsed options, resolved on lookup: command line, environment, config
+/// file, default.
+#[derive(Debug, Clone)]
+pub struct Matches {
+    opts: Vec<Opt>,
+    /// Values given on the command line, in order; empty for switches.
+    given: HashMap<&'static str, Vec<String>>,
+    /// Settings from the config file, by option name.
+    config: HashMap<String, String>,
+    positional: Vec<String>,
+}
+
+impl Matches {
+    /// The value of `--long`, from wherever it was set.
+    pub fn value(&self, long: &str) -> Option<String> {
+        let opt = self.opt(long);
+        if let Some(value) = self.given.get(opt.long).and_then(|v| v.last()) {
+            return Some(value.clone());
+        }
+        if let Some(value) = opt.env.and_then(|var| env::var(var).ok()).filter(|v| !v.is_empty()) {
+            return Some(value);
+        }
+        if let Some(value) = self.config.get(opt.long) {
+            return Some(value.clone());
+        }
+        opt.default.map(String::from)
+    }
+
+    /// `value` parsed as `T`; exits with a message if it does not parse.
+    pub fn get<T: FromStr>(&self, long: &str) -> Option<T> {
+        let value = self.value(long)?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => usage_error(&format!("invalid value for --{}: '{}'", long, value)),
+        }
+    }
+
+    /// True if the switch `--long` was given, or its variable or config
+    /// setting is true.
+    pub fn is_set(&self, long: &str) -> bool {
+        if self.given.contains_key(self.opt(long).long) {
+            return true;
+        }
+        self.value(long).is_some_and(|v| match parse_bool(&v) {
+            Some(set) => set,
+            None => usage_error(&format!("invalid value for --{}: '{}'", long, v)),
+        })
+    }
+
+    /// How many times `--long` was given on the command line.
+    pub fn count(&self, long: &str) -> usize {
+        self.given.get(self.opt(long).long).map_or(0, Vec::len)
+    }
+
+    /// `--address` with its port replaced by `--port`, if that is set.
+    pub fn address(&self) -> Option<String> {
+        self.value("address").map(|address| self.address_or(&address))
+    }
+
+    /// `address`, with `default` for an address set nowhere.
+    pub fn address_or(&self, default: &str) -> String {
+        let address = self.value("address").unwrap_or_else(|| default.to_string());
+        match self.get::<u16>("port") {
+            Some(port) => with_port(&address, port),
+            None => address,
+        }
+    }
+
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    /// Installs the logger from the logging options, tagging lines with
+    /// `tag` (such as `(shell)`).
+    pub fn init_logging(&self, tag: &str) {
+        let mut options = LogOptions {
+            level: self.value("log-level").map_or(LevelFilter::Info, |v| match logging::parse_level(&v) {
+                Some(level) => level,
+                None => usage_error(&format!("invalid log level '{}'", v)),
+            }),
+            file: self.value("log-file").map(PathBuf::from),
+            timestamps: self.value("log-timestamps").map(|v| match parse_bool(&v) {
+                Some(timestamps) => timestamps,
+                None => usage_error(&format!("invalid value for --log-timestamps: '{}'", v)),
+            }),
+        };
+        options.adjust(self.count("verbose") as i32 - self.count("quiet") as i32);
+        if let Err(e) = logging::init(tag, &options) {
+            let path = options.file.take().unwrap_or_default();
+            let _ = logging::init(tag, &options);
+            log::warn!("Cannot open log file {}: {}; logging to stderr", path.display(), e);
+        }
+    }
+
+    fn opt(&self, long: &str) -> &Opt {
+        self.opts
+            .iter()
+            .find(|o| o.long == long)
+            .unwrap_or_else(|| panic!("option --{} is not declared", long))
+    }
+}
+
+/// Reports a usage problem found after parsing and exits with status 2.
+pub fn usage_error(message: &str) -> ! {
+    let name = env::args().next().map(|p| {
+        Path::new(&p).file_name().map_or(p.clone(), |n| n.to_string_lossy().into_owned())
+    });
+    eprintln!("{}: {}", name.unwrap_or_default(), message);
+    process::exit(2);
+}
+
+/// `address` (`host:port`, or a comma-separated list of them) with every
+/// port replaced by `port`. Hosts without a port get one.
+pub fn with_port(address: &str, port: u16) -> String {
+    address
+        .split(',')
+        .map(|a| join_host_port(split_host_port(a).0, port))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Reads the top-level settings of the config file at `path`, overridden
+/// by those in its `[program]` section. Other sections are ignored.
+fn load_config(path: &Path, program: &str) -> Result<HashMap<String, String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read config {}: {}", path.display(), e))?;
+    let mut global = HashMap::new();
+    let mut own = HashMap::new();
+    let mut section: Option<String> = None;
+    for (number, line) in text.lines().enumerate() {
+        let fail = |what: &str| format!("{}:{}: {}", path.display(), number + 1, what);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name.split('#').next().unwrap_or("").trim_end();
+            let name = name.strip_suffix(']').ok_or_else(|| fail("unterminated section name"))?;
+            section = Some(name.trim().trim_matches('"').to_string());
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| fail("expected key = value"))?;
+        let key = key.trim().trim_matches('"').replace('_', "-");
+        let value = parse_toml_value(value.trim()).ok_or_else(|| fail("bad value"))?;
+        match &section {
+            None => global.insert(key, value),
+            Some(name) if name == program => own.insert(key, value),
+            Some(_) => None,
+        };
+    }
+    global.extend(own);
+    Ok(global)
+}
+
+/// A TOML string, number or boolean, followed by an optional comment.
+fn parse_toml_value(s: &str) -> Option<String> {
+    let (value, rest) = if let Some(body) = s.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = body.char_indices();
+        loop {
+            let (i, c) = chars.next()?;
+            match c {
+                '"' => break (out, &body[i + 1..]),
+                '\\' => match chars.next()?.1 {
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    'r' => out.push('\r'),
+                    other => out.push(other),
+                },
+                c => out.push(c),
+            }
+        }
+    } else if let Some(body) = s.strip_prefix('\'') {
+        let end = body.find('\'')?;
+        (body[..end].to_string(), &body[end + 1..])
+    } else {
+        let end = s.find('#').unwrap_or(s.len());
+        let bare = s[..end].trim();
+        let ok = bare == "true" || bare == "false" || bare.replace('_', "").parse::<f64>().is_ok();
+        if !ok {
+            return None;
+        }
+        (bare.replace('_', ""), "")
+    };
+    let rest = rest.trim();
+    (rest.is_empty() || rest.starts_with('#')).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `text` to a config file of its own and returns its path.
+    fn scratch_config(name: &str, text: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cli_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn toml_values_are_strings_numbers_or_booleans() {
+        assert_eq!(parse_toml_value(r#""a b""#).as_deref(), Some("a b"));
+        assert_eq!(parse_toml_value(r#""say \"hi\"\tnow""#).as_deref(), Some("say \"hi\"\tnow"));
+        assert_eq!(parse_toml_value(r"'C:\path'").as_deref(), Some(r"C:\path"));
+        assert_eq!(parse_toml_value(r#""a # b" # comment"#).as_deref(), Some("a # b"));
+        assert_eq!(parse_toml_value("1_000 # ms").as_deref(), Some("1000"));
+        assert_eq!(parse_toml_value("-2.5").as_deref(), Some("-2.5"));
+        assert_eq!(parse_toml_value("true").as_deref(), Some("true"));
+
+        for bad in [r#""unterminated"#, "'unterminated", "bare", r#""a" b"#, "[1, 2]", "{ a = 1 }", ""] {
+            assert_eq!(parse_toml_value(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn config_sections_and_unknown_keys() {
+        let path = scratch_config(
+            "sections",
+            "# settings\nlog_level = \"debug\"\naddress = 'top:1'\nno-such-option = 3\n\n\
+             [\"prog\"] # ours\naddress = \"own:2\"\n\n[other]\naddress = \"other:3\"\nport = 4\n",
+        );
+        let config = load_config(&path, "prog").unwrap();
+        assert_eq!(config.get("log-level").map(String::as_str), Some("debug"));
+        assert_eq!(config.get("address").map(String::as_str), Some("own:2"));
+        assert_eq!(config.get("port"), None);
+        // unknown keys are kept but never looked up
+        assert_eq!(config.get("no-such-option").map(String::as_str), Some("3"));
+
+        let cli = Cli::new("prog", "test").opts(&[Opt::value("address", "ADDR", "")]);
+        let matches = cli.parse(args(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(matches.value("address").as_deref(), Some("own:2"));
+    }
+
+    #[test]
+    fn bad_config_lines_name_the_line() {
+        let path = scratch_config("bad", "address = \"a\"\nlist = [1, 2]\n");
+        let err = load_config(&path, "prog").unwrap_err();
+        assert!(err.ends_with(":2: bad value"), "{}", err);
+
+        let path = scratch_config("unterminated", "[prog\n");
+        assert!(load_config(&path, "prog").unwrap_err().ends_with(":1: unterminated section name"));
+        let path = scratch_config("no_equals", "address\n");
+        assert!(load_config(&path, "prog").unwrap_err().ends_with(":1: expected key = value"));
+    }
+
+    #[test]
+    fn flags_beat_env_beat_config_beat_defaults() {
+        const VAR: &str = "NET_UTILS_CLI_TEST_PRECEDENCE";
+        let cli = Cli::new("prog", "test").opts(&[Opt::value("address", "ADDR", "").short('a').env(VAR).default("default:1")]);
+        let path = scratch_config("precedence", "address = \"config:2\"\n");
+        let config = path.to_str().unwrap();
+
+        env::remove_var(VAR);
+        assert_eq!(cli.parse(args(&[])).unwrap().value("address").as_deref(), Some("default:1"));
+        assert_eq!(cli.parse(args(&["-c", config])).unwrap().value("address").as_deref(), Some("config:2"));
+
+        env::set_var(VAR, "env:3");
+        assert_eq!(cli.parse(args(&["-c", config])).unwrap().value("address").as_deref(), Some("env:3"));
+        let matches = cli.parse(args(&["-c", config, "-a", "flag:4"])).unwrap();
+        assert_eq!(matches.value("address").as_deref(), Some("flag:4"));
+        let matches = cli.parse(args(&["-c", config, "--address=flag:4", "--address", "flag:5"])).unwrap();
+        assert_eq!(matches.value("address").as_deref(), Some("flag:5"));
+
+        // an empty variable counts as unset
+        env::set_var(VAR, "");
+        assert_eq!(cli.parse(args(&["-c", config])).unwrap().value("address").as_deref(), Some("config:2"));
+        env::remove_var(VAR);
+    }
+}
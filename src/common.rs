@@ -1,17 +1,26 @@
 // src/common.rs
+use std::env;
+use std::ffi::OsStr;
 use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use std::os::fd::{AsFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(unix)]
+use std::process::Command;
 
-pub fn print_banner<R: BufRead>(reader: &mut R) -> io::Result<()> {
-    let mut line = String::new();
+/// Prints the SERVER_OS and BANNER lines and returns them.
+pub fn print_banner<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut banner = String::new();
     // read SERVER_OS line
-    reader.read_line(&mut line)?;
-    print!("{}", line);
-    line.clear();
+    reader.read_line(&mut banner)?;
     // read BANNER line
-    reader.read_line(&mut line)?;
-    print!("{}", line);
+    reader.read_line(&mut banner)?;
+    print!("{}", banner);
     io::stdout().flush()?;
-    Ok(())
+    Ok(banner)
 }
 
 pub fn command_loop<F>(mut process_command: F) -> io::Result<()>
@@ -35,3 +44,173 @@ where
     }
     Ok(())
 }
+
+/// True if `path` is a file the current user may run: one with execute
+/// permission on Unix, an existing file elsewhere.
+pub fn is_executable(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+    #[cfg(unix)]
+    {
+        nix::unistd::access(path, nix::unistd::AccessFlags::X_OK).is_ok()
+    }
+    #[cfg(not(unix))]
+    {
+        true
+    }
+}
+
+/// Executables called `name` on PATH in PATH order; only the first unless
+/// `all` is set. A name containing a path separator is checked as is. On
+/// Windows each PATHEXT extension is tried as well.
+pub fn find_in_path(name: &str, all: bool) -> Vec<PathBuf> {
+    find_in(name, env::var_os("PATH").as_deref(), Path::new(""), all)
+}
+
+/// `find_in_path` with the given PATH, and with names containing a path
+/// separator and relative PATH entries taken relative to `cwd`.
+pub fn find_in(name: &str, path_var: Option<&OsStr>, cwd: &Path, all: bool) -> Vec<PathBuf> {
+    if name.contains('/') || name.contains('\\') {
+        return executable_variants(&cwd.join(name)).into_iter().take(1).collect();
+    }
+    let mut found = Vec::new();
+    if let Some(path_var) = path_var {
+        for dir in env::split_paths(path_var) {
+            for candidate in executable_variants(&cwd.join(dir).join(name)) {
+                found.push(candidate);
+                if !all {
+                    return found;
+                }
+            }
+        }
+    }
+    found
+}
+
+#[cfg(not(windows))]
+fn executable_variants(path: &Path) -> Vec<PathBuf> {
+    if is_executable(path) {
+        vec![path.to_path_buf()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// `path` with each PATHEXT extension, and as given if it already has one.
+#[cfg(windows)]
+fn executable_variants(path: &Path) -> Vec<PathBuf> {
+    let exts = env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+    let mut candidates = Vec::new();
+    if let Some(ext) = path.extension() {
+        let ext = format!(".{}", ext.to_string_lossy());
+        if exts.split(';').any(|e| e.eq_ignore_ascii_case(&ext)) {
+            candidates.push(path.to_path_buf());
+        }
+    }
+    for ext in exts.split(';').filter(|e| !e.is_empty()) {
+        let mut with_ext = path.as_os_str().to_os_string();
+        with_ext.push(ext);
+        candidates.push(PathBuf::from(with_ext));
+    }
+    candidates.into_iter().filter(|p| is_executable(p)).collect()
+}
+
+/// Makes `cmd` start as a session leader with the PTY slave `slave` as its
+/// controlling terminal and its own process group in the foreground, so
+/// job control, ^C through the line discipline and tty checks work.
+#[cfg(unix)]
+pub fn set_controlling_tty(cmd: &mut Command, slave: RawFd) {
+    // SAFETY: setsid, ioctl and tcsetpgrp are async-signal-safe, and
+    // `slave` stays open in the child until exec.
+    unsafe {
+        cmd.pre_exec(move || {
+            nix::unistd::setsid()?;
+            if nix::libc::ioctl(slave, nix::libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if nix::libc::tcsetpgrp(slave, nix::libc::getpid()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Sends SIGHUP then SIGCONT to the process group led by `pid`, as the
+/// kernel does when a terminal hangs up.
+#[cfg(unix)]
+pub fn hang_up(pid: u32) {
+    use nix::sys::signal::{killpg, Signal};
+    let pgrp = nix::unistd::Pid::from_raw(pid as i32);
+    let _ = killpg(pgrp, Signal::SIGHUP);
+    let _ = killpg(pgrp, Signal::SIGCONT);
+}
+
+/// Sends SIGKILL to the process group led by `pid`, for a shell that
+/// ignored being hung up.
+#[cfg(unix)]
+pub fn kill_group(pid: u32) {
+    use nix::sys::signal::{killpg, Signal};
+    let _ = killpg(nix::unistd::Pid::from_raw(pid as i32), Signal::SIGKILL);
+}
+
+/// Waits up to `timeout_ms` for `fd` to have data (or EOF) to read.
+#[cfg(unix)]
+pub fn wait_readable<F: AsFd>(fd: F, timeout_ms: u16) -> bool {
+    use nix::poll::{poll, PollFd, PollFlags};
+    let mut fds = [PollFd::new(fd.as_fd(), PollFlags::POLLIN)];
+    matches!(poll(&mut fds, timeout_ms), Ok(n) if n > 0)
+}
+
+/// Days since 1970-01-01 to (year, month, day).
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The current time in UTC as RFC 3339 with milliseconds, such as
+/// `2024-05-01T12:34:56.789Z`.
+pub fn utc_timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let rem = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+/// `s` as a JSON string literal, quotes included.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,1158 @@
+// src/coreutils/fs.rs
+//
+// File utilities: ls, cat, cp, mv, rm, mkdir and find.
+
+use super::{error_text, parse_or_usage, Streams};
+use crate::common::civil_from_days;
+use glob::{MatchOptions, Pattern};
+use std::fs::{self, Metadata};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+////////////////////////////////////////////////////////////////////////////////
+// ls
+////////////////////////////////////////////////////////////////////////////////
+
+struct LsOptions {
+    all: bool,
+    almost_all: bool,
+    long: bool,
+    human: bool,
+    dir_itself: bool,
+    recursive: bool,
+    reverse: bool,
+    by_time: bool,
+}
+
+struct Entry {
+    name: String,
+    path: PathBuf,
+    meta: Metadata,
+}
+
+/// `ls [-aAlhdRrt1] [file...]`
+pub fn ls(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("ls", args, "aAlhdRrt1", "", io) else {
+        return 2;
+    };
+    let o = LsOptions {
+        all: opts.has('a'),
+        almost_all: opts.has('A'),
+        long: opts.has('l'),
+        human: opts.has('h'),
+        dir_itself: opts.has('d'),
+        recursive: opts.has('R'),
+        reverse: opts.has('r'),
+        by_time: opts.has('t'),
+    };
+    let mut operands = opts.operands;
+    if operands.is_empty() {
+        operands.push(".".to_string());
+    }
+
+    let mut status = 0;
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    for op in &operands {
+        let path = io.resolve(op);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) => {
+                // operands that are links to directories are listed like directories
+                let is_dir = fs::metadata(&path).map(|m| m.is_dir()).unwrap_or(false);
+                if is_dir && !o.dir_itself {
+                    dirs.push(op.clone());
+                } else {
+                    files.push(Entry { name: op.clone(), path, meta });
+                }
+            }
+            Err(e) => {
+                writeln!(io.stderr, "ls: cannot access '{}': {}", op, error_text(&e)).ok();
+                status = 2;
+            }
+        }
+    }
+
+    sort_entries(&mut files, &o);
+    print_entries(&files, &o, None, io);
+    dirs.sort();
+    if o.reverse {
+        dirs.reverse();
+    }
+
+    let headers = operands.len() > 1 || o.recursive;
+    let mut first = files.is_empty();
+    for dir in dirs {
+        if !list_dir(&io.resolve(&dir), &dir, &o, headers, &mut first, io) {
+            status = status.max(1);
+        }
+    }
+    status
+}
+
+/// Lists one directory, and its subdirectories with `-R`.
+fn list_dir(path: &Path, label: &str, o: &LsOptions, header: bool, first: &mut bool, io: &mut Streams) -> bool {
+    if header {
+        if !*first {
+            writeln!(io.stdout).ok();
+        }
+        writeln!(io.stdout, "{}:", label).ok();
+    }
+    *first = false;
+
+    let read = match fs::read_dir(path) {
+        Ok(read) => read,
+        Err(e) => {
+            writeln!(io.stderr, "ls: cannot open directory '{}': {}", label, error_text(&e)).ok();
+            return false;
+        }
+    };
+    let mut entries = Vec::new();
+    if o.all {
+        for name in [".", ".."] {
+            if let Ok(meta) = fs::symlink_metadata(path.join(name)) {
+                entries.push(Entry { name: name.to_string(), path: path.join(name), meta });
+            }
+        }
+    }
+    for entry in read.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') && !o.all && !o.almost_all {
+            continue;
+        }
+        if let Ok(meta) = fs::symlink_metadata(entry.path()) {
+            entries.push(Entry { name, path: entry.path(), meta });
+        }
+    }
+    sort_entries(&mut entries, o);
+    print_entries(&entries, o, Some(total_blocks(&entries)), io);
+
+    let mut ok = true;
+    if o.recursive {
+        for entry in &entries {
+            if entry.meta.is_dir() && entry.name != "." && entry.name != ".." {
+                let sub_label = format!("{}/{}", label.trim_end_matches('/'), entry.name);
+                ok &= list_dir(&entry.path, &sub_label, o, true, first, io);
+            }
+        }
+    }
+    ok
+}
+
+fn sort_entries(entries: &mut [Entry], o: &LsOptions) {
+    if o.by_time {
+        // newest first, then by name
+        entries.sort_by(|a, b| mtime(&b.meta).cmp(&mtime(&a.meta)).then_with(|| a.name.cmp(&b.name)));
+    } else {
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    if o.reverse {
+        entries.reverse();
+    }
+}
+
+fn print_entries(entries: &[Entry], o: &LsOptions, total: Option<u64>, io: &mut Streams) {
+    if !o.long {
+        for entry in entries {
+            writeln!(io.stdout, "{}", entry.name).ok();
+        }
+        return;
+    }
+    if let Some(total) = total {
+        writeln!(io.stdout, "total {}", total).ok();
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let rows: Vec<[String; 6]> = entries
+        .iter()
+        .map(|e| {
+            let (links, user, group) = owner_columns(&e.meta);
+            let size = if o.human { human_size(e.meta.len()) } else { e.meta.len().to_string() };
+            [mode_string(&e.meta), links, user, group, size, format_time(mtime(&e.meta), now)]
+        })
+        .collect();
+    let width = |col: usize| rows.iter().map(|r| r[col].len()).max().unwrap_or(0);
+    let (w_links, w_user, w_group, w_size) = (width(1), width(2), width(3), width(4));
+
+    for (row, entry) in rows.iter().zip(entries) {
+        let mut name = entry.name.clone();
+        if entry.meta.file_type().is_symlink() {
+            if let Ok(target) = fs::read_link(&entry.path) {
+                name = format!("{} -> {}", name, target.display());
+            }
+        }
+        writeln!(
+            io.stdout,
+            "{} {:>w_links$} {:<w_user$} {:<w_group$} {:>w_size$} {} {}",
+            row[0], row[1], row[2], row[3], row[4], row[5], name,
+        )
+        .ok();
+    }
+}
+
+fn mtime(meta: &Metadata) -> i64 {
+    match meta.modified().map(|t| t.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(d)) => d.as_secs() as i64,
+        Ok(Err(e)) => -(e.duration().as_secs() as i64),
+        Err(_) => 0,
+    }
+}
+
+/// Size in 1K blocks of the entries, for the "total" line of `ls -l`.
+fn total_blocks(entries: &[Entry]) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        entries.iter().map(|e| e.meta.blocks()).sum::<u64>() / 2
+    }
+    #[cfg(not(unix))]
+    {
+        entries.iter().map(|e| e.meta.len().div_ceil(1024)).sum()
+    }
+}
+
+#[cfg(unix)]
+fn mode_string(meta: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+    let mode = meta.mode();
+    let kind = match mode & 0o170000 {
+        0o040000 => 'd',
+        0o120000 => 'l',
+        0o020000 => 'c',
+        0o060000 => 'b',
+        0o010000 => 'p',
+        0o140000 => 's',
+        _ => '-',
+    };
+    let mut s = String::with_capacity(10);
+    s.push(kind);
+    // (read bit, write bit, execute bit, special bit, special char)
+    let triples = [
+        (0o400, 0o200, 0o100, 0o4000, 's'),
+        (0o040, 0o020, 0o010, 0o2000, 's'),
+        (0o004, 0o002, 0o001, 0o1000, 't'),
+    ];
+    for (r, w, x, special, c) in triples {
+        s.push(if mode & r != 0 { 'r' } else { '-' });
+        s.push(if mode & w != 0 { 'w' } else { '-' });
+        s.push(match (mode & x != 0, mode & special != 0) {
+            (true, true) => c,
+            (false, true) => c.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+#[cfg(not(unix))]
+fn mode_string(meta: &Metadata) -> String {
+    let kind = if meta.is_dir() { 'd' } else if meta.file_type().is_symlink() { 'l' } else { '-' };
+    let perms = if meta.permissions().readonly() { "r--" } else { "rw-" };
+    format!("{}{}{}{}", kind, perms, perms, perms)
+}
+
+/// Link count, owner and group columns of `ls -l`.
+#[cfg(unix)]
+fn owner_columns(meta: &Metadata) -> (String, String, String) {
+    use nix::unistd::{Gid, Group, Uid, User};
+    use std::os::unix::fs::MetadataExt;
+    let user = User::from_uid(Uid::from_raw(meta.uid()))
+        .ok()
+        .flatten()
+        .map(|u| u.name)
+        .unwrap_or_else(|| meta.uid().to_string());
+    let group = Group::from_gid(Gid::from_raw(meta.gid()))
+        .ok()
+        .flatten()
+        .map(|g| g.name)
+        .unwrap_or_else(|| meta.gid().to_string());
+    (meta.nlink().to_string(), user, group)
+}
+
+#[cfg(not(unix))]
+fn owner_columns(_meta: &Metadata) -> (String, String, String) {
+    ("1".to_string(), "-".to_string(), "-".to_string())
+}
+
+/// Sizes as `ls -h` prints them: 512, 1.5K, 12M.
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 {
+        format!("{:.1}{}", (value * 10.0).ceil() / 10.0, UNITS[unit])
+    } else {
+        format!("{}{}", value.ceil() as u64, UNITS[unit])
+    }
+}
+
+/// Formats a modification time (UTC) like `ls -l`: "Oct  5 14:03" for
+/// recent files, "Oct  5  2023" for ones older than six months.
+fn format_time(secs: i64, now: i64) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    const SIX_MONTHS: i64 = 183 * 24 * 3600;
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let month = MONTHS[(month - 1) as usize];
+    if secs > now || now - secs > SIX_MONTHS {
+        format!("{} {:>2}  {}", month, day, year)
+    } else {
+        format!("{} {:>2} {:02}:{:02}", month, day, rem / 3600, rem % 3600 / 60)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// cat
+////////////////////////////////////////////////////////////////////////////////
+
+struct CatOptions {
+    number: bool,
+    number_nonblank: bool,
+    show_ends: bool,
+    squeeze: bool,
+}
+
+/// `cat [-nbEs] [file...]`; `-` or no file reads stdin.
+pub fn cat(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("cat", args, "nbEs", "", io) else {
+        return 2;
+    };
+    let o = CatOptions {
+        number: opts.has('n'),
+        number_nonblank: opts.has('b'),
+        show_ends: opts.has('E'),
+        squeeze: opts.has('s'),
+    };
+    let mut operands = opts.operands;
+    if operands.is_empty() {
+        operands.push("-".to_string());
+    }
+
+    let mut status = 0;
+    // line number and "previous line was blank", carried across files
+    let mut state = (0u64, false);
+    for op in &operands {
+        let result = if op == "-" {
+            cat_stream(&mut *io.stdin, &mut *io.stdout, &o, &mut state)
+        } else if io.resolve(op).is_dir() {
+            Err(io::Error::other("Is a directory"))
+        } else {
+            fs::File::open(io.resolve(op)).and_then(|mut f| cat_stream(&mut f, &mut *io.stdout, &o, &mut state))
+        };
+        if let Err(e) = result {
+            writeln!(io.stderr, "cat: {}: {}", op, error_text(&e)).ok();
+            status = 1;
+        }
+    }
+    status
+}
+
+fn cat_stream(input: &mut dyn Read, out: &mut dyn Write, o: &CatOptions, state: &mut (u64, bool)) -> io::Result<()> {
+    if !o.number && !o.number_nonblank && !o.show_ends && !o.squeeze {
+        io::copy(input, out)?;
+        return Ok(());
+    }
+    let mut reader = BufReader::new(input);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let has_newline = line.last() == Some(&b'\n');
+        let body = if has_newline { &line[..line.len() - 1] } else { &line[..] };
+        let blank = body.is_empty();
+        if o.squeeze && blank && state.1 {
+            continue;
+        }
+        state.1 = blank;
+        if o.number_nonblank && !blank || o.number && !o.number_nonblank {
+            state.0 += 1;
+            write!(out, "{:>6}\t", state.0)?;
+        }
+        out.write_all(body)?;
+        if o.show_ends && has_newline {
+            out.write_all(b"$")?;
+        }
+        if has_newline {
+            out.write_all(b"\n")?;
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// cp / mv
+////////////////////////////////////////////////////////////////////////////////
+
+/// Splits `src... dest` and works out where each source goes: into `dest`
+/// when it is a directory, otherwise onto `dest` itself.
+fn targets(name: &str, operands: &[String], io: &mut Streams) -> Option<Vec<(PathBuf, PathBuf)>> {
+    match operands.len() {
+        0 => {
+            writeln!(io.stderr, "{}: missing file operand", name).ok();
+            return None;
+        }
+        1 => {
+            writeln!(io.stderr, "{}: missing destination file operand after '{}'", name, operands[0]).ok();
+            return None;
+        }
+        _ => {}
+    }
+    let (sources, dest) = operands.split_at(operands.len() - 1);
+    let dest = Path::new(&dest[0]);
+    let into_dir = io.resolve(dest).is_dir();
+    if sources.len() > 1 && !into_dir {
+        writeln!(io.stderr, "{}: target '{}' is not a directory", name, dest.display()).ok();
+        return None;
+    }
+    Some(
+        sources
+            .iter()
+            .map(|src| {
+                let src = PathBuf::from(src);
+                let target = match (into_dir, src.file_name()) {
+                    (true, Some(base)) => dest.join(base),
+                    _ => dest.to_path_buf(),
+                };
+                (src, target)
+            })
+            .collect(),
+    )
+}
+
+/// True when `target` is `src` or lies inside it.
+fn is_inside(src: &Path, target: &Path) -> bool {
+    let src = match fs::canonicalize(src) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    // the target may not exist yet; resolve its parent instead
+    let resolved = fs::canonicalize(target).or_else(|_| {
+        let parent = target.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        fs::canonicalize(parent).map(|p| p.join(target.file_name().unwrap_or_default()))
+    });
+    resolved.map(|t| t.starts_with(&src)).unwrap_or(false)
+}
+
+/// `cp [-rRfnv] src... dest`
+pub fn cp(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("cp", args, "rRfnv", "", io) else {
+        return 2;
+    };
+    let recursive = opts.has('r') || opts.has('R');
+    let Some(pairs) = targets("cp", &opts.operands, io) else {
+        return 1;
+    };
+
+    let mut status = 0;
+    for (src, target) in pairs {
+        let meta = match fs::metadata(io.resolve(&src)) {
+            Ok(meta) => meta,
+            Err(e) => {
+                writeln!(io.stderr, "cp: cannot stat '{}': {}", src.display(), error_text(&e)).ok();
+                status = 1;
+                continue;
+            }
+        };
+        let ok = if meta.is_dir() {
+            if !recursive {
+                writeln!(io.stderr, "cp: -r not specified; omitting directory '{}'", src.display()).ok();
+                false
+            } else if is_inside(&io.resolve(&src), &io.resolve(&target)) {
+                writeln!(
+                    io.stderr,
+                    "cp: cannot copy a directory, '{}', into itself, '{}'",
+                    src.display(),
+                    target.display()
+                )
+                .ok();
+                false
+            } else {
+                copy_tree(&src, &target, opts.has('n'), opts.has('v'), io)
+            }
+        } else {
+            copy_file(&src, &target, opts.has('f'), opts.has('n'), opts.has('v'), io)
+        };
+        if !ok {
+            status = 1;
+        }
+    }
+    status
+}
+
+fn copy_file(src: &Path, target: &Path, force: bool, no_clobber: bool, verbose: bool, io: &mut Streams) -> bool {
+    let (from, to) = (io.resolve(src), io.resolve(target));
+    if to.exists() {
+        if no_clobber {
+            return true;
+        }
+        if let (Ok(a), Ok(b)) = (fs::canonicalize(&from), fs::canonicalize(&to)) {
+            if a == b {
+                writeln!(io.stderr, "cp: '{}' and '{}' are the same file", src.display(), target.display()).ok();
+                return false;
+            }
+        }
+    }
+    let mut result = fs::copy(&from, &to);
+    if result.is_err() && force && to.exists() {
+        // -f: replace a destination that cannot be opened for writing
+        result = fs::remove_file(&to).and_then(|_| fs::copy(&from, &to));
+    }
+    match result {
+        Ok(_) => {
+            if verbose {
+                writeln!(io.stdout, "'{}' -> '{}'", src.display(), target.display()).ok();
+            }
+            true
+        }
+        Err(e) => {
+            writeln!(io.stderr, "cp: cannot create '{}': {}", target.display(), error_text(&e)).ok();
+            false
+        }
+    }
+}
+
+fn copy_tree(src: &Path, target: &Path, no_clobber: bool, verbose: bool, io: &mut Streams) -> bool {
+    if !io.resolve(target).is_dir() {
+        if let Err(e) = fs::create_dir(io.resolve(target)) {
+            writeln!(io.stderr, "cp: cannot create directory '{}': {}", target.display(), error_text(&e)).ok();
+            return false;
+        }
+        if verbose {
+            writeln!(io.stdout, "'{}' -> '{}'", src.display(), target.display()).ok();
+        }
+    }
+    let read = match fs::read_dir(io.resolve(src)) {
+        Ok(read) => read,
+        Err(e) => {
+            writeln!(io.stderr, "cp: cannot access '{}': {}", src.display(), error_text(&e)).ok();
+            return false;
+        }
+    };
+    let mut ok = true;
+    for entry in read.flatten() {
+        let from = src.join(entry.file_name());
+        let to = target.join(entry.file_name());
+        let meta = match fs::symlink_metadata(io.resolve(&from)) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        ok &= if meta.file_type().is_symlink() {
+            copy_link(&from, &to, io)
+        } else if meta.is_dir() {
+            copy_tree(&from, &to, no_clobber, verbose, io)
+        } else {
+            copy_file(&from, &to, false, no_clobber, verbose, io)
+        };
+    }
+    ok
+}
+
+/// Copies a symbolic link inside a tree as a link, not its target.
+#[cfg(unix)]
+fn copy_link(from: &Path, to: &Path, io: &mut Streams) -> bool {
+    let result = fs::read_link(io.resolve(from)).and_then(|target| std::os::unix::fs::symlink(target, io.resolve(to)));
+    if let Err(e) = result {
+        writeln!(io.stderr, "cp: cannot create symbolic link '{}': {}", to.display(), error_text(&e)).ok();
+        return false;
+    }
+    true
+}
+
+#[cfg(not(unix))]
+fn copy_link(from: &Path, to: &Path, io: &mut Streams) -> bool {
+    if io.resolve(from).is_dir() {
+        writeln!(io.stderr, "cp: skipping directory link '{}'", from.display()).ok();
+        return false;
+    }
+    copy_file(from, to, false, false, false, io)
+}
+
+/// `mv [-fnv] src... dest`
+pub fn mv(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("mv", args, "fnv", "", io) else {
+        return 2;
+    };
+    let Some(pairs) = targets("mv", &opts.operands, io) else {
+        return 1;
+    };
+
+    let mut status = 0;
+    for (src, target) in pairs {
+        let (from, to) = (io.resolve(&src), io.resolve(&target));
+        let meta = match fs::symlink_metadata(&from) {
+            Ok(meta) => meta,
+            Err(e) => {
+                writeln!(io.stderr, "mv: cannot stat '{}': {}", src.display(), error_text(&e)).ok();
+                status = 1;
+                continue;
+            }
+        };
+        if opts.has('n') && to.exists() {
+            continue;
+        }
+        if meta.is_dir() && is_inside(&from, &to) {
+            writeln!(
+                io.stderr,
+                "mv: cannot move '{}' to a subdirectory of itself, '{}'",
+                src.display(),
+                target.display()
+            )
+            .ok();
+            status = 1;
+            continue;
+        }
+        if let Err(e) = fs::rename(&from, &to) {
+            // rename cannot cross filesystems; copy and remove instead
+            let moved = if meta.is_dir() {
+                let mut sink = Vec::new();
+                let mut quiet = Streams {
+                    stdin: &mut io::empty(),
+                    stdout: &mut sink,
+                    stderr: &mut io::sink(),
+                    cwd: io.cwd,
+                    search_path: io.search_path,
+                };
+                copy_tree(&src, &target, false, false, &mut quiet) && fs::remove_dir_all(&from).is_ok()
+            } else {
+                fs::copy(&from, &to).is_ok() && fs::remove_file(&from).is_ok()
+            };
+            if !moved {
+                writeln!(
+                    io.stderr,
+                    "mv: cannot move '{}' to '{}': {}",
+                    src.display(),
+                    target.display(),
+                    error_text(&e)
+                )
+                .ok();
+                status = 1;
+                continue;
+            }
+        }
+        if opts.has('v') {
+            writeln!(io.stdout, "renamed '{}' -> '{}'", src.display(), target.display()).ok();
+        }
+    }
+    status
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// rm / mkdir
+////////////////////////////////////////////////////////////////////////////////
+
+/// `rm [-rRfvd] file...`
+pub fn rm(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("rm", args, "rRfvd", "", io) else {
+        return 2;
+    };
+    let recursive = opts.has('r') || opts.has('R');
+    let force = opts.has('f');
+    let verbose = opts.has('v');
+    if opts.operands.is_empty() {
+        if force {
+            return 0;
+        }
+        writeln!(io.stderr, "rm: missing operand").ok();
+        return 1;
+    }
+
+    let mut status = 0;
+    for op in &opts.operands {
+        let path = Path::new(op);
+        let base = path.file_name().map(|b| b.to_string_lossy().to_string());
+        if base.is_none() && path.parent().is_none() {
+            writeln!(io.stderr, "rm: it is dangerous to operate recursively on '{}'", op).ok();
+            status = 1;
+            continue;
+        }
+        if matches!(op.trim_end_matches('/').rsplit('/').next(), Some(".") | Some("..")) {
+            writeln!(io.stderr, "rm: refusing to remove '.' or '..' directory: skipping '{}'", op).ok();
+            status = 1;
+            continue;
+        }
+        let meta = match fs::symlink_metadata(io.resolve(path)) {
+            Ok(meta) => meta,
+            Err(e) => {
+                if !(force && e.kind() == io::ErrorKind::NotFound) {
+                    writeln!(io.stderr, "rm: cannot remove '{}': {}", op, error_text(&e)).ok();
+                    status = 1;
+                }
+                continue;
+            }
+        };
+        let ok = if meta.is_dir() {
+            if recursive {
+                remove_tree(path, verbose, io)
+            } else if opts.has('d') {
+                remove_one(path, true, verbose, io)
+            } else {
+                writeln!(io.stderr, "rm: cannot remove '{}': Is a directory", op).ok();
+                false
+            }
+        } else {
+            remove_one(path, false, verbose, io)
+        };
+        if !ok {
+            status = 1;
+        }
+    }
+    status
+}
+
+fn remove_one(path: &Path, dir: bool, verbose: bool, io: &mut Streams) -> bool {
+    let result = if dir { fs::remove_dir(io.resolve(path)) } else { fs::remove_file(io.resolve(path)) };
+    match result {
+        Ok(()) => {
+            if verbose {
+                let kind = if dir { "removed directory" } else { "removed" };
+                writeln!(io.stdout, "{} '{}'", kind, path.display()).ok();
+            }
+            true
+        }
+        Err(e) => {
+            writeln!(io.stderr, "rm: cannot remove '{}': {}", path.display(), error_text(&e)).ok();
+            false
+        }
+    }
+}
+
+fn remove_tree(path: &Path, verbose: bool, io: &mut Streams) -> bool {
+    let read = match fs::read_dir(io.resolve(path)) {
+        Ok(read) => read,
+        Err(e) => {
+            writeln!(io.stderr, "rm: cannot remove '{}': {}", path.display(), error_text(&e)).ok();
+            return false;
+        }
+    };
+    let mut ok = true;
+    for entry in read.flatten() {
+        let child = path.join(entry.file_name());
+        // symlinks are removed, never followed
+        let is_dir = fs::symlink_metadata(io.resolve(&child)).map(|m| m.is_dir()).unwrap_or(false);
+        ok &= if is_dir { remove_tree(&child, verbose, io) } else { remove_one(&child, false, verbose, io) };
+    }
+    ok && remove_one(path, true, verbose, io)
+}
+
+/// `mkdir [-pv] [-m mode] dir...`
+pub fn mkdir(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("mkdir", args, "pv", "m", io) else {
+        return 2;
+    };
+    let mode = match opts.value('m').map(|m| u32::from_str_radix(m, 8)) {
+        Some(Ok(mode)) if mode <= 0o7777 => Some(mode),
+        Some(_) => {
+            writeln!(io.stderr, "mkdir: invalid mode '{}'", opts.value('m').unwrap_or_default()).ok();
+            return 1;
+        }
+        None => None,
+    };
+    if opts.operands.is_empty() {
+        writeln!(io.stderr, "mkdir: missing operand").ok();
+        return 1;
+    }
+
+    let mut status = 0;
+    for op in &opts.operands {
+        let path = Path::new(op);
+        // with -p, the missing ancestors are created (and reported) first
+        let mut created = Vec::new();
+        if opts.has('p') {
+            for dir in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+                if dir.as_os_str().is_empty() || io.resolve(dir).is_dir() {
+                    continue;
+                }
+                created.push(dir.to_path_buf());
+            }
+        } else {
+            created.push(path.to_path_buf());
+        }
+        for dir in created {
+            if let Err(e) = fs::create_dir(io.resolve(&dir)) {
+                writeln!(io.stderr, "mkdir: cannot create directory '{}': {}", dir.display(), error_text(&e)).ok();
+                status = 1;
+                break;
+            }
+            if opts.has('v') {
+                writeln!(io.stdout, "mkdir: created directory '{}'", dir.display()).ok();
+            }
+            if let Some(mode) = mode {
+                set_mode(&io.resolve(&dir), mode);
+            }
+        }
+    }
+    status
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    let _ = fs::set_permissions(path, fs::Permissions::from_mode(mode));
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) {}
+
+////////////////////////////////////////////////////////////////////////////////
+// find
+////////////////////////////////////////////////////////////////////////////////
+
+enum Test {
+    Name(Pattern, bool),
+    Path(Pattern),
+    Type(char),
+    Empty,
+    Not(Box<Test>),
+}
+
+/// A `find` expression: alternatives joined by `-o`, each a list of
+/// tests that must all hold.
+struct FindExpr {
+    any_of: Vec<Vec<Test>>,
+    min_depth: usize,
+    max_depth: usize,
+}
+
+/// `find [path...] [-maxdepth n] [-mindepth n] [tests...]` with the tests
+/// `-name`, `-iname`, `-path`, `-type [fdl]`, `-empty`, `!`/`-not`, `-a`,
+/// `-o` and `-print`.
+pub fn find(args: &[String], io: &mut Streams) -> i32 {
+    let split = args
+        .iter()
+        .position(|a| a.starts_with('-') || a == "!")
+        .unwrap_or(args.len());
+    let mut roots: Vec<String> = args[..split].to_vec();
+    if roots.is_empty() {
+        roots.push(".".to_string());
+    }
+    let expr = match parse_find(&args[split..]) {
+        Ok(expr) => expr,
+        Err(e) => {
+            writeln!(io.stderr, "find: {}", e).ok();
+            return 1;
+        }
+    };
+
+    let mut status = 0;
+    for root in &roots {
+        match fs::symlink_metadata(io.resolve(root)) {
+            Ok(meta) => {
+                if !find_walk(&io.resolve(root), root, &meta, 0, &expr, io) {
+                    status = 1;
+                }
+            }
+            Err(e) => {
+                writeln!(io.stderr, "find: '{}': {}", root, error_text(&e)).ok();
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+fn parse_find(args: &[String]) -> Result<FindExpr, String> {
+    let mut expr = FindExpr { any_of: vec![Vec::new()], min_depth: 0, max_depth: usize::MAX };
+    let mut negate = false;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        i += 1;
+        let mut value = || -> Result<String, String> {
+            i += 1;
+            args.get(i - 1).cloned().ok_or_else(|| format!("missing argument to '{}'", arg))
+        };
+        let test = match arg {
+            "!" | "-not" => {
+                negate = !negate;
+                continue;
+            }
+            "-a" | "-and" | "-print" => continue,
+            "-o" | "-or" => {
+                expr.any_of.push(Vec::new());
+                continue;
+            }
+            "-maxdepth" | "-mindepth" => {
+                let v = value()?;
+                let depth = v.parse().map_err(|_| format!("invalid depth '{}'", v))?;
+                if arg == "-maxdepth" {
+                    expr.max_depth = depth;
+                } else {
+                    expr.min_depth = depth;
+                }
+                continue;
+            }
+            "-name" | "-iname" => {
+                let v = value()?;
+                let pattern = Pattern::new(&v).map_err(|e| format!("bad pattern '{}': {}", v, e))?;
+                Test::Name(pattern, arg == "-iname")
+            }
+            "-path" => {
+                let v = value()?;
+                Test::Path(Pattern::new(&v).map_err(|e| format!("bad pattern '{}': {}", v, e))?)
+            }
+            "-type" => match value()?.as_str() {
+                t @ ("f" | "d" | "l") => Test::Type(t.chars().next().unwrap_or('f')),
+                t => return Err(format!("unknown argument to -type: {}", t)),
+            },
+            "-empty" => Test::Empty,
+            _ => return Err(format!("unknown predicate '{}'", arg)),
+        };
+        let test = if negate { Test::Not(Box::new(test)) } else { test };
+        negate = false;
+        if let Some(group) = expr.any_of.last_mut() {
+            group.push(test);
+        }
+    }
+    Ok(expr)
+}
+
+fn find_walk(path: &Path, shown: &str, meta: &Metadata, depth: usize, expr: &FindExpr, io: &mut Streams) -> bool {
+    if depth >= expr.min_depth && expr.any_of.iter().any(|group| group.iter().all(|t| find_test(t, path, shown, meta))) {
+        writeln!(io.stdout, "{}", shown).ok();
+    }
+    if !meta.is_dir() || depth >= expr.max_depth {
+        return true;
+    }
+    let read = match fs::read_dir(path) {
+        Ok(read) => read,
+        Err(e) => {
+            writeln!(io.stderr, "find: '{}': {}", shown, error_text(&e)).ok();
+            return false;
+        }
+    };
+    let mut entries: Vec<_> = read.flatten().collect();
+    entries.sort_by_key(|e| e.file_name());
+    let mut ok = true;
+    for entry in entries {
+        let child_shown = format!("{}/{}", shown.trim_end_matches('/'), entry.file_name().to_string_lossy());
+        if let Ok(child_meta) = fs::symlink_metadata(entry.path()) {
+            ok &= find_walk(&entry.path(), &child_shown, &child_meta, depth + 1, expr, io);
+        }
+    }
+    ok
+}
+
+fn find_test(test: &Test, path: &Path, shown: &str, meta: &Metadata) -> bool {
+    match test {
+        Test::Name(pattern, ignore_case) => {
+            let name = Path::new(shown)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| shown.to_string());
+            let options = MatchOptions {
+                case_sensitive: !ignore_case,
+                require_literal_separator: false,
+                require_literal_leading_dot: false,
+            };
+            pattern.matches_with(&name, options)
+        }
+        Test::Path(pattern) => pattern.matches(shown),
+        Test::Type('d') => meta.is_dir(),
+        Test::Type('l') => meta.file_type().is_symlink(),
+        Test::Type(_) => meta.is_file(),
+        Test::Empty => {
+            if meta.is_dir() {
+                fs::read_dir(path).map(|mut r| r.next().is_none()).unwrap_or(false)
+            } else {
+                meta.is_file() && meta.len() == 0
+            }
+        }
+        Test::Not(inner) => !find_test(inner, path, shown, meta),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coreutils::tests::{run_in, scratch_dir};
+
+    /// A directory with `b.txt` ("hello\n"), an empty `a.txt`, `.hidden`
+    /// and `sub/c.txt`.
+    fn tree(name: &str) -> PathBuf {
+        let dir = scratch_dir(name);
+        fs::write(dir.join("b.txt"), "hello\n").unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/c.txt"), "c\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn ls_lists_names_in_order() {
+        let dir = tree("ls");
+        assert_eq!(run_in(&dir, "ls", "").stdout, "a.txt\nb.txt\nsub\n");
+        assert_eq!(run_in(&dir, "ls -r", "").stdout, "sub\nb.txt\na.txt\n");
+        assert_eq!(run_in(&dir, "ls -A", "").stdout, ".hidden\na.txt\nb.txt\nsub\n");
+        assert_eq!(run_in(&dir, "ls -a", "").stdout, ".\n..\n.hidden\na.txt\nb.txt\nsub\n");
+        assert_eq!(run_in(&dir, "ls -d sub b.txt", "").stdout, "b.txt\nsub\n");
+        assert_eq!(run_in(&dir, "ls b.txt sub", "").stdout, "b.txt\n\nsub:\nc.txt\n");
+        assert_eq!(run_in(&dir, "ls -R", "").stdout, ".:\na.txt\nb.txt\nsub\n\n./sub:\nc.txt\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ls_reports_missing_operands() {
+        let dir = tree("ls_missing");
+        let out = run_in(&dir, "ls nope a.txt", "");
+        assert_eq!(out.status, 2);
+        assert_eq!(out.stdout, "a.txt\n");
+        assert_eq!(out.stderr, "ls: cannot access 'nope': No such file or directory\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ls_long_format() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tree("ls_long");
+        fs::set_permissions(dir.join("b.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+        let out = run_in(&dir, "ls -l b.txt sub", "").stdout;
+        let mut lines = out.lines();
+        let file: Vec<&str> = lines.next().unwrap().split_whitespace().collect();
+        assert_eq!(file[0], "-rw-r-----");
+        assert_eq!(file[1], "1");
+        assert_eq!(file[4], "6");
+        assert_eq!(file[8], "b.txt");
+        assert_eq!(lines.next(), Some(""));
+        assert_eq!(lines.next(), Some("sub:"));
+        assert!(lines.next().unwrap().starts_with("total "));
+        assert!(lines.next().unwrap().ends_with(" c.txt"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mode_sizes_and_times_format_like_ls() {
+        assert_eq!(human_size(512), "512");
+        assert_eq!(human_size(1536), "1.5K");
+        assert_eq!(human_size(10 * 1024 * 1024), "10M");
+        assert_eq!(human_size(1025), "1.1K");
+        // 2023-10-05 14:03:00 UTC
+        let t = 1_696_514_580;
+        assert_eq!(format_time(t, t + 60), "Oct  5 14:03");
+        assert_eq!(format_time(t, t + 365 * 86400), "Oct  5  2023");
+        assert_eq!(format_time(t + 3600, t), "Oct  5  2023");
+    }
+
+    #[test]
+    fn cat_numbers_and_marks_lines() {
+        let dir = scratch_dir("cat");
+        fs::write(dir.join("f"), "a\n\n\n\nb").unwrap();
+        assert_eq!(run_in(&dir, "cat f", "").stdout, "a\n\n\n\nb");
+        assert_eq!(run_in(&dir, "cat -n f", "").stdout, "     1\ta\n     2\t\n     3\t\n     4\t\n     5\tb");
+        assert_eq!(run_in(&dir, "cat -b f", "").stdout, "     1\ta\n\n\n\n     2\tb");
+        assert_eq!(run_in(&dir, "cat -sE f", "").stdout, "a$\n$\nb");
+        // numbering carries across operands, stdin included
+        assert_eq!(run_in(&dir, "cat -n - f", "x\n").stdout, "     1\tx\n     2\ta\n     3\t\n     4\t\n     5\t\n     6\tb");
+        let out = run_in(&dir, "cat . f", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "cat: .: Is a directory\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cp_copies_files_and_trees() {
+        let dir = tree("cp");
+        let out = run_in(&dir, "cp -v b.txt copy.txt", "");
+        assert_eq!((out.status, out.stdout.as_str()), (0, "'b.txt' -> 'copy.txt'\n"));
+        assert_eq!(fs::read_to_string(dir.join("copy.txt")).unwrap(), "hello\n");
+        assert_eq!(run_in(&dir, "cp a.txt b.txt sub", "").status, 0);
+        assert!(dir.join("sub/a.txt").is_file() && dir.join("sub/b.txt").is_file());
+
+        let out = run_in(&dir, "cp sub other", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "cp: -r not specified; omitting directory 'sub'\n"));
+        assert_eq!(run_in(&dir, "cp -r sub other", "").status, 0);
+        assert_eq!(fs::read_to_string(dir.join("other/c.txt")).unwrap(), "c\n");
+        let out = run_in(&dir, "cp -r sub sub/inner", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "cp: cannot copy a directory, 'sub', into itself, 'sub/inner'\n"));
+
+        fs::write(dir.join("copy.txt"), "kept\n").unwrap();
+        assert_eq!(run_in(&dir, "cp -n b.txt copy.txt", "").status, 0);
+        assert_eq!(fs::read_to_string(dir.join("copy.txt")).unwrap(), "kept\n");
+        let out = run_in(&dir, "cp a.txt b.txt copy.txt", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "cp: target 'copy.txt' is not a directory\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mv_renames_and_moves_into_directories() {
+        let dir = tree("mv");
+        let out = run_in(&dir, "mv -v b.txt new.txt", "");
+        assert_eq!((out.status, out.stdout.as_str()), (0, "renamed 'b.txt' -> 'new.txt'\n"));
+        assert!(!dir.join("b.txt").exists() && dir.join("new.txt").is_file());
+        assert_eq!(run_in(&dir, "mv new.txt a.txt sub", "").status, 0);
+        assert!(dir.join("sub/new.txt").is_file() && dir.join("sub/a.txt").is_file());
+        let out = run_in(&dir, "mv sub sub/deeper", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "mv: cannot move 'sub' to a subdirectory of itself, 'sub/deeper'\n"));
+        let out = run_in(&dir, "mv nope x", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "mv: cannot stat 'nope': No such file or directory\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rm_removes_files_and_trees() {
+        let dir = tree("rm");
+        let out = run_in(&dir, "rm -v a.txt", "");
+        assert_eq!((out.status, out.stdout.as_str()), (0, "removed 'a.txt'\n"));
+        let out = run_in(&dir, "rm sub", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "rm: cannot remove 'sub': Is a directory\n"));
+        let out = run_in(&dir, "rm -rv sub", "");
+        assert_eq!((out.status, out.stdout.as_str()), (0, "removed 'sub/c.txt'\nremoved directory 'sub'\n"));
+        assert!(!dir.join("sub").exists());
+
+        assert_eq!(run_in(&dir, "rm -f nope", "").status, 0);
+        let out = run_in(&dir, "rm nope", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "rm: cannot remove 'nope': No such file or directory\n"));
+        let out = run_in(&dir, "rm -rf .", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "rm: refusing to remove '.' or '..' directory: skipping '.'\n"));
+        assert!(dir.join("b.txt").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mkdir_creates_parents_with_p() {
+        let dir = scratch_dir("mkdir");
+        let out = run_in(&dir, "mkdir x/y", "");
+        assert_eq!(out.status, 1);
+        assert!(out.stderr.starts_with("mkdir: cannot create directory 'x/y': "));
+        let out = run_in(&dir, "mkdir -pv x/y", "");
+        assert_eq!((out.status, out.stdout.as_str()), (0, "mkdir: created directory 'x'\nmkdir: created directory 'x/y'\n"));
+        assert!(dir.join("x/y").is_dir());
+        assert_eq!(run_in(&dir, "mkdir -p x/y", "").stdout, "");
+        let out = run_in(&dir, "mkdir -m 99 z", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "mkdir: invalid mode '99'\n"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(run_in(&dir, "mkdir -m 700 z", "").status, 0);
+            assert_eq!(fs::metadata(dir.join("z")).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn find_filters_by_name_type_and_depth() {
+        let dir = tree("find");
+        assert_eq!(run_in(&dir, "find", "").stdout, ".\n./.hidden\n./a.txt\n./b.txt\n./sub\n./sub/c.txt\n");
+        assert_eq!(run_in(&dir, "find . -name *.txt", "").stdout, "./a.txt\n./b.txt\n./sub/c.txt\n");
+        assert_eq!(run_in(&dir, "find sub -iname C.TXT", "").stdout, "sub/c.txt\n");
+        assert_eq!(run_in(&dir, "find -type d", "").stdout, ".\n./sub\n");
+        assert_eq!(run_in(&dir, "find -maxdepth 1 -type f ! -empty", "").stdout, "./b.txt\n");
+        assert_eq!(run_in(&dir, "find -mindepth 2", "").stdout, "./sub/c.txt\n");
+        assert_eq!(run_in(&dir, "find -name a.txt -o -path ./sub*", "").stdout, "./a.txt\n./sub\n./sub/c.txt\n");
+        let out = run_in(&dir, "find -bogus", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "find: unknown predicate '-bogus'\n"));
+        let out = run_in(&dir, "find nope", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "find: 'nope': No such file or directory\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,269 @@
+// src/coreutils/mod.rs
+//
+// Small, portable stand-ins for common utilities, for targets that ship
+// without them (stripped containers, Windows). Enabled with the
+// `coreutils` cargo feature; a shell calls `run` with the command's argv
+// and its three streams.
+
+mod fs;
+mod sys;
+mod text;
+
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Names of the utilities `run` knows.
+pub const UTILITIES: &[&str] = &[
+    "ls", "cat", "cp", "mv", "rm", "mkdir", "head", "tail", "grep", "wc", "ps", "kill", "id",
+    "uname", "which", "find",
+];
+
+/// The streams a utility reads from and writes to, and the directory and
+/// `PATH` it runs with, which need not be the process's own.
+pub struct Streams<'a> {
+    pub stdin: &'a mut dyn Read,
+    pub stdout: &'a mut dyn Write,
+    pub stderr: &'a mut dyn Write,
+    /// Directory relative operands are resolved against.
+    pub cwd: &'a Path,
+    /// `PATH` searched by `which`.
+    pub search_path: Option<&'a OsStr>,
+}
+
+impl Streams<'_> {
+    /// Where an operand refers to: relative to `cwd` unless absolute.
+    fn resolve(&self, operand: impl AsRef<Path>) -> PathBuf {
+        self.cwd.join(operand)
+    }
+}
+
+pub fn is_utility(name: &str) -> bool {
+    UTILITIES.contains(&name)
+}
+
+/// Runs the utility named by `argv[0]` and returns its exit status.
+pub fn run(argv: &[String], io: &mut Streams) -> i32 {
+    let Some(name) = argv.first() else {
+        return 0;
+    };
+    let args = &argv[1..];
+    let status = match name.as_str() {
+        "ls" => fs::ls(args, io),
+        "cat" => fs::cat(args, io),
+        "cp" => fs::cp(args, io),
+        "mv" => fs::mv(args, io),
+        "rm" => fs::rm(args, io),
+        "mkdir" => fs::mkdir(args, io),
+        "find" => fs::find(args, io),
+        "head" => text::head(args, io),
+        "tail" => text::tail(args, io),
+        "grep" => text::grep(args, io),
+        "wc" => text::wc(args, io),
+        "ps" => sys::ps(args, io),
+        "kill" => sys::kill(args, io),
+        "id" => sys::id(args, io),
+        "uname" => sys::uname(args, io),
+        "which" => sys::which(args, io),
+        _ => {
+            writeln!(io.stderr, "{}: not a built-in utility", name).ok();
+            127
+        }
+    };
+    io.stdout.flush().ok();
+    status
+}
+
+/// Short options and operands of a command line.
+struct Opts {
+    /// options in the order given, with their value if they take one
+    flags: Vec<(char, Option<String>)>,
+    operands: Vec<String>,
+}
+
+impl Opts {
+    fn has(&self, flag: char) -> bool {
+        self.flags.iter().any(|(f, _)| *f == flag)
+    }
+
+    /// Value of the last occurrence of `flag`.
+    fn value(&self, flag: char) -> Option<&str> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|(f, _)| *f == flag)
+            .and_then(|(_, v)| v.as_deref())
+    }
+}
+
+/// Parses grouped short options (`-la`, `-n5`, `-n 5`). `allowed` lists
+/// the accepted flags and `with_value` those that take an argument.
+/// Options and operands may be mixed; everything after `--` is an
+/// operand, and so is a lone `-`.
+fn parse_opts(args: &[String], allowed: &str, with_value: &str) -> Result<Opts, String> {
+    let mut opts = Opts { flags: Vec::new(), operands: Vec::new() };
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        if arg == "--" {
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            opts.operands.push(arg.clone());
+            continue;
+        }
+        let flags: Vec<char> = arg[1..].chars().collect();
+        for (j, &flag) in flags.iter().enumerate() {
+            if !allowed.contains(flag) && !with_value.contains(flag) {
+                return Err(format!("invalid option -- '{}'", flag));
+            }
+            if with_value.contains(flag) {
+                let rest: String = flags[j + 1..].iter().collect();
+                let value = if !rest.is_empty() {
+                    rest
+                } else if i < args.len() {
+                    i += 1;
+                    args[i - 1].clone()
+                } else {
+                    return Err(format!("option requires an argument -- '{}'", flag));
+                };
+                opts.flags.push((flag, Some(value)));
+                break;
+            }
+            opts.flags.push((flag, None));
+        }
+    }
+    opts.operands.extend(args[i..].iter().cloned());
+    Ok(opts)
+}
+
+/// Parses options, reporting a bad command line on stderr. Callers exit
+/// with status 2 when this returns `None`.
+fn parse_or_usage(
+    name: &str,
+    args: &[String],
+    allowed: &str,
+    with_value: &str,
+    io: &mut Streams,
+) -> Option<Opts> {
+    match parse_opts(args, allowed, with_value) {
+        Ok(opts) => Some(opts),
+        Err(e) => {
+            writeln!(io.stderr, "{}: {}", name, e).ok();
+            None
+        }
+    }
+}
+
+/// An I/O error as the utilities print it: the message without the
+/// trailing "(os error N)".
+fn error_text(e: &io::Error) -> String {
+    let text = e.to_string();
+    match text.find(" (os error") {
+        Some(pos) => text[..pos].to_string(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    /// A fresh, empty directory under the system temp dir.
+    pub(super) fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("coreutils_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// What a utility wrote and the status it exited with.
+    pub(super) struct Output {
+        pub status: i32,
+        pub stdout: String,
+        pub stderr: String,
+    }
+
+    /// Runs `argv` (split on spaces) in `cwd`, feeding it `stdin`.
+    pub(super) fn run_in(cwd: &Path, argv: &str, stdin: &str) -> Output {
+        let argv: Vec<String> = argv.split(' ').map(String::from).collect();
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let status = run(
+            &argv,
+            &mut Streams {
+                stdin: &mut stdin.as_bytes(),
+                stdout: &mut stdout,
+                stderr: &mut stderr,
+                cwd,
+                search_path: None,
+            },
+        );
+        Output {
+            status,
+            stdout: String::from_utf8(stdout).unwrap(),
+            stderr: String::from_utf8(stderr).unwrap(),
+        }
+    }
+
+    fn parse(args: &[&str], allowed: &str, with_value: &str) -> Result<Opts, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_opts(&args, allowed, with_value)
+    }
+
+    fn flags(opts: &Opts) -> Vec<(char, Option<&str>)> {
+        opts.flags.iter().map(|(f, v)| (*f, v.as_deref())).collect()
+    }
+
+    #[test]
+    fn grouped_flags_are_split() {
+        let opts = parse(&["-la", "x", "-h"], "alh", "").unwrap();
+        assert_eq!(flags(&opts), [('l', None), ('a', None), ('h', None)]);
+        assert_eq!(opts.operands, ["x"]);
+        assert!(opts.has('a') && !opts.has('R'));
+    }
+
+    #[test]
+    fn values_attached_or_separate() {
+        let opts = parse(&["-n5", "-vn", "7", "-n", "9", "f"], "v", "n").unwrap();
+        assert_eq!(flags(&opts), [('n', Some("5")), ('v', None), ('n', Some("7")), ('n', Some("9"))]);
+        assert_eq!(opts.value('n'), Some("9"));
+        assert_eq!(opts.value('v'), None);
+        assert_eq!(opts.operands, ["f"]);
+        // a value may itself start with a dash
+        assert_eq!(parse(&["-e", "-x"], "", "e").unwrap().value('e'), Some("-x"));
+    }
+
+    #[test]
+    fn double_dash_and_lone_dash_are_operands() {
+        let opts = parse(&["-a", "--", "-b", "--"], "ab", "").unwrap();
+        assert_eq!(flags(&opts), [('a', None)]);
+        assert_eq!(opts.operands, ["-b", "--"]);
+        let opts = parse(&["-", "-a", "-"], "a", "").unwrap();
+        assert_eq!(opts.operands, ["-", "-"]);
+    }
+
+    #[test]
+    fn bad_command_lines_are_errors() {
+        assert_eq!(parse(&["-ax"], "a", "").err().as_deref(), Some("invalid option -- 'x'"));
+        assert_eq!(parse(&["-an"], "a", "n").err().as_deref(), Some("option requires an argument -- 'n'"));
+        let out = run_in(Path::new("."), "wc -z", "");
+        assert_eq!((out.status, out.stderr.as_str()), (2, "wc: invalid option -- 'z'\n"));
+    }
+
+    #[test]
+    fn unknown_utilities_exit_127() {
+        let out = run_in(Path::new("."), "frobnicate", "");
+        assert_eq!((out.status, out.stderr.as_str()), (127, "frobnicate: not a built-in utility\n"));
+        assert!(is_utility("grep") && !is_utility("sort"));
+    }
+
+    #[test]
+    fn os_error_numbers_are_dropped() {
+        let e = io::Error::from_raw_os_error(2);
+        assert!(e.to_string().contains("(os error 2)"));
+        assert!(!error_text(&e).contains("os error"));
+        assert_eq!(error_text(&io::Error::other("Is a directory")), "Is a directory");
+    }
+}
@@ -0,0 +1,556 @@
+// src/coreutils/sys.rs
+//
+// System utilities: ps, kill, id, uname and which.
+
+use super::{parse_or_usage, Streams};
+use std::env;
+
+////////////////////////////////////////////////////////////////////////////////
+// ps
+////////////////////////////////////////////////////////////////////////////////
+
+/// `ps [-eAf] [-p pid,...]` or BSD-style `ps aux`. Reads /proc, so it
+/// only works on Linux.
+pub fn ps(args: &[String], io: &mut Streams) -> i32 {
+    let mut pids: Vec<i32> = Vec::new();
+    let (all, full) = match args.first() {
+        // BSD style: `ps aux`, `ps ax`
+        Some(bsd) if !bsd.starts_with('-') && args.len() == 1 => {
+            (bsd.contains('a') || bsd.contains('x'), bsd.contains('u'))
+        }
+        _ => {
+            let Some(opts) = parse_or_usage("ps", args, "eAf", "p", io) else {
+                return 2;
+            };
+            for (flag, value) in &opts.flags {
+                if let ('p', Some(list)) = (flag, value) {
+                    for pid in list.split(',').filter(|p| !p.is_empty()) {
+                        match pid.trim().parse() {
+                            Ok(pid) => pids.push(pid),
+                            Err(_) => {
+                                writeln!(io.stderr, "ps: invalid process id: {}", pid).ok();
+                                return 1;
+                            }
+                        }
+                    }
+                }
+            }
+            (opts.has('e') || opts.has('A'), opts.has('f'))
+        }
+    };
+    list_processes(all, full, &pids, io)
+}
+
+#[cfg(target_os = "linux")]
+struct Process {
+    pid: i32,
+    ppid: i32,
+    uid: u32,
+    tty: String,
+    cpu_secs: u64,
+    comm: String,
+    cmdline: String,
+}
+
+#[cfg(target_os = "linux")]
+fn list_processes(all: bool, full: bool, pids: &[i32], io: &mut Streams) -> i32 {
+    let read = match std::fs::read_dir("/proc") {
+        Ok(read) => read,
+        Err(e) => {
+            writeln!(io.stderr, "ps: cannot read /proc: {}", super::error_text(&e)).ok();
+            return 1;
+        }
+    };
+    let me = nix::unistd::geteuid().as_raw();
+    let mut procs: Vec<Process> = read
+        .flatten()
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse().ok()))
+        .filter_map(read_process)
+        .filter(|p| {
+            if !pids.is_empty() {
+                pids.contains(&p.pid)
+            } else {
+                all || p.uid == me
+            }
+        })
+        .collect();
+    procs.sort_by_key(|p| p.pid);
+
+    if full {
+        writeln!(io.stdout, "{:<8} {:>7} {:>7} {:<8} {:>8} CMD", "UID", "PID", "PPID", "TTY", "TIME").ok();
+    } else {
+        writeln!(io.stdout, "{:>7} {:<8} {:>8} CMD", "PID", "TTY", "TIME").ok();
+    }
+    for p in &procs {
+        let time = format!("{:02}:{:02}:{:02}", p.cpu_secs / 3600, p.cpu_secs % 3600 / 60, p.cpu_secs % 60);
+        if full {
+            let user = nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(p.uid))
+                .ok()
+                .flatten()
+                .map(|u| u.name)
+                .unwrap_or_else(|| p.uid.to_string());
+            writeln!(io.stdout, "{:<8} {:>7} {:>7} {:<8} {:>8} {}", user, p.pid, p.ppid, p.tty, time, p.cmdline).ok();
+        } else {
+            writeln!(io.stdout, "{:>7} {:<8} {:>8} {}", p.pid, p.tty, time, p.comm).ok();
+        }
+    }
+    if !pids.is_empty() && procs.is_empty() {
+        1
+    } else {
+        0
+    }
+}
+
+/// Reads one process from /proc/<pid>; `None` if it has gone away.
+#[cfg(target_os = "linux")]
+fn read_process(pid: i32) -> Option<Process> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name is in parentheses and may itself contain them
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let comm = stat[open + 1..close].to_string();
+    let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+    let ppid = fields.get(1)?.parse().ok()?;
+    let tty_nr: u32 = fields.get(4)?.parse().ok()?;
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let uid = status
+        .lines()
+        .find_map(|l| l.strip_prefix("Uid:"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|u| u.parse().ok())
+        .unwrap_or(0);
+
+    let raw = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let args: Vec<String> = raw
+        .split(|&b| b == 0)
+        .filter(|a| !a.is_empty())
+        .map(|a| String::from_utf8_lossy(a).to_string())
+        .collect();
+    let cmdline = if args.is_empty() { format!("[{}]", comm) } else { args.join(" ") };
+
+    let ticks = nix::unistd::sysconf(nix::unistd::SysconfVar::CLK_TCK)
+        .ok()
+        .flatten()
+        .filter(|&t| t > 0)
+        .unwrap_or(100) as u64;
+
+    Some(Process {
+        pid,
+        ppid,
+        uid,
+        tty: tty_name(tty_nr),
+        cpu_secs: (utime + stime) / ticks,
+        comm,
+        cmdline,
+    })
+}
+
+/// Names a controlling terminal from the `tty_nr` field of /proc stat.
+#[cfg(target_os = "linux")]
+fn tty_name(tty_nr: u32) -> String {
+    let major = (tty_nr >> 8) & 0xfff;
+    let minor = (tty_nr & 0xff) | ((tty_nr >> 12) & 0xfff00);
+    match major {
+        0 => "?".to_string(),
+        4 if minor < 64 => format!("tty{}", minor),
+        4 => format!("ttyS{}", minor - 64),
+        136..=143 => format!("pts/{}", (major - 136) * 256 + minor),
+        _ => format!("{}:{}", major, minor),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn list_processes(_all: bool, _full: bool, _pids: &[i32], io: &mut Streams) -> i32 {
+    writeln!(io.stderr, "ps: not supported on this platform").ok();
+    1
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// kill
+////////////////////////////////////////////////////////////////////////////////
+
+/// `kill [-s sig | -sig] pid...` and `kill -l`. On Windows every signal
+/// terminates the process.
+pub fn kill(args: &[String], io: &mut Streams) -> i32 {
+    if args.first().map(String::as_str) == Some("-l") {
+        writeln!(io.stdout, "{}", signal_names().join(" ")).ok();
+        return 0;
+    }
+
+    let mut signal = "TERM".to_string();
+    let mut rest = args;
+    match args.first().map(String::as_str) {
+        Some("-s") => match args.get(1) {
+            Some(name) => {
+                signal = name.clone();
+                rest = &args[2..];
+            }
+            None => {
+                writeln!(io.stderr, "kill: option requires an argument -- 's'").ok();
+                return 2;
+            }
+        },
+        Some("--") => rest = &args[1..],
+        Some(arg) if arg.starts_with('-') && arg.len() > 1 => {
+            signal = arg[1..].to_string();
+            rest = &args[1..];
+        }
+        _ => {}
+    }
+    if rest.first().map(String::as_str) == Some("--") {
+        rest = &rest[1..];
+    }
+    if rest.is_empty() {
+        writeln!(io.stderr, "Usage: kill [-s sigspec | -sigspec] pid... | kill -l").ok();
+        return 2;
+    }
+
+    let mut status = 0;
+    for pid in rest {
+        let result = match pid.parse::<i32>() {
+            Ok(pid) => send_signal(pid, &signal),
+            Err(_) => Err(format!("{}: arguments must be process IDs", pid)),
+        };
+        if let Err(e) = result {
+            writeln!(io.stderr, "kill: {}", e).ok();
+            status = 1;
+        }
+    }
+    status
+}
+
+#[cfg(unix)]
+fn signal_names() -> Vec<String> {
+    nix::sys::signal::Signal::iterator()
+        .map(|s| s.as_str().trim_start_matches("SIG").to_string())
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn signal_names() -> Vec<String> {
+    vec!["TERM".to_string(), "KILL".to_string()]
+}
+
+#[cfg(unix)]
+fn send_signal(pid: i32, signal: &str) -> Result<(), String> {
+    use nix::sys::signal::{self, Signal};
+    use nix::unistd::Pid;
+    use std::str::FromStr;
+
+    let sig = match signal.parse::<i32>() {
+        Ok(0) => None,
+        Ok(n) => Some(Signal::try_from(n).map_err(|_| format!("{}: invalid signal specification", signal))?),
+        Err(_) => {
+            let name = signal.to_uppercase();
+            let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+            Some(Signal::from_str(&name).map_err(|_| format!("{}: invalid signal specification", signal))?)
+        }
+    };
+    signal::kill(Pid::from_raw(pid), sig).map_err(|e| format!("({}) - {}", pid, e.desc()))
+}
+
+#[cfg(windows)]
+fn send_signal(pid: i32, _signal: &str) -> Result<(), String> {
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+    use winapi::um::winnt::PROCESS_TERMINATE;
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_TERMINATE, 0, pid as u32);
+        if handle.is_null() {
+            return Err(format!("({}) - {}", pid, std::io::Error::last_os_error()));
+        }
+        let ok = TerminateProcess(handle, 1);
+        CloseHandle(handle);
+        if ok == 0 {
+            return Err(format!("({}) - {}", pid, std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// id / uname
+////////////////////////////////////////////////////////////////////////////////
+
+/// `id [-ugGnr] [user]`
+#[cfg(unix)]
+pub fn id(args: &[String], io: &mut Streams) -> i32 {
+    use nix::unistd::{self, Gid, Group, Uid, User};
+
+    let Some(opts) = parse_or_usage("id", args, "ugGnr", "", io) else {
+        return 2;
+    };
+    let (uid, gid, groups) = match opts.operands.first() {
+        Some(name) => match User::from_name(name) {
+            Ok(Some(user)) => {
+                let groups = user_groups(&user);
+                (user.uid, user.gid, groups)
+            }
+            _ => {
+                writeln!(io.stderr, "id: '{}': no such user", name).ok();
+                return 1;
+            }
+        },
+        None if opts.has('r') => (unistd::getuid(), unistd::getgid(), unistd::getgroups().unwrap_or_default()),
+        None => (unistd::geteuid(), unistd::getegid(), unistd::getgroups().unwrap_or_default()),
+    };
+    // the primary group is always listed first
+    let groups: Vec<Gid> = std::iter::once(gid).chain(groups.into_iter().filter(|&g| g != gid)).collect();
+    let user_name = |uid: Uid| User::from_uid(uid).ok().flatten().map(|u| u.name);
+    let group_name = |gid: Gid| Group::from_gid(gid).ok().flatten().map(|g| g.name);
+    let names = opts.has('n');
+
+    if opts.has('u') {
+        let name = user_name(uid).filter(|_| names);
+        writeln!(io.stdout, "{}", name.unwrap_or_else(|| uid.to_string())).ok();
+    } else if opts.has('g') {
+        let name = group_name(gid).filter(|_| names);
+        writeln!(io.stdout, "{}", name.unwrap_or_else(|| gid.to_string())).ok();
+    } else if opts.has('G') {
+        let list: Vec<String> = groups
+            .iter()
+            .map(|&g| group_name(g).filter(|_| names).unwrap_or_else(|| g.to_string()))
+            .collect();
+        writeln!(io.stdout, "{}", list.join(" ")).ok();
+    } else {
+        let with_name = |id: String, name: Option<String>| match name {
+            Some(name) => format!("{}({})", id, name),
+            None => id,
+        };
+        let list: Vec<String> = groups.iter().map(|&g| with_name(g.to_string(), group_name(g))).collect();
+        writeln!(
+            io.stdout,
+            "uid={} gid={} groups={}",
+            with_name(uid.to_string(), user_name(uid)),
+            with_name(gid.to_string(), group_name(gid)),
+            list.join(",")
+        )
+        .ok();
+    }
+    0
+}
+
+#[cfg(all(unix, not(target_vendor = "apple")))]
+fn user_groups(user: &nix::unistd::User) -> Vec<nix::unistd::Gid> {
+    std::ffi::CString::new(user.name.as_str())
+        .ok()
+        .and_then(|name| nix::unistd::getgrouplist(&name, user.gid).ok())
+        .unwrap_or_else(|| vec![user.gid])
+}
+
+#[cfg(target_vendor = "apple")]
+fn user_groups(user: &nix::unistd::User) -> Vec<nix::unistd::Gid> {
+    vec![user.gid]
+}
+
+#[cfg(not(unix))]
+pub fn id(_args: &[String], io: &mut Streams) -> i32 {
+    writeln!(io.stderr, "id: not supported on this platform").ok();
+    1
+}
+
+/// `uname [-asnrvmo]`
+pub fn uname(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("uname", args, "asnrvmo", "", io) else {
+        return 2;
+    };
+    let info = system_info();
+    let fields = ['s', 'n', 'r', 'v', 'm', 'o'];
+    let wanted: Vec<usize> = if opts.has('a') {
+        (0..fields.len()).collect()
+    } else {
+        let picked: Vec<usize> = (0..fields.len()).filter(|&i| opts.has(fields[i])).collect();
+        if picked.is_empty() { vec![0] } else { picked }
+    };
+    let parts: Vec<&str> = wanted.iter().map(|&i| info[i].as_str()).collect();
+    writeln!(io.stdout, "{}", parts.join(" ")).ok();
+    0
+}
+
+/// Kernel name, node name, release, version, machine and OS name.
+#[cfg(unix)]
+fn system_info() -> [String; 6] {
+    let os = if cfg!(target_os = "linux") { "GNU/Linux" } else { env::consts::OS };
+    match nix::sys::utsname::uname() {
+        Ok(u) => [
+            u.sysname().to_string_lossy().to_string(),
+            u.nodename().to_string_lossy().to_string(),
+            u.release().to_string_lossy().to_string(),
+            u.version().to_string_lossy().to_string(),
+            u.machine().to_string_lossy().to_string(),
+            os.to_string(),
+        ],
+        Err(_) => {
+            let unknown = || "unknown".to_string();
+            [env::consts::OS.to_string(), unknown(), unknown(), unknown(), env::consts::ARCH.to_string(), os.to_string()]
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn system_info() -> [String; 6] {
+    let unknown = || "unknown".to_string();
+    [
+        "Windows_NT".to_string(),
+        env::var("COMPUTERNAME").unwrap_or_else(|_| unknown()),
+        unknown(),
+        unknown(),
+        env::consts::ARCH.to_string(),
+        "MS/Windows".to_string(),
+    ]
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// which
+////////////////////////////////////////////////////////////////////////////////
+
+/// `which [-a] name...`; exits 1 if any name is not found.
+pub fn which(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("which", args, "a", "", io) else {
+        return 2;
+    };
+    let mut status = 0;
+    for name in &opts.operands {
+        let found = crate::common::find_in(name, io.search_path, io.cwd, opts.has('a'));
+        if found.is_empty() {
+            status = 1;
+        }
+        for path in found {
+            writeln!(io.stdout, "{}", path.display()).ok();
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coreutils::tests::{run_in, scratch_dir};
+    use std::ffi::OsStr;
+    use std::path::Path;
+
+    fn run(argv: &str) -> crate::coreutils::tests::Output {
+        run_in(Path::new("."), argv, "")
+    }
+
+    #[test]
+    fn uname_prints_the_selected_fields() {
+        let info = system_info();
+        assert_eq!(run("uname").stdout, format!("{}\n", info[0]));
+        assert_eq!(run("uname -m").stdout, format!("{}\n", info[4]));
+        assert_eq!(run("uname -sr").stdout, format!("{} {}\n", info[0], info[2]));
+        assert_eq!(run("uname -a").stdout, format!("{}\n", info.join(" ")));
+    }
+
+    #[test]
+    fn kill_lists_signals_and_rejects_bad_operands() {
+        let out = run("kill -l");
+        assert_eq!(out.status, 0);
+        assert!(out.stdout.split_whitespace().any(|s| s == "KILL"));
+        let out = run("kill abc");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "kill: abc: arguments must be process IDs\n"));
+        assert_eq!(run("kill").status, 2);
+        assert_eq!(run("kill -s").status, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kill_sends_signals() {
+        let me = std::process::id();
+        // signal 0 only checks that the process exists
+        assert_eq!(run(&format!("kill -0 {}", me)).status, 0);
+        assert_eq!(run(&format!("kill -s 0 {}", me)).status, 0);
+        let out = run(&format!("kill -NOSUCH {}", me));
+        assert_eq!((out.status, out.stderr.as_str()), (1, "kill: NOSUCH: invalid signal specification\n"));
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        assert_eq!(run(&format!("kill -KILL {}", child.id())).status, 0);
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(child.wait().unwrap().signal(), Some(9));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn id_prints_ids_and_names() {
+        let uid = nix::unistd::geteuid();
+        let gid = nix::unistd::getegid();
+        assert_eq!(run("id -u").stdout, format!("{}\n", uid));
+        assert_eq!(run("id -g").stdout, format!("{}\n", gid));
+        assert!(run("id -G").stdout.starts_with(&gid.to_string()));
+        let out = run("id").stdout;
+        assert!(out.starts_with(&format!("uid={}", uid)), "{}", out);
+        assert!(out.contains(&format!(" gid={}", gid)), "{}", out);
+        if let Ok(Some(user)) = nix::unistd::User::from_uid(uid) {
+            assert_eq!(run("id -un").stdout, format!("{}\n", user.name));
+            assert_eq!(run(&format!("id -u {}", user.name)).stdout, format!("{}\n", uid));
+        }
+        let out = run("id no-such-user-xyz");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "id: 'no-such-user-xyz': no such user\n"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn ps_lists_the_requested_processes() {
+        let me = std::process::id();
+        let out = run(&format!("ps -p {}", me)).stdout;
+        let mut lines = out.lines();
+        assert_eq!(lines.next().unwrap().split_whitespace().collect::<Vec<_>>(), ["PID", "TTY", "TIME", "CMD"]);
+        assert_eq!(lines.next().unwrap().split_whitespace().next(), Some(me.to_string().as_str()));
+        assert_eq!(lines.next(), None);
+        let out = run(&format!("ps -f -p {}", me)).stdout;
+        assert!(out.starts_with("UID "));
+        assert_eq!(out.lines().nth(1).unwrap().split_whitespace().nth(1), Some(me.to_string().as_str()));
+        assert_eq!(run("ps -p 0").status, 1);
+        assert_eq!(run("ps -p x").stderr, "ps: invalid process id: x\n");
+        assert_eq!(tty_name(0), "?");
+        assert_eq!(tty_name((136 << 8) | 3), "pts/3");
+        assert_eq!(tty_name((4 << 8) | 65), "ttyS1");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn which_searches_the_given_path() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("which");
+        for sub in ["a", "b"] {
+            executable(&dir.join(sub).join("tool"));
+        }
+        std::fs::write(dir.join("a/plain"), "").unwrap();
+        std::fs::set_permissions(dir.join("a/plain"), std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let search = std::env::join_paths([dir.join("a"), dir.join("b")]).unwrap();
+        let which = |argv: &str| {
+            let argv: Vec<String> = argv.split(' ').map(String::from).collect();
+            let mut out = Vec::new();
+            let status = crate::coreutils::run(
+                &argv,
+                &mut crate::coreutils::Streams {
+                    stdin: &mut std::io::empty(),
+                    stdout: &mut out,
+                    stderr: &mut std::io::sink(),
+                    cwd: &dir,
+                    search_path: Some(OsStr::new(&search)),
+                },
+            );
+            (status, String::from_utf8(out).unwrap())
+        };
+        let (a, b) = (dir.join("a/tool"), dir.join("b/tool"));
+        assert_eq!(which("which tool"), (0, format!("{}\n", a.display())));
+        assert_eq!(which("which -a tool"), (0, format!("{}\n{}\n", a.display(), b.display())));
+        assert_eq!(which("which plain").0, 1);
+        assert_eq!(which("which tool nothing"), (1, format!("{}\n", a.display())));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// An empty executable at `path`.
+    #[cfg(unix)]
+    fn executable(path: &Path) {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
@@ -0,0 +1,672 @@
+// src/coreutils/text.rs
+//
+// Text utilities: head, tail, grep and wc.
+
+use super::{error_text, parse_or_usage, Streams};
+use regex::bytes::{Regex, RegexBuilder};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+/// Rewrites the old `-N` shorthand of head/tail into `-n N`.
+fn expand_count_shorthand(args: &[String]) -> Vec<String> {
+    let mut out = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('-') {
+            Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+                out.push("-n".to_string());
+                out.push(n.to_string());
+            }
+            _ => out.push(arg.clone()),
+        }
+    }
+    out
+}
+
+/// Opens an operand for reading, relative to `cwd`; `-` is stdin.
+fn open_input<'a>(op: &str, cwd: &Path, stdin: &'a mut dyn Read) -> io::Result<Box<dyn Read + 'a>> {
+    if op == "-" {
+        return Ok(Box::new(stdin));
+    }
+    let path = cwd.join(op);
+    if path.is_dir() {
+        return Err(io::Error::other("Is a directory"));
+    }
+    Ok(Box::new(fs::File::open(path)?))
+}
+
+/// Name shown for an operand in headers and prefixes.
+fn display_name(op: &str) -> &str {
+    if op == "-" {
+        "(standard input)"
+    } else {
+        op
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// head / tail
+////////////////////////////////////////////////////////////////////////////////
+
+/// A `-n`/`-c` count, optionally `+N` (from the start) for tail.
+enum Count {
+    Lines(usize, bool),
+    Bytes(usize, bool),
+}
+
+fn parse_count(name: &str, opts: &super::Opts, io: &mut Streams) -> Option<Count> {
+    let (value, bytes) = match (opts.value('c'), opts.value('n')) {
+        (Some(c), _) => (c, true),
+        (None, Some(n)) => (n, false),
+        (None, None) => return Some(Count::Lines(10, false)),
+    };
+    let (digits, from_start) = match value.strip_prefix('+') {
+        Some(rest) => (rest, true),
+        None => (value, false),
+    };
+    match digits.parse::<usize>() {
+        Ok(n) if bytes => Some(Count::Bytes(n, from_start)),
+        Ok(n) => Some(Count::Lines(n, from_start)),
+        Err(_) => {
+            let what = if bytes { "bytes" } else { "lines" };
+            writeln!(io.stderr, "{}: invalid number of {}: '{}'", name, what, value).ok();
+            None
+        }
+    }
+}
+
+/// Runs head or tail over each operand, with `==> name <==` headers
+/// when there is more than one (or `-v`), unless `-q`.
+fn head_tail(
+    name: &str,
+    args: &[String],
+    io: &mut Streams,
+    each: fn(&mut dyn Read, &mut dyn Write, &Count) -> io::Result<()>,
+) -> i32 {
+    let args = expand_count_shorthand(args);
+    let Some(opts) = parse_or_usage(name, &args, "qv", "nc", io) else {
+        return 2;
+    };
+    let Some(count) = parse_count(name, &opts, io) else {
+        return 1;
+    };
+    let mut operands = opts.operands.clone();
+    if operands.is_empty() {
+        operands.push("-".to_string());
+    }
+    let headers = (operands.len() > 1 || opts.has('v')) && !opts.has('q');
+
+    let mut status = 0;
+    // headers after the first one printed are set off by a blank line
+    let mut first = true;
+    for op in &operands {
+        let result = open_input(op, io.cwd, &mut *io.stdin).and_then(|mut input| {
+            if headers {
+                let sep = if first { "" } else { "\n" };
+                first = false;
+                writeln!(io.stdout, "{}==> {} <==", sep, display_name(op))?;
+            }
+            each(&mut input, &mut *io.stdout, &count)
+        });
+        if let Err(e) = result {
+            writeln!(io.stderr, "{}: cannot open '{}' for reading: {}", name, op, error_text(&e)).ok();
+            status = 1;
+        }
+    }
+    status
+}
+
+/// `head [-qv] [-n lines | -c bytes | -N] [file...]`
+pub fn head(args: &[String], io: &mut Streams) -> i32 {
+    head_tail("head", args, io, |input, out, count| match *count {
+        Count::Bytes(n, _) => io::copy(&mut input.take(n as u64), out).map(|_| ()),
+        Count::Lines(n, _) => {
+            let mut reader = BufReader::new(input);
+            let mut line = Vec::new();
+            for _ in 0..n {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+                out.write_all(&line)?;
+            }
+            Ok(())
+        }
+    })
+}
+
+/// `tail [-qv] [-n [+]lines | -c [+]bytes | -N] [file...]`
+pub fn tail(args: &[String], io: &mut Streams) -> i32 {
+    head_tail("tail", args, io, |input, out, count| {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let start = match *count {
+            Count::Bytes(n, true) => n.saturating_sub(1).min(data.len()),
+            Count::Bytes(n, false) => data.len().saturating_sub(n),
+            Count::Lines(n, true) => {
+                // skip the first n - 1 lines
+                let mut start = 0;
+                for _ in 1..n {
+                    match data[start..].iter().position(|&b| b == b'\n') {
+                        Some(pos) => start += pos + 1,
+                        None => start = data.len(),
+                    }
+                }
+                start
+            }
+            Count::Lines(n, false) => {
+                // a final line without a newline still counts
+                let end = if data.last() == Some(&b'\n') { data.len() - 1 } else { data.len() };
+                let mut start = end;
+                let mut seen = 0;
+                while start > 0 && n > 0 {
+                    if data[start - 1] == b'\n' {
+                        seen += 1;
+                        if seen == n {
+                            break;
+                        }
+                    }
+                    start -= 1;
+                }
+                if n == 0 { data.len() } else { start }
+            }
+        };
+        out.write_all(&data[start..])
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// grep
+////////////////////////////////////////////////////////////////////////////////
+
+struct GrepOptions {
+    invert: bool,
+    line_numbers: bool,
+    count: bool,
+    files_with_matches: bool,
+    files_without_match: bool,
+    only_matching: bool,
+    quiet: bool,
+    no_messages: bool,
+    show_names: bool,
+}
+
+/// `grep [-ivnclLrRqsHhwxoEF] [-e pattern]... [pattern] [file...]`.
+/// Patterns are basic regular expressions unless `-E` or `-F` is given.
+pub fn grep(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("grep", args, "ivnclLrRqsHhwxoEF", "e", io) else {
+        return 2;
+    };
+    let mut operands = opts.operands.clone();
+    let mut patterns: Vec<String> = opts
+        .flags
+        .iter()
+        .filter(|(f, _)| *f == 'e')
+        .filter_map(|(_, v)| v.clone())
+        .collect();
+    if patterns.is_empty() {
+        if operands.is_empty() {
+            writeln!(io.stderr, "Usage: grep [OPTION]... PATTERNS [FILE]...").ok();
+            return 2;
+        }
+        patterns.push(operands.remove(0));
+    }
+    let regex = match build_regex(&patterns, &opts) {
+        Ok(regex) => regex,
+        Err(e) => {
+            writeln!(io.stderr, "grep: {}", e).ok();
+            return 2;
+        }
+    };
+
+    let recursive = opts.has('r') || opts.has('R');
+    if operands.is_empty() {
+        operands.push(if recursive { "." } else { "-" }.to_string());
+    }
+    let o = GrepOptions {
+        invert: opts.has('v'),
+        line_numbers: opts.has('n'),
+        count: opts.has('c'),
+        files_with_matches: opts.has('l'),
+        files_without_match: opts.has('L'),
+        only_matching: opts.has('o'),
+        quiet: opts.has('q'),
+        no_messages: opts.has('s'),
+        show_names: !opts.has('h') && (opts.has('H') || operands.len() > 1 || recursive),
+    };
+
+    let mut matched = false;
+    let mut failed = false;
+    for op in &operands {
+        let mut files = Vec::new();
+        if recursive && op != "-" && io.resolve(op).is_dir() {
+            collect_files(&io.resolve(op), op, &mut files);
+        } else {
+            files.push(op.clone());
+        }
+        for file in files {
+            match open_input(&file, io.cwd, &mut *io.stdin).and_then(|input| grep_stream(input, &file, &regex, &o, io.stdout)) {
+                Ok(found) => matched |= found,
+                Err(e) => {
+                    if !o.no_messages {
+                        writeln!(io.stderr, "grep: {}: {}", file, error_text(&e)).ok();
+                    }
+                    failed = true;
+                }
+            }
+            if matched && o.quiet {
+                return 0;
+            }
+        }
+    }
+    if failed {
+        2
+    } else if matched {
+        0
+    } else {
+        1
+    }
+}
+
+fn build_regex(patterns: &[String], opts: &super::Opts) -> Result<Regex, regex::Error> {
+    let parts: Vec<String> = patterns
+        .iter()
+        .map(|p| {
+            let p = if opts.has('F') { regex::escape(p) } else { to_regex_syntax(p, !opts.has('E')) };
+            if opts.has('x') {
+                format!("^(?:{})$", p)
+            } else if opts.has('w') {
+                format!(r"\b(?:{})\b", p)
+            } else {
+                p
+            }
+        })
+        .collect();
+    RegexBuilder::new(&parts.join("|"))
+        .case_insensitive(opts.has('i'))
+        .unicode(false)
+        .build()
+}
+
+/// Translates a POSIX basic (`basic`) or extended regular expression into
+/// the syntax of the regex crate: BRE `\( \) \{ \} \| \+ \?` become
+/// operators and their bare forms literals, `\<`/`\>` become `\b`, and
+/// backslashes inside brackets are literal.
+fn to_regex_syntax(pattern: &str, basic: bool) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    // true where `*` would have nothing to repeat
+    let mut at_start = true;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        match c {
+            '\\' if i < chars.len() => {
+                let next = chars[i];
+                i += 1;
+                match next {
+                    '(' | ')' | '{' | '}' | '|' | '+' | '?' if basic => out.push(next),
+                    '<' | '>' => out.push_str(r"\b"),
+                    _ => {
+                        out.push('\\');
+                        out.push(next);
+                    }
+                }
+                at_start = basic && (next == '(' || next == '|');
+                continue;
+            }
+            '\\' => out.push_str(r"\\"),
+            '(' | ')' | '{' | '}' | '|' | '+' | '?' if basic => {
+                out.push('\\');
+                out.push(c);
+            }
+            '*' if at_start => out.push_str(r"\*"),
+            '[' => {
+                // copy the bracket expression, keeping `]` first as a literal
+                out.push('[');
+                if chars.get(i) == Some(&'^') {
+                    out.push('^');
+                    i += 1;
+                }
+                if chars.get(i) == Some(&']') {
+                    out.push_str(r"\]");
+                    i += 1;
+                }
+                while i < chars.len() && chars[i] != ']' {
+                    if chars[i] == '[' && chars.get(i + 1) == Some(&':') {
+                        // a class like [:alpha:] is copied whole
+                        let end = chars[i..].iter().collect::<String>().find(":]").map(|e| i + e + 2);
+                        if let Some(end) = end {
+                            out.extend(&chars[i..end]);
+                            i = end;
+                            continue;
+                        }
+                    }
+                    match chars[i] {
+                        '\\' | '[' | '&' | '~' => {
+                            out.push('\\');
+                            out.push(chars[i]);
+                        }
+                        ch => out.push(ch),
+                    }
+                    i += 1;
+                }
+                out.push(']');
+                i += 1;
+            }
+            _ => out.push(c),
+        }
+        at_start = !basic && (c == '(' || c == '|');
+    }
+    out
+}
+
+/// Files under a directory for `grep -r`, in name order.
+fn collect_files(dir: &Path, shown: &str, files: &mut Vec<String>) {
+    let Ok(read) = fs::read_dir(dir) else {
+        files.push(shown.to_string());
+        return;
+    };
+    let mut entries: Vec<_> = read.flatten().collect();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let child = format!("{}/{}", shown.trim_end_matches('/'), entry.file_name().to_string_lossy());
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => collect_files(&entry.path(), &child, files),
+            Ok(t) if t.is_file() => files.push(child),
+            _ => {}
+        }
+    }
+}
+
+/// Searches one input; returns whether any line was selected.
+fn grep_stream(input: Box<dyn Read + '_>, name: &str, regex: &Regex, o: &GrepOptions, out: &mut dyn Write) -> io::Result<bool> {
+    let mut reader = BufReader::new(input);
+    let name = display_name(name);
+    let mut line = Vec::new();
+    let mut number = 0u64;
+    let mut selected = 0u64;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        number += 1;
+        let body = line.strip_suffix(b"\n").unwrap_or(&line);
+        if regex.is_match(body) == o.invert {
+            continue;
+        }
+        selected += 1;
+        if o.quiet || o.files_with_matches || o.files_without_match {
+            break;
+        }
+        if o.count {
+            continue;
+        }
+        let prefix = match (o.show_names, o.line_numbers) {
+            (true, true) => format!("{}:{}:", name, number),
+            (true, false) => format!("{}:", name),
+            (false, true) => format!("{}:", number),
+            (false, false) => String::new(),
+        };
+        if o.only_matching && !o.invert {
+            for m in regex.find_iter(body).filter(|m| !m.as_bytes().is_empty()) {
+                out.write_all(prefix.as_bytes())?;
+                out.write_all(m.as_bytes())?;
+                out.write_all(b"\n")?;
+            }
+        } else {
+            out.write_all(prefix.as_bytes())?;
+            out.write_all(body)?;
+            out.write_all(b"\n")?;
+        }
+    }
+
+    if o.quiet {
+        // nothing is printed
+    } else if o.files_with_matches {
+        if selected > 0 {
+            writeln!(out, "{}", name)?;
+        }
+    } else if o.files_without_match {
+        if selected == 0 {
+            writeln!(out, "{}", name)?;
+        }
+    } else if o.count {
+        if o.show_names {
+            write!(out, "{}:", name)?;
+        }
+        writeln!(out, "{}", selected)?;
+    }
+    Ok(selected > 0)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// wc
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    lines: u64,
+    words: u64,
+    chars: u64,
+    bytes: u64,
+}
+
+fn count_stream(input: &mut dyn Read) -> io::Result<Counts> {
+    let mut counts = Counts::default();
+    let mut in_word = false;
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            return Ok(counts);
+        }
+        for &b in &buf[..n] {
+            counts.bytes += 1;
+            // count the first byte of each UTF-8 sequence
+            if b & 0xC0 != 0x80 {
+                counts.chars += 1;
+            }
+            if b == b'\n' {
+                counts.lines += 1;
+            }
+            if b.is_ascii_whitespace() {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                counts.words += 1;
+            }
+        }
+    }
+}
+
+/// `wc [-lwmc] [file...]`
+pub fn wc(args: &[String], io: &mut Streams) -> i32 {
+    let Some(opts) = parse_or_usage("wc", args, "lwmc", "", io) else {
+        return 2;
+    };
+    let any = ['l', 'w', 'm', 'c'].iter().any(|&f| opts.has(f));
+    let show = |f: char| if any { opts.has(f) } else { f != 'm' };
+    let mut operands = opts.operands.clone();
+    if operands.is_empty() {
+        operands.push("-".to_string());
+    }
+
+    let mut status = 0;
+    let mut results: Vec<(Counts, &str)> = Vec::new();
+    for op in &operands {
+        match open_input(op, io.cwd, &mut *io.stdin).and_then(|mut input| count_stream(&mut input)) {
+            Ok(counts) => results.push((counts, op)),
+            Err(e) => {
+                writeln!(io.stderr, "wc: {}: {}", op, error_text(&e)).ok();
+                status = 1;
+            }
+        }
+    }
+    let mut total = Counts::default();
+    for (c, _) in &results {
+        total.lines += c.lines;
+        total.words += c.words;
+        total.chars += c.chars;
+        total.bytes += c.bytes;
+    }
+    if operands.len() > 1 {
+        results.push((total, "total"));
+    }
+
+    // one column for one input needs no padding; stdin gets the usual 7
+    let columns = ['l', 'w', 'm', 'c'].iter().filter(|&&f| show(f)).count();
+    let width = if columns == 1 && results.len() == 1 {
+        0
+    } else if operands.iter().any(|op| op == "-") {
+        7
+    } else {
+        let max = total.lines.max(total.words).max(total.chars).max(total.bytes);
+        max.to_string().len()
+    };
+    for (c, name) in results {
+        let mut fields = Vec::new();
+        for (flag, value) in [('l', c.lines), ('w', c.words), ('m', c.chars), ('c', c.bytes)] {
+            if show(flag) {
+                fields.push(format!("{:>width$}", value));
+            }
+        }
+        let mut row = fields.join(" ");
+        if name != "-" {
+            row.push(' ');
+            row.push_str(name);
+        }
+        writeln!(io.stdout, "{}", row).ok();
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coreutils::tests::{run_in, scratch_dir};
+
+    const LINES: &str = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+
+    fn stdout(argv: &str, stdin: &str) -> String {
+        run_in(Path::new("."), argv, stdin).stdout
+    }
+
+    #[test]
+    fn head_takes_lines_or_bytes() {
+        assert_eq!(stdout("head", LINES), "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+        assert_eq!(stdout("head -n 2", LINES), "1\n2\n");
+        assert_eq!(stdout("head -n2", LINES), "1\n2\n");
+        assert_eq!(stdout("head -3", LINES), "1\n2\n3\n");
+        assert_eq!(stdout("head -c 3", LINES), "1\n2");
+        assert_eq!(stdout("head -n 5", "a\nb"), "a\nb");
+        let out = run_in(Path::new("."), "head -n x", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "head: invalid number of lines: 'x'\n"));
+    }
+
+    #[test]
+    fn tail_takes_lines_or_bytes_from_either_end() {
+        assert_eq!(stdout("tail", LINES), "3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n");
+        assert_eq!(stdout("tail -n 2", LINES), "11\n12\n");
+        assert_eq!(stdout("tail -2", "a\nb\nc"), "b\nc");
+        assert_eq!(stdout("tail -n +11", LINES), "11\n12\n");
+        assert_eq!(stdout("tail -n 0", LINES), "");
+        assert_eq!(stdout("tail -c 3", LINES), "12\n");
+        assert_eq!(stdout("tail -c +19", LINES), "10\n11\n12\n");
+        assert_eq!(stdout("tail -n 5", "a\nb\n"), "a\nb\n");
+    }
+
+    #[test]
+    fn head_and_tail_headers() {
+        let dir = scratch_dir("head_tail");
+        fs::write(dir.join("x"), "x1\nx2\n").unwrap();
+        fs::write(dir.join("y"), "y1\ny2\n").unwrap();
+        assert_eq!(run_in(&dir, "head -n 1 x y", "").stdout, "==> x <==\nx1\n\n==> y <==\ny1\n");
+        assert_eq!(run_in(&dir, "tail -qn 1 x y", "").stdout, "x2\ny2\n");
+        assert_eq!(run_in(&dir, "head -vn 1 -", "in\n").stdout, "==> (standard input) <==\nin\n");
+        let out = run_in(&dir, "head nope x", "");
+        assert_eq!(out.status, 1);
+        assert_eq!(out.stderr, "head: cannot open 'nope' for reading: No such file or directory\n");
+        assert_eq!(out.stdout, "==> x <==\nx1\nx2\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn grep_selects_lines() {
+        let text = "apple pie\nBanana\ncherry\napplesauce\n";
+        assert_eq!(stdout("grep apple", text), "apple pie\napplesauce\n");
+        assert_eq!(stdout("grep -i banana", text), "Banana\n");
+        assert_eq!(stdout("grep -v apple", text), "Banana\ncherry\n");
+        assert_eq!(stdout("grep -n apple", text), "1:apple pie\n4:applesauce\n");
+        assert_eq!(stdout("grep -c apple", text), "2\n");
+        assert_eq!(stdout("grep -w apple", text), "apple pie\n");
+        assert_eq!(stdout("grep -x cherry", text), "cherry\n");
+        assert_eq!(stdout("grep -o p*le", text), "pple\npple\n");
+        assert_eq!(stdout("grep -e cherry -e Ban", text), "Banana\ncherry\n");
+        assert_eq!(stdout("grep -H cherry", text), "(standard input):cherry\n");
+    }
+
+    #[test]
+    fn grep_exit_statuses() {
+        assert_eq!(run_in(Path::new("."), "grep a", "a\n").status, 0);
+        assert_eq!(run_in(Path::new("."), "grep z", "a\n").status, 1);
+        let out = run_in(Path::new("."), "grep -q a", "a\n");
+        assert_eq!((out.status, out.stdout.as_str()), (0, ""));
+        let out = run_in(Path::new("."), "grep a nope", "");
+        assert_eq!((out.status, out.stderr.as_str()), (2, "grep: nope: No such file or directory\n"));
+        assert_eq!(run_in(Path::new("."), "grep -s a nope", "").stderr, "");
+        assert_eq!(run_in(Path::new("."), "grep", "").status, 2);
+    }
+
+    #[test]
+    fn grep_regex_dialects() {
+        // basic: bare ( | + ? are literals, the escaped forms operators
+        assert_eq!(stdout("grep a+b", "aab\na+b\n"), "a+b\n");
+        assert_eq!(stdout(r"grep a\+b", "aab\na+b\n"), "aab\n");
+        assert_eq!(stdout(r"grep x\|y", "x\nz\ny\n"), "x\ny\n");
+        assert_eq!(stdout(r"grep \<the\>", "the end\nother\n"), "the end\n");
+        assert_eq!(stdout("grep -E a+b", "aab\na+b\n"), "aab\n");
+        assert_eq!(stdout("grep -E (x|y)z", "xz\nyz\n(x|y)z\n"), "xz\nyz\n");
+        assert_eq!(stdout("grep -F a.c", "abc\na.c\n"), "a.c\n");
+        assert_eq!(stdout("grep *a", "*a\nb\n"), "*a\n");
+        assert_eq!(stdout("grep [[:digit:]]", "a\n1\n"), "1\n");
+        assert_eq!(to_regex_syntax(r"[\]x]", true), r"[\\]x]");
+    }
+
+    #[test]
+    fn grep_files_and_recursion() {
+        let dir = scratch_dir("grep");
+        fs::create_dir(dir.join("d")).unwrap();
+        fs::write(dir.join("d/one"), "needle\nhay\n").unwrap();
+        fs::write(dir.join("d/two"), "hay\n").unwrap();
+        assert_eq!(run_in(&dir, "grep -r needle", "").stdout, "./d/one:needle\n");
+        assert_eq!(run_in(&dir, "grep -rn needle d", "").stdout, "d/one:1:needle\n");
+        assert_eq!(run_in(&dir, "grep -l hay d/one d/two", "").stdout, "d/one\nd/two\n");
+        assert_eq!(run_in(&dir, "grep -L needle d/one d/two", "").stdout, "d/two\n");
+        assert_eq!(run_in(&dir, "grep -c hay d/one d/two", "").stdout, "d/one:1\nd/two:1\n");
+        assert_eq!(run_in(&dir, "grep -h hay d/one d/two", "").stdout, "hay\nhay\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn wc_counts_and_pads() {
+        let text = "one two\nthree\n";
+        assert_eq!(stdout("wc", text), "      2       3      14\n");
+        assert_eq!(stdout("wc -l", text), "2\n");
+        assert_eq!(stdout("wc -lw", text), "      2       3\n");
+        assert_eq!(stdout("wc -m", "h\u{e9}\n"), "3\n");
+        assert_eq!(stdout("wc -c", "h\u{e9}\n"), "4\n");
+
+        let dir = scratch_dir("wc");
+        fs::write(dir.join("x"), text).unwrap();
+        fs::write(dir.join("y"), "a\n").unwrap();
+        assert_eq!(run_in(&dir, "wc x", "").stdout, " 2  3 14 x\n");
+        // as wide as the largest total, bytes included
+        assert_eq!(run_in(&dir, "wc -l x y", "").stdout, " 2 x\n 1 y\n 3 total\n");
+        let out = run_in(&dir, "wc nope", "");
+        assert_eq!((out.status, out.stderr.as_str()), (1, "wc: nope: No such file or directory\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
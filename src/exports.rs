@@ -1,3 +1,7 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
 use std::sync::Mutex;
 
 use lazy_static::lazy_static;
@@ -15,4 +19,29 @@ pub struct CommandSpec {
     pub redirect_out_append: Option<String>,
     pub redirect_err: Option<String>,
     pub redirect_err_append: Option<String>,
+    /// Directory to run in, and that relative redirections are opened
+    /// in; the process's own if `None`.
+    pub cwd: Option<PathBuf>,
+    /// The command's whole environment; the process's own if `None`.
+    pub env: Option<BTreeMap<OsString, OsString>>,
+}
+
+impl CommandSpec {
+    /// Where the redirection target `path` refers to.
+    pub fn path(&self, path: &str) -> PathBuf {
+        match &self.cwd {
+            Some(cwd) => cwd.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Gives `cmd` this command's directory and environment.
+    pub fn configure(&self, cmd: &mut Command) {
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        if let Some(env) = &self.env {
+            cmd.env_clear().envs(env);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,181 @@
+// src/logging.rs
+//! Diagnostics for every binary, through the `log` macros (`error!`,
+//! `warn!`, `info!`, `debug!`, `trace!`). Lines are tagged with the program,
+//! as in `(shell) Connected to ...`, and go to stderr or to a file.
+//!
+//! Binaries set it up through `cli::Matches::init_logging`: the level is
+//! `info` unless `--log-level`/`LOG_LEVEL` says otherwise, and each `-v`
+//! raises it a step and each `-q` lowers it.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::common::utc_timestamp;
+
+/// `off`, `error`, `warn`, `info`, `debug` or `trace`.
+pub const LOG_LEVEL_ENV_VAR: &str = "LOG_LEVEL";
+/// File to append log lines to instead of stderr.
+pub const LOG_FILE_ENV_VAR: &str = "LOG_FILE";
+/// `1`/`true`/`yes` to start lines with the time, `0`/`false`/`no` not
+/// to; by default only lines written to a file carry it.
+pub const LOG_TIMESTAMPS_ENV_VAR: &str = "LOG_TIMESTAMPS";
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// How and where to log.
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    pub level: LevelFilter,
+    /// Append to this file instead of writing to stderr.
+    pub file: Option<PathBuf>,
+    /// Start lines with the time; `None` for only when logging to a file.
+    pub timestamps: Option<bool>,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions { level: LevelFilter::Info, file: None, timestamps: None }
+    }
+}
+
+impl LogOptions {
+    /// Raises the level by `steps` (lowers it when negative), staying
+    /// between `off` and `trace`.
+    pub fn adjust(&mut self, steps: i32) {
+        let index = LEVELS.iter().position(|&l| l == self.level).unwrap_or(3) as i32;
+        self.level = LEVELS[(index + steps).clamp(0, LEVELS.len() as i32 - 1) as usize];
+    }
+}
+
+/// A level name as used in `LOG_LEVEL`, in any case.
+pub fn parse_level(name: &str) -> Option<LevelFilter> {
+    name.trim().parse().ok()
+}
+
+struct Logger {
+    tag: String,
+    timestamps: bool,
+    /// `None` for stderr.
+    file: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = String::new();
+        if self.timestamps {
+            line.push_str(&utc_timestamp());
+            line.push(' ');
+        }
+        line.push_str(&self.tag);
+        line.push(' ');
+        match record.level() {
+            Level::Error => line.push_str("error: "),
+            Level::Warn => line.push_str("warning: "),
+            Level::Info => {}
+            Level::Debug => line.push_str("debug: "),
+            Level::Trace => line.push_str("trace: "),
+        }
+        line.push_str(&record.args().to_string());
+        line.push('\n');
+        // Logging must never take the program down with it
+        match &self.file {
+            Some(file) => {
+                let _ = file.lock().unwrap().write_all(line.as_bytes());
+            }
+            None => {
+                let _ = io::stderr().lock().write_all(line.as_bytes());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Installs the logger, tagging lines with `tag` (such as `(shell)`).
+/// Only the first call in a process has an effect.
+pub fn init(tag: &str, options: &LogOptions) -> io::Result<()> {
+    let file = options.file.as_deref().map(open_log_file).transpose()?;
+    let logger = Logger {
+        tag: tag.to_string(),
+        timestamps: options.timestamps.unwrap_or(file.is_some()),
+        file: file.map(Mutex::new),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(options.level);
+    }
+    Ok(())
+}
+
+fn open_log_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+
+    fn adjusted(level: LevelFilter, steps: i32) -> LevelFilter {
+        let mut options = LogOptions { level, ..LogOptions::default() };
+        options.adjust(steps);
+        options.level
+    }
+
+    #[test]
+    fn level_names_in_any_case() {
+        assert_eq!(parse_level("debug"), Some(LevelFilter::Debug));
+        assert_eq!(parse_level(" WARN "), Some(LevelFilter::Warn));
+        assert_eq!(parse_level("Off"), Some(LevelFilter::Off));
+        assert_eq!(parse_level("trace"), Some(LevelFilter::Trace));
+        assert_eq!(parse_level("verbose"), None);
+        assert_eq!(parse_level(""), None);
+    }
+
+    #[test]
+    fn adjusting_stays_between_off_and_trace() {
+        assert_eq!(adjusted(LevelFilter::Info, 1), LevelFilter::Debug);
+        assert_eq!(adjusted(LevelFilter::Info, -2), LevelFilter::Error);
+        assert_eq!(adjusted(LevelFilter::Info, 10), LevelFilter::Trace);
+        assert_eq!(adjusted(LevelFilter::Info, -10), LevelFilter::Off);
+        assert_eq!(adjusted(LevelFilter::Trace, 1), LevelFilter::Trace);
+        assert_eq!(adjusted(LevelFilter::Off, -1), LevelFilter::Off);
+        assert_eq!(adjusted(LevelFilter::Off, 1), LevelFilter::Error);
+    }
+
+    #[test]
+    fn verbose_and_quiet_flags_offset_each_other() {
+        let cli = Cli::new("prog", "test");
+        for (args, level) in [
+            (&["-vvq"][..], LevelFilter::Debug),
+            (&["-v", "-q"], LevelFilter::Info),
+            (&["-qq", "--verbose"], LevelFilter::Warn),
+            (&["-qqqqqq", "-v"], LevelFilter::Off),
+        ] {
+            let matches = cli.parse(args.iter().map(|a| a.to_string())).unwrap();
+            let steps = matches.count("verbose") as i32 - matches.count("quiet") as i32;
+            assert_eq!(adjusted(LevelFilter::Info, steps), level, "{:?}", args);
+        }
+    }
+}
@@ -1,5 +1,8 @@
+pub mod script;
+pub mod session;
+
 #[cfg(unix)]
 pub mod unix_pty;
 
 #[cfg(windows)]
-pub mod windows_pty;
\ No newline at end of file
+pub mod windows_pty;
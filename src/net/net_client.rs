@@ -1,21 +1,41 @@
-use std::env;
 use std::fs;
-use std::io::{self, BufReader, Read, Write};
-use std::net::TcpStream;
+use std::io::{self, BufReader, Write};
 use std::path::Path;
+use std::time::Duration;
+
+use log::{error, info};
 
 use net_utils::user_shell;
 use net_utils::common;
-
-fn connect_and_run(address: &str) -> io::Result<()> {
-    let mut stream = TcpStream::connect(address)?;
-    println!("Connected to {}", address);
+use net_utils::cli::{Cli, Opt, PRECOMPILED_ADDRESS};
+use net_utils::addr;
+use net_utils::reconnect::CONNECT_TIMEOUT_ENV_VAR;
+use net_utils::recording::Recorder;
+
+fn connect_and_run(address: &str, timeout: Duration) -> io::Result<()> {
+    let mut stream = addr::connect(address, timeout)?;
+    info!("Connected to {}", address);
+
+    // Recorded when RECORD_DIR is set; output is what this client prints
+    let recorder = Recorder::from_env("net_client", address, 80, 24);
+    let record_output = |text: &str| {
+        if let Some(recorder) = &recorder {
+            recorder.output(text.as_bytes());
+        }
+    };
 
     let mut buf_reader = BufReader::new(stream.try_clone()?);
-    common::print_banner(&mut buf_reader)?; 
+    let banner = common::print_banner(&mut buf_reader)?;
+    record_output(&banner);
     user_shell::setup_signal_handler(&stream)?;
 
     common::command_loop(|trimmed_command_line| {
+        if let Some(recorder) = &recorder {
+            let typed = format!("{}\n", trimmed_command_line);
+            recorder.input(typed.as_bytes());
+            recorder.output(format!("$ {}", typed).as_bytes());
+        }
+
         if let Some((command, _redir_op, filename)) = user_shell::parse_redirect(trimmed_command_line) {
             stream.write_all(command.as_bytes())?;
             stream.write_all(b"\n")?;
@@ -33,6 +53,7 @@ fn connect_and_run(address: &str) -> io::Result<()> {
                 file.write_all(output.as_bytes())?;
             }
             println!("Output written to {}", filename);
+            record_output(&format!("Output written to {}\n", filename));
         } 
         else if let Some(exec_line) = trimmed_command_line.strip_prefix("exec ") {
             let mut parts = exec_line.split_whitespace();
@@ -65,6 +86,7 @@ fn connect_and_run(address: &str) -> io::Result<()> {
 
                 let output = user_shell::capture_until_prompt(&mut stream)?;
                 print!("{}", output);
+                record_output(&output);
             } else {
                 println!("Usage: exec <local_binary_path> [args...]");
             }
@@ -76,19 +98,29 @@ fn connect_and_run(address: &str) -> io::Result<()> {
 
             let output = user_shell::capture_until_prompt(&mut stream)?;
             print!("{}", output);
+            record_output(&output);
         }
 
         io::stdout().flush()?;
         Ok(())
     })?;
 
-    println!("Disconnecting...");
+    info!("Disconnecting...");
     Ok(())
 }
 
 fn main() {
-    let address = env::var("CONNECT_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
-    if let Err(e) = connect_and_run(&address) {
-        eprintln!("Connection error: {}", e);
+    let matches = Cli::new("net_client", "runs commands on a net_shell listening for connections")
+        .opts(&[
+            Opt::value("address", "ADDR", "net_shell to connect to").short('a').env("CONNECT_ADDRESS").default(PRECOMPILED_ADDRESS),
+            Opt::value("port", "PORT", "Connect to this port").short('p'),
+            Opt::value("connect-timeout", "MS", "Limit on connecting").env(CONNECT_TIMEOUT_ENV_VAR).default("10000"),
+        ])
+        .parse_env_or_exit();
+    matches.init_logging("(client)");
+    let address = matches.address().unwrap_or_default();
+    let timeout = Duration::from_millis(matches.get("connect-timeout").filter(|&ms| ms > 0).unwrap_or(10_000));
+    if let Err(e) = connect_and_run(&address, timeout) {
+        error!("Connection error: {}", e);
     }
 }
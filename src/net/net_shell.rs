@@ -1,21 +1,36 @@
 //! A cross-platform shell that can parse simple pipelines *or*
 //! launch an interactive PTY for commands like `sudo`, `vim`, etc.,
-//! and reconnect if the TCP connection breaks.
+//! and reconnect if the TCP connection breaks. With `--mode bind` it
+//! listens for operators instead of connecting out.
 
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::io::{self, BufRead, BufReader, Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use signal_hook::iterator::Signals;
 use signal_hook::consts::signal::{SIGINT, SIGTSTP};
 
-use glob::glob;
+use glob::{glob_with, MatchOptions};
+use log::{debug, error, info, warn};
 
+#[cfg(feature = "coreutils")]
+use net_utils::coreutils::{self, Streams};
+use net_utils::addr;
+use net_utils::audit::{self, AuditRecord};
+use net_utils::common::{find_in, is_executable};
+use net_utils::cli::{Cli, Mode, Opt, MODE_OPT};
 use net_utils::exports::CommandSpec;
+use net_utils::net::script::{self, Connector, Node, ParseError};
+use net_utils::net::session::{Session, SessionManager};
+use net_utils::reconnect::{ReconnectPolicy, RECONNECT_OPTS};
 #[cfg(unix)]
 use net_utils::net::unix_pty;
 #[cfg(windows)]
@@ -23,40 +38,394 @@ use net_utils::net::win_pty;
 
 #[cfg(windows)]
 use crate::win_pty_big;
-static INTERACTIVE_CMDS: &[&str] = &["vim", "nano", "less", "more", "sudo", "vi"];
+/// Commands that get a PTY by default; each session keeps its own copy
+/// (`ptycmds`).
+static INTERACTIVE_CMDS: &[&str] = &[
+    "vim", "nano", "less", "more", "sudo", "vi", "su", "top", "htop", "ssh", "man", "watch",
+    "python", "python3", "irb", "node", "mysql", "psql", "ftp", "telnet", "bash", "sh", "zsh",
+];
+
+/// Guards against runaway recursion in shell functions.
+const MAX_FUNCTION_DEPTH: usize = 100;
+
+/// Upper bound on the words a single `{a..b}` range may produce.
+const MAX_BRACE_ITEMS: u64 = 100_000;
+
+/// Upper bound on the words one line may expand to, so brace expansions
+/// like `{1..100000}{1..100000}` fail instead of exhausting memory.
+const MAX_LINE_WORDS: usize = 200_000;
+/// The error for a line past `MAX_LINE_WORDS`; it fails the command with
+/// status 1 rather than being a parse error.
+const TOO_MANY_WORDS: &str = "brace expansion: too many words";
+
+/// Largest script `SCRIPT_UPLOAD` accepts; bigger ones are read and
+/// dropped so the connection stays in step.
+const MAX_SCRIPT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Startup file run at the start of every session, if present. The path
+/// in `NET_SHELL_RC` wins over the file embedded at build time (see
+/// `CARGO_PKG_METADATA_PRECOMPILED_RCFILE` in build.rs).
+const RC_ENV_VAR: &str = "NET_SHELL_RC";
+static EMBEDDED_RC: &str = include_str!(concat!(env!("OUT_DIR"), "/net_shell_rc.sh"));
+
+/// Output kept for a detached session, in bytes, unless
+/// `NET_SHELL_REPLAY_BYTES` says otherwise.
+const REPLAY_ENV_VAR: &str = "NET_SHELL_REPLAY_BYTES";
+const DEFAULT_REPLAY_BYTES: usize = 256 * 1024;
+
+/// Sessions run at once, unless `NET_SHELL_MAX_SESSIONS` says otherwise.
+const MAX_SESSIONS_ENV_VAR: &str = "NET_SHELL_MAX_SESSIONS";
+const DEFAULT_MAX_SESSIONS: usize = 8;
+
+/// Seconds a session may stay detached before it is ended, unless
+/// `NET_SHELL_DETACHED_SECS` says otherwise (0 for no limit).
+const DETACHED_ENV_VAR: &str = "NET_SHELL_DETACHED_SECS";
+const DEFAULT_DETACHED_SECS: u64 = 60 * 60;
+
+/// Words `type` reports as shell keywords.
+const KEYWORDS: &[&str] = &[
+    "if", "then", "elif", "else", "fi", "while", "until", "do", "done", "for", "in", "case", "esac",
+    "{", "}",
+];
+
+/// Built-ins that work on the shell's own state, run by `run_simple`.
+const SPECIAL_BUILTINS: &[&str] = &[
+    "exit", "break", "continue", "return", "shift", "source", ".", "alias", "unalias", "run", "hash",
+    "type", "command", "pty", "nopty", "ptycmds", "detach",
+];
+
+/// Interpreter state for one connection: aliases, defined functions,
+/// positional arguments of the current function call, the last exit
+/// status and the `set -e` / `set -x` / `set -o pipefail` options.
+struct Shell {
+    aliases: BTreeMap<String, String>,
+    functions: HashMap<String, Arc<Vec<Node>>>,
+    /// Commands already found on PATH, with their use counts (`hash`).
+    hashed: BTreeMap<String, (PathBuf, u32)>,
+    /// PATH the table was filled from; a new PATH empties it.
+    hashed_path: Option<OsString>,
+    positional: Vec<String>,
+    last_status: i32,
+    depth: usize,
+    /// Status of each stage of the last pipeline, for `$PIPESTATUS`.
+    pipestatus: Vec<i32>,
+    /// Set by `set_pipeline_status`; other commands get a one-entry list.
+    pipestatus_set: bool,
+    errexit: bool,
+    xtrace: bool,
+    pipefail: bool,
+    stderr_mode: StderrMode,
+    /// Commands run in a PTY, matched by basename.
+    pty_commands: BTreeSet<String>,
+    /// `set -o ptyauto`: every foreground command gets a PTY when the
+    /// client has advertised a terminal.
+    pty_auto: bool,
+    /// The client's terminal, from a `TERMINAL` line.
+    terminal: Option<Terminal>,
+    /// Non-zero while running an `if`/`while`/`until` condition,
+    /// where a failing command must not trigger `errexit`.
+    in_condition: usize,
+    /// The detachable session this shell runs in (`detach`).
+    session: Option<Arc<Session>>,
+    /// Working directory and environment of this shell. Sessions share
+    /// one process, so these live here and are handed to every command;
+    /// the process's own are never changed.
+    cwd: PathBuf,
+    vars: BTreeMap<OsString, OsString>,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Shell {
+            aliases: BTreeMap::new(),
+            functions: HashMap::new(),
+            hashed: BTreeMap::new(),
+            hashed_path: None,
+            positional: Vec::new(),
+            last_status: 0,
+            depth: 0,
+            pipestatus: vec![0],
+            pipestatus_set: false,
+            errexit: false,
+            xtrace: false,
+            pipefail: false,
+            stderr_mode: StderrMode::Plain,
+            pty_commands: INTERACTIVE_CMDS.iter().map(|c| c.to_string()).collect(),
+            pty_auto: false,
+            terminal: None,
+            in_condition: 0,
+            session: None,
+            cwd: env::current_dir().unwrap_or_else(|_| PathBuf::from("/")),
+            vars: env::vars_os().collect(),
+        }
+    }
+
+    /// Value of the environment variable `name`, if set and valid UTF-8.
+    fn var(&self, name: &str) -> Option<String> {
+        self.vars.get(OsStr::new(name)).and_then(|v| v.to_str()).map(str::to_string)
+    }
+
+    fn set_var(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.into(), value.into());
+    }
+
+    /// Where `path` refers to: relative to the shell's cwd unless absolute.
+    fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.cwd.join(path)
+    }
+
+
+    /// Records a finished pipeline: `$PIPESTATUS` gets every stage and
+    /// `$?` the last one, or with `pipefail` the rightmost failure.
+    fn set_pipeline_status(&mut self, statuses: &[StageStatus]) {
+        self.pipestatus = statuses.iter().map(|s| s.code()).collect();
+        self.pipestatus_set = true;
+        let last = self.pipestatus.last().copied().unwrap_or(0);
+        self.last_status = if self.pipefail {
+            self.pipestatus.iter().rev().find(|&&c| c != 0).copied().unwrap_or(0)
+        } else {
+            last
+        };
+    }
+
+    /// Finds the program to run for `cmd`, remembering PATH lookups in
+    /// the hash table. A name with a path separator must be executable.
+    fn resolve(&mut self, cmd: &str) -> io::Result<PathBuf> {
+        if cmd.contains('/') || cmd.contains('\\') {
+            // on Windows this may add a PATHEXT extension
+            if let Some(path) = self.find_in_path(cmd).pop() {
+                return Ok(path);
+            }
+            let path = self.path(cmd);
+            return if path.is_dir() {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "Is a directory"))
+            } else if path.exists() {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied"))
+            } else {
+                Err(io::Error::new(io::ErrorKind::NotFound, "No such file"))
+            };
+        }
+
+        self.check_hashed_path();
+        if let Some((path, hits)) = self.hashed.get_mut(cmd) {
+            if is_executable(path) {
+                *hits += 1;
+                return Ok(path.clone());
+            }
+            self.hashed.remove(cmd);
+        }
+        match self.find_in_path(cmd).pop() {
+            Some(path) => {
+                self.hashed.insert(cmd.to_string(), (path.clone(), 1));
+                Ok(path)
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found in PATH", cmd))),
+        }
+    }
+
+    /// The first executable called `cmd` on the shell's PATH.
+    fn find_in_path(&self, cmd: &str) -> Vec<PathBuf> {
+        find_in(cmd, self.vars.get(OsStr::new("PATH")).map(OsString::as_os_str), &self.cwd, false)
+    }
+
+    /// Empties the hash table when PATH has changed since it was filled.
+    fn check_hashed_path(&mut self) {
+        let path_var = self.vars.get(OsStr::new("PATH")).cloned();
+        if path_var != self.hashed_path {
+            self.hashed.clear();
+            self.hashed_path = path_var;
+        }
+    }
+}
+
+/// The operator this process serves, in bind mode on unix, where each
+/// connection has a process of its own and no detachable session.
+static CONNECTION_PEER: OnceLock<String> = OnceLock::new();
+
+/// Address of the client a shell serves, for the audit log.
+fn client_peer(session: Option<&Session>) -> Option<String> {
+    session.and_then(|s| s.peer()).or_else(|| CONNECTION_PEER.get().cloned())
+}
+
+/// How stderr of commands is shown to the client: as is, in red, or as
+/// `[stderr] `-tagged lines (`set -o stderrcolor` / `set -o stderrtag`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StderrMode {
+    Plain,
+    Color,
+    Tagged,
+}
+
+/// Size of the terminal a client advertised with
+/// `TERMINAL <term> [<rows> <cols>]`.
+#[derive(Debug, Clone)]
+struct Terminal {
+    rows: u16,
+    cols: u16,
+}
+
+/// How control leaves a command: normally, or via `break`/`continue`
+/// (with their loop count), `return`, `exit`, or a failure under `set -e`.
+enum Flow {
+    Normal,
+    Break(usize),
+    Continue(usize),
+    Return,
+    Exit,
+    Fail,
+}
 
 fn main() -> io::Result<()> {
-    let address = env::var("LISTENER_ADDRESS")
-        .unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let matches = Cli::new("net_shell", "shell that connects out to a net_listener, or listens for operators")
+        .opts(&[
+            MODE_OPT,
+            Opt::value(
+                "address",
+                "ADDR",
+                concat!(
+                    "Listener to connect to, with comma-separated fallbacks (default ",
+                    env!("CARGO_PKG_METADATA_PRECOMPILED_ADDRESS"),
+                    "), or address to listen on (default 0.0.0.0:8080)"
+                ),
+            )
+            .short('a')
+            .env("LISTENER_ADDRESS"),
+            Opt::value("port", "PORT", "Port to connect to or listen on").short('p'),
+        ])
+        .opts(RECONNECT_OPTS)
+        .parse_env_or_exit();
+    matches.init_logging("(shell)");
 
-    loop {
-        eprintln!("(shell) Attempting to connect to {}", address);
-        match TcpStream::connect(&address) {
-            Ok(stream) => {
-                eprintln!("(shell) Connected to {}", address);
+    if matches.get::<Mode>("mode") == Some(Mode::Bind) {
+        return listen(&matches.address_or(Mode::Bind.default_address()));
+    }
 
-                // Attempt to install signal handler (non-fatal if it fails)
-                if let Err(e) = setup_signal_handler(&stream) {
-                    eprintln!("(shell) WARNING: Could not set up signal handler: {}", e);
-                }
+    let policy = ReconnectPolicy::from_matches(&matches);
+    let sessions = session_manager();
+    policy.run(|stream| {
+        // Attempt to install signal handler (non-fatal if it fails)
+        if let Err(e) = setup_signal_handler(&stream) {
+            warn!("Could not set up signal handler: {}", e);
+        }
 
-                // Start our main interactive loop
-                if let Err(err) = shell_loop(stream) {
-                    eprintln!("(shell) Error in session: {}", err);
-                }
-                eprintln!("(shell) Connection ended. Will reconnect...");
+        // Attach to a detached session or start our main interactive loop
+        sessions.serve(stream, |stream, session| {
+            if let Err(err) = run_session(stream, Some(session)) {
+                error!("Error in session: {}", err);
             }
+        })
+    })
+}
+
+/// Detachable sessions, limited as `NET_SHELL_REPLAY_BYTES`,
+/// `NET_SHELL_MAX_SESSIONS` and `NET_SHELL_DETACHED_SECS` say.
+fn session_manager() -> Arc<SessionManager> {
+    fn setting<T: std::str::FromStr>(var: &str, default: T) -> T {
+        env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+    let detached_secs = setting(DETACHED_ENV_VAR, DEFAULT_DETACHED_SECS);
+    SessionManager::new(
+        setting(REPLAY_ENV_VAR, DEFAULT_REPLAY_BYTES),
+        setting(MAX_SESSIONS_ENV_VAR, DEFAULT_MAX_SESSIONS).max(1),
+        (detached_secs > 0).then(|| Duration::from_secs(detached_secs)),
+    )
+}
+
+/// Bind mode: serves the shell to each operator connecting to `address`.
+/// Every connection runs in a process of its own, forked from this one,
+/// so working directories and environments cannot leak between them.
+/// There is no `detach` here, as no other connection could reattach.
+#[cfg(unix)]
+fn listen(address: &str) -> io::Result<()> {
+    use nix::sys::signal::{signal, SigHandler, Signal};
+    use nix::unistd::{fork, ForkResult};
+
+    let listener = addr::bind(address)?;
+    info!("Listening on {}", address);
+    // Let the kernel reap finished connections
+    unsafe { signal(Signal::SIGCHLD, SigHandler::SigIgn) }.map_err(io::Error::from)?;
+
+    loop {
+        let (stream, remote) = match addr::accept(&listener) {
+            Ok(conn) => conn,
             Err(e) => {
-                eprintln!("(shell) Connection error: {}. Retrying in 1s...", e);
-                thread::sleep(Duration::from_secs(1));
+                warn!("Accept failed: {}", e);
+                continue;
+            }
+        };
+        info!("Connection from {}", remote);
+
+        // Safe: nothing but this thread runs in the listening process
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                drop(listener);
+                // Commands are waited for, so they must not be reaped for us
+                let _ = unsafe { signal(Signal::SIGCHLD, SigHandler::SigDfl) };
+                let _ = CONNECTION_PEER.set(remote.to_string());
+                serve_connection(stream, remote, None);
+                std::process::exit(0);
             }
+            Ok(ForkResult::Parent { child }) => debug!("{}: served by process {}", remote, child),
+            Err(e) => error!("{}: could not start a process for it: {}", remote, e),
         }
     }
 }
 
+/// Bind mode without fork: each connection is served on its own thread,
+/// in a session of its own, the way reconnects are in connect mode.
+#[cfg(not(unix))]
+fn listen(address: &str) -> io::Result<()> {
+    let listener = addr::bind(address)?;
+    info!("Listening on {}", address);
+    let sessions = session_manager();
+
+    loop {
+        let (stream, remote) = match addr::accept(&listener) {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Accept failed: {}", e);
+                continue;
+            }
+        };
+        info!("Connection from {}", remote);
+        let sessions = Arc::clone(&sessions);
+        thread::spawn(move || serve_connection(stream, remote, Some(sessions)));
+    }
+}
+
+/// Serves one operator connection in bind mode, through `sessions` when
+/// there are any, otherwise as a session of its own on this thread.
+fn serve_connection(stream: TcpStream, remote: SocketAddr, sessions: Option<Arc<SessionManager>>) {
+    if let Err(e) = setup_signal_handler(&stream) {
+        warn!("Could not set up signal handler: {}", e);
+    }
+    let result = match sessions {
+        Some(sessions) => sessions.serve(stream, |stream, session| {
+            if let Err(err) = run_session(stream, Some(session)) {
+                error!("Error in session: {}", err);
+            }
+        }),
+        None => {
+            let started = Instant::now();
+            audit::record(AuditRecord { peer: Some(remote.to_string()), ..AuditRecord::new("net_shell", "session_start") });
+            let result = run_session(stream, None);
+            audit::record(AuditRecord {
+                peer: Some(remote.to_string()),
+                duration: Some(started.elapsed()),
+                ..AuditRecord::new("net_shell", "session_end")
+            });
+            result
+        }
+    };
+    match result {
+        Ok(()) => info!("{}: Connection closed", remote),
+        Err(err) => error!("{}: Error in session: {}", remote, err),
+    }
+}
+
 /// Forward local signals (Ctrl+C, etc.) up the chain if desired.
 fn setup_signal_handler(stream: &TcpStream) -> io::Result<()> {
-    let mut signals = Signals::new(&[SIGINT, SIGTSTP])?;
+    let mut signals = Signals::new([SIGINT, SIGTSTP])?;
     let mut stream_clone = stream.try_clone()?;
     thread::spawn(move || {
         for sig in signals.forever() {
@@ -71,88 +440,1223 @@ fn setup_signal_handler(stream: &TcpStream) -> io::Result<()> {
     Ok(())
 }
 
+/// Set for `full_pty` builds (see build.rs): every connection gets the
+/// system login shell in a PTY, the way net_mini does. `partial_pty`
+/// builds run our own shell and use a PTY only for interactive commands.
+const FULL_PTY: bool = cfg!(all(feature = "full_pty", unix));
+
+/// Runs one session in the mode picked at build time. `stream` is the
+/// session's end of its loopback pair, which outlives any connection, or
+/// the connection itself when there is no detachable session.
+fn run_session(stream: TcpStream, session: Option<Arc<Session>>) -> io::Result<()> {
+    if FULL_PTY {
+        #[cfg(unix)]
+        return login_shell_session(stream, session.as_deref());
+    }
+    shell_loop(stream, session)
+}
+
+/// Runs the user's login shell in a PTY for the whole connection. A
+/// `TERMINAL` line the client sends first sets `TERM` and the PTY size.
+#[cfg(unix)]
+fn login_shell_session(mut stream: TcpStream, session: Option<&Session>) -> io::Result<()> {
+    let mut size = Terminal { rows: 24, cols: 80 };
+    // TERM goes to the login shell only; other sessions share this process
+    let mut vars: BTreeMap<OsString, OsString> = env::vars_os().collect();
+    if let Some((term, terminal)) = read_terminal_line(&stream)? {
+        vars.insert("TERM".into(), term.into());
+        size = terminal;
+    }
+
+    let program = login_shell();
+    info!("Starting login shell {} ({}x{})", program, size.cols, size.rows);
+    let cmdspec = CommandSpec {
+        argv: vec![program, "-l".to_string()],
+        redirect_in: None,
+        redirect_out: None,
+        redirect_out_append: None,
+        redirect_err: None,
+        redirect_err_append: None,
+        cwd: None,
+        env: Some(vars),
+    };
+    let cwd = env::current_dir().ok();
+    let started = Instant::now();
+    let exit = unix_pty::run_in_pty_sized(&cmdspec, &mut stream, size.rows, size.cols)?;
+    info!("Login shell exited with: {}", exit.status);
+    audit::record(AuditRecord {
+        peer: client_peer(session),
+        session: session.map(|s| s.token().to_string()),
+        argv: vec![cmdspec.argv.clone()],
+        cwd,
+        status: Some(StageStatus::from(exit.status).code()),
+        duration: Some(started.elapsed()),
+        bytes_in: Some(exit.bytes_in),
+        bytes_out: Some(exit.bytes_out),
+        ..AuditRecord::new("net_shell", "command")
+    });
+
+    // The session is over once the shell is; this also stops the bridge
+    // thread still reading from the connection.
+    let _ = stream.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+/// `$SHELL`, else the shell in the user's passwd entry, else `/bin/sh`.
+#[cfg(unix)]
+fn login_shell() -> String {
+    env::var("SHELL")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .or_else(|| {
+            nix::unistd::User::from_uid(nix::unistd::getuid())
+                .ok()
+                .flatten()
+                .map(|user| user.shell.to_string_lossy().into_owned())
+                .filter(|s| !s.is_empty())
+        })
+        .unwrap_or_else(|| "/bin/sh".to_string())
+}
+
+/// Consumes a `TERMINAL` line if the client sends one right after
+/// connecting. Anything else is left unread for the login shell.
+#[cfg(unix)]
+fn read_terminal_line(stream: &TcpStream) -> io::Result<Option<(String, Terminal)>> {
+    let mut buf = [0u8; 256];
+    stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let peeked = match stream.peek(&mut buf) {
+        Ok(n) => n,
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => 0,
+        Err(e) => return Err(e),
+    };
+    stream.set_read_timeout(None)?;
+
+    let data = &buf[..peeked];
+    let Some(end) = data.iter().position(|&b| b == b'\n') else {
+        return Ok(None);
+    };
+    if !data.starts_with(b"TERMINAL ") {
+        return Ok(None);
+    }
+    let mut line = vec![0u8; end + 1];
+    let mut reader = stream;
+    reader.read_exact(&mut line)?;
+    let line = String::from_utf8_lossy(&line);
+    Ok(parse_terminal(line["TERMINAL ".len()..].trim_end()))
+}
+
 /// Main shell loop: read lines from TCP, parse, run commands, etc.
-fn shell_loop(mut stream: TcpStream) -> io::Result<()> {
+/// Lines are accumulated while a compound command (`if`, `while`, a
+/// function body, ...) is still open or a line ends in `\`, showing a
+/// secondary "> " prompt.
+fn shell_loop(mut stream: TcpStream, session: Option<Arc<Session>>) -> io::Result<()> {
     let mut reader = BufReader::new(stream.try_clone()?);
+    let mut shell = Shell::new();
+    shell.session = session;
+    let mut script = String::new();
 
     // Greet them
     writeln!(stream, "Welcome to the cross-platform shell!")?;
     writeln!(stream, "Type 'help' or 'exit'.")?;
     stream.flush()?;
 
+    load_rc(&mut shell, &mut stream)?;
+
     loop {
-        // Prompt
-        write!(stream, "rust-sh> ")?;
+        // Prompt, showing the last exit status when it is non-zero
+        if script.is_empty() && shell.last_status != 0 {
+            write!(stream, "rust-sh [{}]> ", shell.last_status)?;
+        } else if script.is_empty() {
+            write!(stream, "rust-sh> ")?;
+        } else {
+            write!(stream, "> ")?;
+        }
         stream.flush()?;
 
         // Read a line
         let mut line = String::new();
         let n = reader.read_line(&mut line)?;
         if n == 0 {
-            eprintln!("(shell) Remote closed connection.");
+            info!("Remote closed connection.");
             break;
         }
         let line = line.trim_end();
-        if line.is_empty() {
+        if line.is_empty() && script.is_empty() {
             continue;
         }
-        if line.eq_ignore_ascii_case("exit") {
-            writeln!(stream, "Bye!")?;
-            stream.flush()?;
-            break;
+        if script.is_empty() {
+            if let Some(spec) = line.strip_prefix("TERMINAL ") {
+                set_terminal(&mut shell, spec);
+                continue;
+            }
+            if let Some(header) = line.strip_prefix("SCRIPT_UPLOAD ") {
+                let flow = run_uploaded_script(&mut shell, header, &mut reader, &mut stream)?;
+                if let Flow::Exit = flow {
+                    writeln!(stream, "Bye!")?;
+                    stream.flush()?;
+                    break;
+                }
+                continue;
+            }
+        }
+        script.push_str(line);
+        script.push('\n');
+
+        // A trailing unescaped backslash continues the line
+        let trailing = line.chars().rev().take_while(|&c| c == '\\').count();
+        if trailing % 2 == 1 {
+            continue;
         }
 
         // Parse
-        let pipeline = match handle_line(line) {
+        let program = match script::parse(&script) {
             Ok(p) => p,
+            Err(e) if e.is_incomplete() => continue,
             Err(e) => {
                 writeln!(stream, "Parse error: {}", e)?;
+                script.clear();
                 continue;
             }
         };
+        script.clear();
 
-        // If the pipeline is just 1 command, and that command is interactive
-        // (e.g. "vim"), spawn in a PTY. Otherwise, do normal pipeline logic.
-        if pipeline.len() == 1 && is_interactive_command(&pipeline[0]) {
-            #[cfg(unix)]
-            {
-                // We'll drop into a PTY session for that command
-                let cmd = &pipeline[0];
-                unix_pty::run_in_pty(cmd, &mut stream)?;
+        let flow = exec_nodes(&mut shell, &program, &mut stream)?;
+        if let Flow::Exit = flow {
+            writeln!(stream, "Bye!")?;
+            stream.flush()?;
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// SCRIPTS: control flow and functions on top of run_pipeline
+////////////////////////////////////////////////////////////////////////////////
+
+fn exec_nodes(shell: &mut Shell, nodes: &[Node], stream: &mut TcpStream) -> io::Result<Flow> {
+    for node in nodes {
+        match exec_node(shell, node, stream)? {
+            Flow::Normal => {}
+            flow => return Ok(flow),
+        }
+    }
+    Ok(Flow::Normal)
+}
+
+fn exec_node(shell: &mut Shell, node: &Node, stream: &mut TcpStream) -> io::Result<Flow> {
+    match node {
+        Node::Simple(line) => {
+            shell.pipestatus_set = false;
+            let flow = run_simple(shell, line, stream)?;
+            if !shell.pipestatus_set {
+                shell.pipestatus = vec![shell.last_status];
             }
-            #[cfg(windows)]
-            {
-                let cmd = &pipeline[0];
-                win_pty::run_in_pty(cmd, &mut stream)?;
+            if let Flow::Normal = flow {
+                if shell.errexit && shell.last_status != 0 && shell.in_condition == 0 {
+                    return Ok(Flow::Fail);
+                }
             }
-        } else {
-            // Non-interactive pipeline
-            if let Err(e) = run_pipeline(&pipeline, &mut stream) {
-                writeln!(stream, "Error: {}", e).ok();
+            Ok(flow)
+        }
+        Node::Group(body) => exec_nodes(shell, body, stream),
+        Node::If { branches, else_body } => {
+            for (cond, body) in branches {
+                match exec_condition(shell, cond, stream)? {
+                    Flow::Normal => {}
+                    flow => return Ok(flow),
+                }
+                if shell.last_status == 0 {
+                    return exec_nodes(shell, body, stream);
+                }
+            }
+            match else_body {
+                Some(body) => exec_nodes(shell, body, stream),
+                None => {
+                    shell.last_status = 0;
+                    Ok(Flow::Normal)
+                }
+            }
+        }
+        Node::While { cond, body, until } => {
+            let mut status = 0;
+            loop {
+                match exec_condition(shell, cond, stream)? {
+                    Flow::Normal => {}
+                    flow => return Ok(flow),
+                }
+                if (shell.last_status == 0) == *until {
+                    break;
+                }
+                match exec_nodes(shell, body, stream)? {
+                    Flow::Normal => {}
+                    Flow::Break(n) if n > 1 => return Ok(Flow::Break(n - 1)),
+                    Flow::Break(_) => break,
+                    Flow::Continue(n) if n > 1 => return Ok(Flow::Continue(n - 1)),
+                    Flow::Continue(_) => {}
+                    flow => return Ok(flow),
+                }
+                status = shell.last_status;
+            }
+            shell.last_status = status;
+            Ok(Flow::Normal)
+        }
+        Node::For { var, words, body } => {
+            let items = match words {
+                Some(raw) => match shell_tokenize(raw, shell) {
+                    Ok(tokens) => tokens
+                        .into_iter()
+                        .filter_map(|t| match t {
+                            Token::Word(w) => Some(w),
+                            Token::Op(_) => None,
+                        })
+                        .collect(),
+                    Err(e) => {
+                        report_line_error(shell, &e, stream)?;
+                        return Ok(Flow::Normal);
+                    }
+                },
+                None => shell.positional.clone(),
+            };
+            shell.last_status = 0;
+            for item in items {
+                shell.set_var(var, &item);
+                match exec_nodes(shell, body, stream)? {
+                    Flow::Normal => {}
+                    Flow::Break(n) if n > 1 => return Ok(Flow::Break(n - 1)),
+                    Flow::Break(_) => break,
+                    Flow::Continue(n) if n > 1 => return Ok(Flow::Continue(n - 1)),
+                    Flow::Continue(_) => {}
+                    flow => return Ok(flow),
+                }
+            }
+            Ok(Flow::Normal)
+        }
+        Node::Case { word, arms } => {
+            let subject = expand_case_word(word, shell, false);
+            shell.last_status = 0;
+            for arm in arms {
+                let matched = arm.patterns.iter().any(|p| {
+                    glob::Pattern::new(&expand_case_word(p, shell, true))
+                        .map(|pat| pat.matches(&subject))
+                        .unwrap_or(false)
+                });
+                if matched {
+                    return exec_nodes(shell, &arm.body, stream);
+                }
+            }
+            Ok(Flow::Normal)
+        }
+        Node::Function { name, body } => {
+            shell.functions.insert(name.clone(), Arc::clone(body));
+            shell.last_status = 0;
+            Ok(Flow::Normal)
+        }
+        Node::AndOr { first, rest } => {
+            // Only the last command of the list is subject to `set -e`
+            let mut flow = exec_condition(shell, std::slice::from_ref(&**first), stream)?;
+            for (i, (connector, node)) in rest.iter().enumerate() {
+                if !matches!(flow, Flow::Normal) {
+                    break;
+                }
+                let run = match connector {
+                    Connector::And => shell.last_status == 0,
+                    Connector::Or => shell.last_status != 0,
+                };
+                if !run {
+                    continue;
+                }
+                flow = if i + 1 == rest.len() {
+                    exec_node(shell, node, stream)?
+                } else {
+                    exec_condition(shell, std::slice::from_ref(node), stream)?
+                };
+            }
+            Ok(flow)
+        }
+    }
+}
+
+/// Reports a line that could not be parsed or expanded: status 2 for a
+/// parse error, 1 for an expansion that got too big.
+fn report_line_error(shell: &mut Shell, error: &str, stream: &mut TcpStream) -> io::Result<()> {
+    if error == TOO_MANY_WORDS {
+        writeln!(stream, "{}", error)?;
+        shell.last_status = 1;
+    } else {
+        writeln!(stream, "Parse error: {}", error)?;
+        shell.last_status = 2;
+    }
+    Ok(())
+}
+
+/// Runs one simple command line: control-flow builtins, function calls,
+/// then PTY or pipeline execution like a line typed at the prompt.
+fn run_simple(shell: &mut Shell, line: &str, stream: &mut TcpStream) -> io::Result<Flow> {
+    let mut pipeline = match handle_line(line, shell) {
+        Ok(p) => p,
+        Err(e) => {
+            report_line_error(shell, &e, stream)?;
+            return Ok(Flow::Normal);
+        }
+    };
+    if pipeline.is_empty() {
+        return Ok(Flow::Normal);
+    }
+
+    // `command name args...` runs `name` without looking for a function;
+    // `pty name args...` / `nopty name args...` force or forbid a PTY
+    let mut skip_functions = false;
+    let mut force_pty = None;
+    for cmd in pipeline.iter_mut() {
+        loop {
+            let prefix = cmd.argv[0].as_str();
+            if cmd.argv.len() < 2 || !matches!(prefix, "command" | "pty" | "nopty") {
+                break;
+            }
+            if prefix == "command" && cmd.argv[1].starts_with('-') {
+                break;
+            }
+            match prefix {
+                "command" => skip_functions = true,
+                "pty" => force_pty = Some(true),
+                _ => force_pty = Some(false),
+            }
+            cmd.argv.remove(0);
+        }
+    }
+    if pipeline.len() > 1 && force_pty == Some(true) {
+        writeln!(stream, "[warn] pty: only a single command can run in a PTY")?;
+    }
+
+    if shell.xtrace {
+        let trace: Vec<String> = pipeline.iter().map(|c| c.argv.join(" ")).collect();
+        writeln!(stream, "+ {}", trace.join(" | "))?;
+    }
+
+    // Every command is audited once it is done, however it ended
+    let cwd = Some(shell.cwd.clone());
+    let started = Instant::now();
+    let mut bytes = None;
+    let flow = run_command(shell, &pipeline, skip_functions, force_pty, &mut bytes, stream);
+    audit::record(AuditRecord {
+        peer: client_peer(shell.session.as_deref()),
+        session: shell.session.as_ref().map(|s| s.token().to_string()),
+        argv: pipeline.iter().map(|c| c.argv.clone()).collect(),
+        cwd,
+        status: Some(shell.last_status),
+        duration: Some(started.elapsed()),
+        bytes_in: bytes.map(|(sent_in, _)| sent_in),
+        bytes_out: bytes.map(|(_, sent_out)| sent_out),
+        ..AuditRecord::new("net_shell", "command")
+    });
+    flow
+}
+
+/// Runs a parsed command line: the shell's own builtins, a function, or
+/// programs in a PTY or a pipeline. `bytes` gets the bytes from and to the
+/// client when programs ran; builtins that write to the connection
+/// themselves are not counted.
+fn run_command(
+    shell: &mut Shell,
+    pipeline: &[CommandSpec],
+    skip_functions: bool,
+    force_pty: Option<bool>,
+    bytes: &mut Option<(u64, u64)>,
+    stream: &mut TcpStream,
+) -> io::Result<Flow> {
+    if pipeline.len() == 1 {
+        let argv = &pipeline[0].argv;
+        let count = || {
+            argv.get(1)
+                .and_then(|a| a.parse::<usize>().ok())
+                .unwrap_or(1)
+                .max(1)
+        };
+        match argv[0].as_str() {
+            cmd if cmd.eq_ignore_ascii_case("exit") => {
+                if let Some(code) = argv.get(1).and_then(|a| a.parse::<i32>().ok()) {
+                    shell.last_status = code;
+                }
+                return Ok(Flow::Exit);
+            }
+            "break" => return Ok(Flow::Break(count())),
+            "continue" => return Ok(Flow::Continue(count())),
+            "return" => {
+                if let Some(code) = argv.get(1).and_then(|a| a.parse::<i32>().ok()) {
+                    shell.last_status = code;
+                }
+                return Ok(Flow::Return);
+            }
+            "shift" => {
+                let n = count().min(shell.positional.len());
+                shell.positional.drain(..n);
+                shell.last_status = 0;
+                return Ok(Flow::Normal);
+            }
+            "set" if argv.len() > 1 && (argv[1].starts_with('-') || argv[1].starts_with('+')) => {
+                set_options(shell, &argv[1..], stream)?;
+                return Ok(Flow::Normal);
+            }
+            "source" | "." => return source_file(shell, &argv[1..], stream),
+            "alias" => {
+                alias_builtin(shell, &argv[1..], stream)?;
+                return Ok(Flow::Normal);
+            }
+            "unalias" => {
+                unalias_builtin(shell, &argv[1..], stream)?;
+                return Ok(Flow::Normal);
+            }
+            "hash" => {
+                hash_builtin(shell, &argv[1..], stream)?;
+                return Ok(Flow::Normal);
+            }
+            "type" => {
+                type_builtin(shell, &argv[1..], stream)?;
+                return Ok(Flow::Normal);
+            }
+            "command" => {
+                command_builtin(shell, &argv[1..], stream)?;
+                return Ok(Flow::Normal);
+            }
+            "pty" | "nopty" => {
+                writeln!(stream, "Usage: {} <command> [args...]", argv[0])?;
+                shell.last_status = 2;
+                return Ok(Flow::Normal);
+            }
+            "ptycmds" => {
+                ptycmds_builtin(shell, &argv[1..], stream)?;
+                return Ok(Flow::Normal);
+            }
+            "detach" => {
+                match &shell.session {
+                    Some(session) => {
+                        session.detach();
+                        shell.last_status = 0;
+                    }
+                    None => {
+                        writeln!(stream, "detach: not in a detachable session")?;
+                        shell.last_status = 1;
+                    }
+                }
+                return Ok(Flow::Normal);
+            }
+            "run" => {
+                let run = match parse_run_args(&argv[1..]) {
+                    Ok(run) => run,
+                    Err(e) => {
+                        writeln!(stream, "run: {}", e)?;
+                        shell.last_status = 2;
+                        return Ok(Flow::Normal);
+                    }
+                };
+                let src = match fs::read_to_string(shell.path(&run.name)) {
+                    Ok(src) => src,
+                    Err(e) => {
+                        writeln!(stream, "run: {}: {}", run.name, e)?;
+                        shell.last_status = 1;
+                        return Ok(Flow::Normal);
+                    }
+                };
+                return run_script(shell, run, &src, stream);
+            }
+            _ => {}
+        }
+        if let Some(body) = shell.functions.get(&argv[0]).cloned().filter(|_| !skip_functions) {
+            let args = argv[1..].to_vec();
+            return call_function(shell, &body, args, stream);
+        }
+    }
+
+    // If the pipeline is just 1 command, and that command is interactive
+    // (e.g. "vim"), spawn in a PTY. Otherwise, do normal pipeline logic.
+    let use_pty = pipeline.len() == 1
+        && !is_builtin(&pipeline[0].argv[0], shell)
+        && force_pty.unwrap_or_else(|| is_interactive_command(&pipeline[0], shell));
+    if use_pty {
+        #[cfg(unix)]
+        {
+            // We'll drop into a PTY session for that command
+            let cmd = &pipeline[0];
+            let (rows, cols) = shell.terminal.as_ref().map_or((24, 80), |t| (t.rows, t.cols));
+            let exit = unix_pty::run_in_pty_sized(cmd, stream, rows, cols)?;
+            *bytes = Some((exit.bytes_in, exit.bytes_out));
+            shell.set_pipeline_status(&[StageStatus::from(exit.status)]);
+        }
+        #[cfg(windows)]
+        {
+            let cmd = &pipeline[0];
+            win_pty::run_in_pty(cmd, stream)?;
+            shell.last_status = 0;
+        }
+    } else {
+        // Non-interactive pipeline
+        let statuses = match run_pipeline(pipeline, stream, shell) {
+            Ok((statuses, sent)) => {
+                *bytes = Some((0, sent));
+                statuses
+            }
+            Err(e) => {
+                let message = format!("Error: {}\n", e);
+                write_output(stream, message.as_bytes(), Some(shell.stderr_mode)).ok();
+                vec![StageStatus::Exited(1)]
+            }
+        };
+        for (cmd, status) in pipeline.iter().zip(&statuses) {
+            // like other shells, stay quiet about stages that lost their reader
+            if let StageStatus::Signaled(sig) = status {
+                if is_sigpipe(*sig) {
+                    continue;
+                }
+                writeln!(stream, "[status] {}: terminated by {} (signal {})", cmd.argv[0], signal_name(*sig), sig)?;
+            }
+        }
+        shell.set_pipeline_status(&statuses);
+    }
+    Ok(Flow::Normal)
+}
+
+fn call_function(
+    shell: &mut Shell,
+    body: &[Node],
+    args: Vec<String>,
+    stream: &mut TcpStream,
+) -> io::Result<Flow> {
+    if shell.depth >= MAX_FUNCTION_DEPTH {
+        writeln!(stream, "Error: maximum function nesting depth ({}) exceeded", MAX_FUNCTION_DEPTH)?;
+        shell.last_status = 1;
+        return Ok(Flow::Normal);
+    }
+    let saved = std::mem::replace(&mut shell.positional, args);
+    shell.depth += 1;
+    let flow = exec_nodes(shell, body, stream);
+    shell.depth -= 1;
+    shell.positional = saved;
+    match flow? {
+        flow @ (Flow::Exit | Flow::Fail) => Ok(flow),
+        _ => Ok(Flow::Normal),
+    }
+}
+
+/// Runs the startup file for a new session. Its functions, aliases and
+/// options stay in effect for the rest of the session.
+fn load_rc(shell: &mut Shell, stream: &mut TcpStream) -> io::Result<()> {
+    let (name, src) = match env::var(RC_ENV_VAR) {
+        Ok(path) => match fs::read_to_string(&path) {
+            Ok(src) => (path, src),
+            Err(e) => {
+                warn!("Could not read rc file {}: {}", path, e);
+                writeln!(stream, "rc: {}: {}", path, e)?;
+                return Ok(());
+            }
+        },
+        Err(_) if !EMBEDDED_RC.trim().is_empty() => ("(embedded rc)".to_string(), EMBEDDED_RC.to_string()),
+        Err(_) => return Ok(()),
+    };
+    exec_script(shell, &src, &name, stream)?;
+    Ok(())
+}
+
+/// `alias` lists aliases, `alias name` shows one, `alias name=value` sets one.
+fn alias_builtin(shell: &mut Shell, args: &[String], stream: &mut TcpStream) -> io::Result<()> {
+    shell.last_status = 0;
+    if args.is_empty() {
+        for (name, value) in &shell.aliases {
+            writeln!(stream, "alias {}='{}'", name, value)?;
+        }
+        return Ok(());
+    }
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) if is_alias_name(name) => {
+                shell.aliases.insert(name.to_string(), value.to_string());
+            }
+            Some((name, _)) => {
+                writeln!(stream, "alias: '{}': invalid alias name", name)?;
+                shell.last_status = 1;
+            }
+            None => match shell.aliases.get(arg) {
+                Some(value) => writeln!(stream, "alias {}='{}'", arg, value)?,
+                None => {
+                    writeln!(stream, "alias: {}: not found", arg)?;
+                    shell.last_status = 1;
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+fn unalias_builtin(shell: &mut Shell, args: &[String], stream: &mut TcpStream) -> io::Result<()> {
+    shell.last_status = 0;
+    if args.is_empty() {
+        writeln!(stream, "Usage: unalias [-a] name [name ...]")?;
+        shell.last_status = 2;
+        return Ok(());
+    }
+    for arg in args {
+        if arg == "-a" {
+            shell.aliases.clear();
+        } else if shell.aliases.remove(arg).is_none() {
+            writeln!(stream, "unalias: {}: not found", arg)?;
+            shell.last_status = 1;
+        }
+    }
+    Ok(())
+}
+
+/// What a command name refers to, in the order the shell looks it up.
+enum CommandKind {
+    Alias(String),
+    Keyword,
+    Function,
+    Builtin,
+    /// a program, and whether it came from the hash table
+    File(PathBuf, bool),
+}
+
+fn describe_command(shell: &Shell, name: &str) -> Option<CommandKind> {
+    if let Some(value) = shell.aliases.get(name) {
+        return Some(CommandKind::Alias(value.clone()));
+    }
+    if KEYWORDS.contains(&name) {
+        return Some(CommandKind::Keyword);
+    }
+    if shell.functions.contains_key(name) {
+        return Some(CommandKind::Function);
+    }
+    if SPECIAL_BUILTINS.contains(&name) || is_shell_builtin(name) {
+        return Some(CommandKind::Builtin);
+    }
+    if shell.hashed_path.as_deref() == shell.vars.get(OsStr::new("PATH")).map(OsString::as_os_str) {
+        if let Some((path, _)) = shell.hashed.get(name).filter(|(p, _)| is_executable(p)) {
+            return Some(CommandKind::File(path.clone(), true));
+        }
+    }
+    if let Some(path) = shell.find_in_path(name).pop() {
+        return Some(CommandKind::File(path, false));
+    }
+    if is_utility(name) {
+        return Some(CommandKind::Builtin);
+    }
+    None
+}
+
+/// `type [-t] name...`
+fn type_builtin(shell: &mut Shell, args: &[String], stream: &mut TcpStream) -> io::Result<()> {
+    shell.last_status = 0;
+    let (terse, names) = match args.first() {
+        Some(flag) if flag == "-t" => (true, &args[1..]),
+        _ => (false, args),
+    };
+    for name in names {
+        let kind = match describe_command(shell, name) {
+            Some(kind) => kind,
+            None => {
+                if !terse {
+                    writeln!(stream, "type: {}: not found", name)?;
+                }
+                shell.last_status = 1;
+                continue;
             }
+        };
+        if terse {
+            let word = match kind {
+                CommandKind::Alias(_) => "alias",
+                CommandKind::Keyword => "keyword",
+                CommandKind::Function => "function",
+                CommandKind::Builtin => "builtin",
+                CommandKind::File(..) => "file",
+            };
+            writeln!(stream, "{}", word)?;
+            continue;
+        }
+        match kind {
+            CommandKind::Alias(value) => writeln!(stream, "{} is aliased to `{}'", name, value)?,
+            CommandKind::Keyword => writeln!(stream, "{} is a shell keyword", name)?,
+            CommandKind::Function => writeln!(stream, "{} is a function", name)?,
+            CommandKind::Builtin => writeln!(stream, "{} is a shell builtin", name)?,
+            CommandKind::File(path, true) => writeln!(stream, "{} is hashed ({})", name, path.display())?,
+            CommandKind::File(path, false) => writeln!(stream, "{} is {}", name, path.display())?,
         }
     }
+    Ok(())
+}
 
+/// `command -v name...` and `command -V name...`; running a command
+/// through `command` is handled in `run_simple`.
+fn command_builtin(shell: &mut Shell, args: &[String], stream: &mut TcpStream) -> io::Result<()> {
+    shell.last_status = 0;
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("-V") => return type_builtin(shell, &args[1..], stream),
+        Some("-v") => {
+            for name in &args[1..] {
+                match describe_command(shell, name) {
+                    Some(CommandKind::Alias(value)) => writeln!(stream, "alias {}='{}'", name, value)?,
+                    Some(CommandKind::File(path, _)) => writeln!(stream, "{}", path.display())?,
+                    Some(_) => writeln!(stream, "{}", name)?,
+                    None => shell.last_status = 1,
+                }
+            }
+        }
+        Some(opt) => {
+            writeln!(stream, "command: {}: invalid option", opt)?;
+            writeln!(stream, "Usage: command [-v | -V] name [arg ...]")?;
+            shell.last_status = 2;
+        }
+    }
     Ok(())
 }
 
-fn is_interactive_command(cmd: &CommandSpec) -> bool {
-    let base = cmd.argv[0].to_lowercase();
-    INTERACTIVE_CMDS.contains(&base.as_str())
+/// `hash` lists remembered commands, `hash -r` forgets them all,
+/// `hash -d name` forgets one and `hash name...` looks names up now.
+fn hash_builtin(shell: &mut Shell, args: &[String], stream: &mut TcpStream) -> io::Result<()> {
+    shell.last_status = 0;
+    shell.check_hashed_path();
+    match args.first().map(String::as_str) {
+        None => {
+            if shell.hashed.is_empty() {
+                writeln!(stream, "hash: hash table empty")?;
+                return Ok(());
+            }
+            writeln!(stream, "hits\tcommand")?;
+            for (path, hits) in shell.hashed.values() {
+                writeln!(stream, "{:>4}\t{}", hits, path.display())?;
+            }
+        }
+        Some("-r") => shell.hashed.clear(),
+        Some("-d") => {
+            for name in &args[1..] {
+                if shell.hashed.remove(name).is_none() {
+                    writeln!(stream, "hash: {}: not found", name)?;
+                    shell.last_status = 1;
+                }
+            }
+        }
+        Some(_) => {
+            for name in args {
+                if SPECIAL_BUILTINS.contains(&name.as_str()) || is_shell_builtin(name) {
+                    continue;
+                }
+                if shell.resolve(name).is_err() {
+                    writeln!(stream, "hash: {}: not found", name)?;
+                    shell.last_status = 1;
+                } else if let Some((_, hits)) = shell.hashed.get_mut(name) {
+                    *hits = 0;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Alias names may also use characters like `.`, `-` or `+` (e.g. `..`, `ls-l`).
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '+' | ':' | '@' | '%'))
+}
+
+/// Runs an `if`/`while`/`until` condition, where `set -e` does not apply.
+fn exec_condition(shell: &mut Shell, cond: &[Node], stream: &mut TcpStream) -> io::Result<Flow> {
+    shell.in_condition += 1;
+    let flow = exec_nodes(shell, cond, stream);
+    shell.in_condition -= 1;
+    flow
+}
+
+/// `set -e`, `set +x`, `set -o errexit`, ...; `set -o` alone lists options.
+fn set_options(shell: &mut Shell, args: &[String], stream: &mut TcpStream) -> io::Result<()> {
+    shell.last_status = 0;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let (enable, flags) = match (arg.strip_prefix('-'), arg.strip_prefix('+')) {
+            (Some(flags), _) => (true, flags),
+            (None, Some(flags)) => (false, flags),
+            (None, None) => {
+                writeln!(stream, "set: invalid option: {}", arg)?;
+                shell.last_status = 2;
+                continue;
+            }
+        };
+        let sign = if enable { '-' } else { '+' };
+        if flags == "o" {
+            match iter.next().map(String::as_str) {
+                Some("errexit") => shell.errexit = enable,
+                Some("xtrace") => shell.xtrace = enable,
+                Some("pipefail") => shell.pipefail = enable,
+                Some("stderrcolor") if enable => shell.stderr_mode = StderrMode::Color,
+                Some("stderrtag") if enable => shell.stderr_mode = StderrMode::Tagged,
+                Some("stderrcolor") | Some("stderrtag") => shell.stderr_mode = StderrMode::Plain,
+                Some("ptyauto") => shell.pty_auto = enable,
+                Some(name) => {
+                    writeln!(stream, "set: unknown option: {}", name)?;
+                    shell.last_status = 2;
+                }
+                None => {
+                    let on_off = |b: bool| if b { "on" } else { "off" };
+                    writeln!(stream, "errexit\t{}", on_off(shell.errexit))?;
+                    writeln!(stream, "pipefail\t{}", on_off(shell.pipefail))?;
+                    writeln!(stream, "ptyauto\t{}", on_off(shell.pty_auto))?;
+                    writeln!(stream, "stderrcolor\t{}", on_off(shell.stderr_mode == StderrMode::Color))?;
+                    writeln!(stream, "stderrtag\t{}", on_off(shell.stderr_mode == StderrMode::Tagged))?;
+                    writeln!(stream, "xtrace\t{}", on_off(shell.xtrace))?;
+                }
+            }
+            continue;
+        }
+        for flag in flags.chars() {
+            if !set_option(shell, flag, enable) {
+                writeln!(stream, "set: unknown option: {}{}", sign, flag)?;
+                shell.last_status = 2;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn set_option(shell: &mut Shell, flag: char, enable: bool) -> bool {
+    match flag {
+        'e' => shell.errexit = enable,
+        'x' => shell.xtrace = enable,
+        _ => return false,
+    }
+    true
+}
+
+/// Parses and runs `src` as a script in the given shell.
+fn exec_script(shell: &mut Shell, src: &str, name: &str, stream: &mut TcpStream) -> io::Result<Flow> {
+    let program = match script::parse(src) {
+        Ok(p) => p,
+        Err(ParseError::Incomplete) => {
+            writeln!(stream, "{}: unexpected end of file", name)?;
+            shell.last_status = 2;
+            return Ok(Flow::Normal);
+        }
+        Err(e) => {
+            writeln!(stream, "{}: {}", name, e)?;
+            shell.last_status = 2;
+            return Ok(Flow::Normal);
+        }
+    };
+    exec_nodes(shell, &program, stream)
+}
+
+/// `source <file> [args...]`: runs a script in the current shell, so its
+/// functions, options and `cd` stay in effect afterwards.
+fn source_file(shell: &mut Shell, args: &[String], stream: &mut TcpStream) -> io::Result<Flow> {
+    let Some(path) = args.first() else {
+        writeln!(stream, "Usage: source <file> [args...]")?;
+        shell.last_status = 2;
+        return Ok(Flow::Normal);
+    };
+    let src = match fs::read_to_string(shell.path(path)) {
+        Ok(src) => src,
+        Err(e) => {
+            writeln!(stream, "source: {}: {}", path, e)?;
+            shell.last_status = 1;
+            return Ok(Flow::Normal);
+        }
+    };
+    let saved = if args.len() > 1 {
+        Some(std::mem::replace(&mut shell.positional, args[1..].to_vec()))
+    } else {
+        None
+    };
+    let flow = exec_script(shell, &src, path, stream);
+    if let Some(saved) = saved {
+        shell.positional = saved;
+    }
+    match flow? {
+        Flow::Return => Ok(Flow::Normal),
+        flow => Ok(flow),
+    }
+}
+
+/// Arguments of `run` and `SCRIPT_UPLOAD`: `[-e] [-x] <name> [args...]`.
+struct RunArgs {
+    errexit: bool,
+    xtrace: bool,
+    name: String,
+    args: Vec<String>,
+}
+
+fn parse_run_args(argv: &[String]) -> Result<RunArgs, String> {
+    let mut run = RunArgs {
+        errexit: false,
+        xtrace: false,
+        name: String::new(),
+        args: Vec::new(),
+    };
+    let mut i = 0;
+    while i < argv.len() && argv[i].starts_with('-') && argv[i].len() > 1 {
+        for flag in argv[i][1..].chars() {
+            match flag {
+                'e' => run.errexit = true,
+                'x' => run.xtrace = true,
+                _ => return Err(format!("unknown option: -{}", flag)),
+            }
+        }
+        i += 1;
+    }
+    match argv.get(i) {
+        Some(name) => run.name = name.clone(),
+        None => return Err("Usage: run [-e] [-x] <file> [args...]".into()),
+    }
+    run.args = argv[i + 1..].to_vec();
+    Ok(run)
+}
+
+/// `run [-e] [-x] <file> [args...]`: runs a script in a fresh scope with
+/// its own positional arguments and options. It starts in the shell's cwd
+/// with its variables; functions it defines and `cd`/`set` in it do not
+/// leak back, and `exit` only ends the script.
+fn run_script(shell: &mut Shell, run: RunArgs, src: &str, stream: &mut TcpStream) -> io::Result<Flow> {
+    let mut sub = Shell {
+        aliases: shell.aliases.clone(),
+        functions: shell.functions.clone(),
+        positional: run.args,
+        errexit: run.errexit,
+        xtrace: run.xtrace,
+        depth: shell.depth,
+        pty_commands: shell.pty_commands.clone(),
+        pty_auto: shell.pty_auto,
+        terminal: shell.terminal.clone(),
+        session: shell.session.clone(),
+        stderr_mode: shell.stderr_mode,
+        cwd: shell.cwd.clone(),
+        vars: shell.vars.clone(),
+        ..Shell::new()
+    };
+    exec_script(&mut sub, src, &run.name, stream)?;
+    shell.last_status = sub.last_status;
+    if sub.last_status != 0 {
+        writeln!(stream, "run: {} exited with status {}", run.name, sub.last_status)?;
+    }
+    Ok(Flow::Normal)
+}
+
+/// Handles `SCRIPT_UPLOAD <size> [-e] [-x] <name> [args...]` followed by
+/// `size` bytes of script text, running it like `run`.
+fn run_uploaded_script<R: Read>(
+    shell: &mut Shell,
+    header: &str,
+    reader: &mut R,
+    stream: &mut TcpStream,
+) -> io::Result<Flow> {
+    let mut parts = header.split_whitespace();
+    let size: u64 = match parts.next().and_then(|s| s.parse().ok()) {
+        Some(size) => size,
+        None => {
+            writeln!(stream, "SCRIPT_UPLOAD: missing or invalid size")?;
+            return Ok(Flow::Normal);
+        }
+    };
+    if size > MAX_SCRIPT_BYTES {
+        io::copy(&mut reader.take(size), &mut io::sink())?;
+        writeln!(stream, "SCRIPT_UPLOAD: script of {} bytes is over the limit of {}", size, MAX_SCRIPT_BYTES)?;
+        return Ok(Flow::Normal);
+    }
+    let mut data = Vec::new();
+    reader.take(size).read_to_end(&mut data)?;
+    if (data.len() as u64) < size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during SCRIPT_UPLOAD"));
+    }
+
+    let argv: Vec<String> = parts.map(String::from).collect();
+    let run = match parse_run_args(&argv) {
+        Ok(run) => run,
+        Err(e) => {
+            writeln!(stream, "SCRIPT_UPLOAD: {}", e)?;
+            return Ok(Flow::Normal);
+        }
+    };
+    let src = match String::from_utf8(data) {
+        Ok(src) => src,
+        Err(_) => {
+            writeln!(stream, "SCRIPT_UPLOAD: {}: script is not valid UTF-8", run.name)?;
+            return Ok(Flow::Normal);
+        }
+    };
+    run_script(shell, run, &src, stream)
+}
+
+/// Whether a command gets a PTY: always in `ptyauto` mode once the client
+/// has a terminal, otherwise when its basename is in the session's list.
+fn is_interactive_command(cmd: &CommandSpec, shell: &Shell) -> bool {
+    if shell.pty_auto && shell.terminal.is_some() {
+        return true;
+    }
+    shell.pty_commands.contains(&command_basename(&cmd.argv[0]))
+}
+
+/// `/usr/bin/vim` -> `vim`; lowercased, without `.exe` on Windows.
+fn command_basename(program: &str) -> String {
+    let base = program.rsplit(['/', '\\']).next().unwrap_or(program).to_lowercase();
+    if cfg!(windows) {
+        if let Some(stem) = base.strip_suffix(".exe") {
+            return stem.to_string();
+        }
+    }
+    base
+}
+
+/// `ptycmds` lists the commands run in a PTY, `ptycmds name...` adds to
+/// the list, `ptycmds -d name...` removes and `ptycmds -r` resets it.
+fn ptycmds_builtin(shell: &mut Shell, args: &[String], stream: &mut TcpStream) -> io::Result<()> {
+    shell.last_status = 0;
+    match args.first().map(String::as_str) {
+        None => {
+            let list: Vec<&str> = shell.pty_commands.iter().map(String::as_str).collect();
+            writeln!(stream, "{}", list.join(" "))?;
+        }
+        Some("-r") => shell.pty_commands = INTERACTIVE_CMDS.iter().map(|c| c.to_string()).collect(),
+        Some("-d") => {
+            for name in &args[1..] {
+                if !shell.pty_commands.remove(&command_basename(name)) {
+                    writeln!(stream, "ptycmds: {}: not in the list", name)?;
+                    shell.last_status = 1;
+                }
+            }
+        }
+        Some(_) => {
+            for name in args {
+                shell.pty_commands.insert(command_basename(name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handles `TERMINAL <term> [<rows> <cols>]` from a client running in a
+/// terminal. PTY commands then get that size, and `TERM` is set.
+fn set_terminal(shell: &mut Shell, spec: &str) {
+    let Some((term, terminal)) = parse_terminal(spec) else {
+        return;
+    };
+    shell.set_var("TERM", &term);
+    debug!("Client terminal: {} {}x{}", term, terminal.cols, terminal.rows);
+    shell.terminal = Some(terminal);
+}
+
+/// Splits the `<term> [<rows> <cols>]` of a `TERMINAL` line; the size
+/// defaults to 24x80.
+fn parse_terminal(spec: &str) -> Option<(String, Terminal)> {
+    let mut parts = spec.split_whitespace();
+    let term = parts.next()?;
+    let rows = parts.next().and_then(|r| r.parse().ok()).filter(|&r| r > 0).unwrap_or(24);
+    let cols = parts.next().and_then(|c| c.parse().ok()).filter(|&c| c > 0).unwrap_or(80);
+    Some((term.to_string(), Terminal { rows, cols }))
 }
 
 
 
 pub type Pipeline = Vec<CommandSpec>;
 
-fn handle_line(line: &str) -> Result<Pipeline, String> {
-    let tokens = shell_tokenize(line)?;
+/// Replaces aliases in command position: the start of the line and after
+/// each `|`. An alias is not expanded again inside its own expansion, and
+/// a value ending in a blank makes the next word eligible too, as in POSIX sh.
+fn expand_aliases(line: &str, shell: &Shell) -> String {
+    if shell.aliases.is_empty() {
+        return line.to_string();
+    }
+    let mut chars: Vec<char> = line.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    let mut command_pos = true;
+    // end of an expansion that ended in a blank; the next word is a command
+    let mut blank_boundary: Option<usize> = None;
+    let mut used: Vec<String> = Vec::new();
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '<' | '>' => {
+                out.push(c);
+                i += 1;
+                continue;
+            }
+            '|' => {
+                out.push(c);
+                i += 1;
+                command_pos = true;
+                used.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        let start = i;
+        while i < chars.len() {
+            match chars[i] {
+                ' ' | '\t' | '|' | '<' | '>' => break,
+                '\'' | '"' => {
+                    let quote = chars[i];
+                    i += 1;
+                    while i < chars.len() && chars[i] != quote {
+                        if quote == '"' && chars[i] == '\\' {
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                '\\' => i += 2,
+                _ => i += 1,
+            }
+        }
+        let end = i.min(chars.len());
+        let word: String = chars[start..end].iter().collect();
+
+        if let Some(boundary) = blank_boundary {
+            if start >= boundary {
+                command_pos = true;
+                blank_boundary = None;
+            }
+        }
+
+        if command_pos && !used.contains(&word) {
+            if let Some(value) = shell.aliases.get(&word) {
+                used.push(word);
+                // splice the value in place of the word and rescan it
+                let mut spliced: Vec<char> = value.chars().collect();
+                blank_boundary = if value.ends_with([' ', '\t']) {
+                    Some(spliced.len())
+                } else {
+                    None
+                };
+                spliced.extend_from_slice(&chars[end..]);
+                chars = spliced;
+                i = 0;
+                continue;
+            }
+        }
+        out.push_str(&word);
+        command_pos = false;
+    }
+    out
+}
+
+/// A token produced by `shell_tokenize`. Operators are kept apart from
+/// words so that a quoted or escaped `|` or `>` stays an ordinary word.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Op(&'static str),
+}
+
+fn handle_line(line: &str, shell: &Shell) -> Result<Pipeline, String> {
+    let line = expand_aliases(line, shell);
+    let tokens = shell_tokenize(&line, shell)?;
 
     // 2) split on '|'
     let mut commands = Vec::new();
     let mut current = Vec::new();
     for token in tokens {
-        if token == "|" {
+        if token == Token::Op("|") {
             if !current.is_empty() {
                 commands.push(current);
             }
@@ -167,13 +1671,16 @@ fn handle_line(line: &str) -> Result<Pipeline, String> {
 
     let mut pipeline = Vec::new();
     for cmd_tokens in commands {
-        pipeline.push(parse_one_command(cmd_tokens)?);
+        let mut cmdspec = parse_one_command(cmd_tokens)?;
+        cmdspec.cwd = Some(shell.cwd.clone());
+        cmdspec.env = Some(shell.vars.clone());
+        pipeline.push(cmdspec);
     }
 
     Ok(pipeline)
 }
 
-fn parse_one_command(tokens: Vec<String>) -> Result<CommandSpec, String> {
+fn parse_one_command(tokens: Vec<Token>) -> Result<CommandSpec, String> {
     let mut argv = Vec::new();
     let mut redirect_in = None;
     let mut redirect_out = None;
@@ -183,39 +1690,23 @@ fn parse_one_command(tokens: Vec<String>) -> Result<CommandSpec, String> {
 
     let mut i = 0;
     while i < tokens.len() {
-        let t = &tokens[i];
-        if t == "<" {
-            i += 1;
-            if i >= tokens.len() {
-                return Err("Missing filename after '<'".into());
-            }
-            redirect_in = Some(tokens[i].clone());
-        } else if t == ">" {
-            i += 1;
-            if i >= tokens.len() {
-                return Err("Missing filename after '>'".into());
-            }
-            redirect_out = Some(tokens[i].clone());
-        } else if t == ">>" {
-            i += 1;
-            if i >= tokens.len() {
-                return Err("Missing filename after '>>'".into());
-            }
-            redirect_out_append = Some(tokens[i].clone());
-        } else if t == "2>" {
-            i += 1;
-            if i >= tokens.len() {
-                return Err("Missing filename after '2>'".into());
-            }
-            redirect_err = Some(tokens[i].clone());
-        } else if t == "2>>" {
-            i += 1;
-            if i >= tokens.len() {
-                return Err("Missing filename after '2>>'".into());
-            }
-            redirect_err_append = Some(tokens[i].clone());
-        } else {
-            argv.push(t.clone());
+        match &tokens[i] {
+            Token::Word(w) => argv.push(w.clone()),
+            Token::Op(op) => {
+                i += 1;
+                let target = match tokens.get(i) {
+                    Some(Token::Word(w)) => w.clone(),
+                    _ => return Err(format!("Missing filename after '{}'", op)),
+                };
+                match *op {
+                    "<" => redirect_in = Some(target),
+                    ">" => redirect_out = Some(target),
+                    ">>" => redirect_out_append = Some(target),
+                    "2>" => redirect_err = Some(target),
+                    "2>>" => redirect_err_append = Some(target),
+                    _ => return Err(format!("Unexpected '{}'", op)),
+                }
+            }
         }
         i += 1;
     }
@@ -231,12 +1722,40 @@ fn parse_one_command(tokens: Vec<String>) -> Result<CommandSpec, String> {
         redirect_out_append,
         redirect_err,
         redirect_err_append,
+        cwd: None,
+        env: None,
     })
 }
 
-fn shell_tokenize(line: &str) -> Result<Vec<String>, String> {
+/// How a character of a word was quoted. Single quotes and backslash
+/// escapes suppress variable expansion and globbing; double quotes
+/// suppress only globbing.
+#[derive(Clone, Copy, PartialEq)]
+enum Quoting {
+    Unquoted,
+    Single,
+    Double,
+}
+
+#[derive(Clone, Copy)]
+struct WordChar {
+    ch: char,
+    quoting: Quoting,
+}
+
+impl WordChar {
+    fn unquoted(ch: char) -> Self {
+        WordChar { ch, quoting: Quoting::Unquoted }
+    }
+}
+
+/// Splits a command line into words and operators, expanding each word.
+/// Handles POSIX quoting, backslash escapes and `\<newline>` continuations.
+fn shell_tokenize(line: &str, shell: &Shell) -> Result<Vec<Token>, String> {
     let mut tokens = Vec::new();
-    let mut current = String::new();
+    let mut current: Vec<WordChar> = Vec::new();
+    // true once the current word has any content, even an empty "" or ''
+    let mut in_word = false;
     let mut chars = line.chars().peekable();
 
     enum State {
@@ -250,54 +1769,32 @@ fn shell_tokenize(line: &str) -> Result<Vec<String>, String> {
         match state {
             State::Normal => match ch {
                 ' ' | '\t' => {
-                    if !current.is_empty() {
-                        // expand
-                        let expanded = expand_token(&current)?;
-                        tokens.extend(expanded);
-                        current.clear();
-                    }
+                    flush_word(&mut current, &mut in_word, &mut tokens, shell)?;
                 }
                 '|' => {
-                    if !current.is_empty() {
-                        let expanded = expand_token(&current)?;
-                        tokens.extend(expanded);
-                        current.clear();
-                    }
-                    tokens.push("|".to_string());
+                    flush_word(&mut current, &mut in_word, &mut tokens, shell)?;
+                    tokens.push(Token::Op("|"));
                 }
                 '<' => {
-                    if !current.is_empty() {
-                        let expanded = expand_token(&current)?;
-                        tokens.extend(expanded);
-                        current.clear();
-                    }
-                    tokens.push("<".to_string());
+                    flush_word(&mut current, &mut in_word, &mut tokens, shell)?;
+                    tokens.push(Token::Op("<"));
                 }
                 '>' => {
-                    if !current.is_empty() {
-                        let expanded = expand_token(&current)?;
-                        tokens.extend(expanded);
-                        current.clear();
-                    }
+                    flush_word(&mut current, &mut in_word, &mut tokens, shell)?;
                     // peek next for >>?
                     if let Some(&nch) = chars.peek() {
                         if nch == '>' {
                             chars.next();
-                            tokens.push(">>".to_string());
+                            tokens.push(Token::Op(">>"));
                         } else {
-                            tokens.push(">".to_string());
+                            tokens.push(Token::Op(">"));
                         }
                     } else {
-                        tokens.push(">".to_string());
+                        tokens.push(Token::Op(">"));
                     }
                 }
-                '2' => {
-                    // detect 2> or 2>> 
-                    if !current.is_empty() {
-                        let expanded = expand_token(&current)?;
-                        tokens.extend(expanded);
-                        current.clear();
-                    }
+                '2' if !in_word => {
+                    // detect 2> or 2>> at the start of a word
                     if let Some(&nch) = chars.peek() {
                         if nch == '>' {
                             chars.next();
@@ -305,320 +1802,1527 @@ fn shell_tokenize(line: &str) -> Result<Vec<String>, String> {
                             if let Some(&nn) = chars.peek() {
                                 if nn == '>' {
                                     chars.next();
-                                    tokens.push("2>>".to_string());
+                                    tokens.push(Token::Op("2>>"));
                                 } else {
-                                    tokens.push("2>".to_string());
+                                    tokens.push(Token::Op("2>"));
                                 }
                             } else {
-                                tokens.push("2>".to_string());
+                                tokens.push(Token::Op("2>"));
                             }
                         } else {
                             // just '2'
-                            current.push('2');
+                            current.push(WordChar::unquoted('2'));
+                            in_word = true;
                         }
                     } else {
-                        current.push('2');
+                        current.push(WordChar::unquoted('2'));
+                        in_word = true;
                     }
                 }
+                '\\' => match chars.next() {
+                    // line continuation
+                    Some('\n') => {}
+                    // escaped character: taken literally
+                    Some(nextch) => {
+                        current.push(WordChar { ch: nextch, quoting: Quoting::Single });
+                        in_word = true;
+                    }
+                    // a lone trailing backslash stays as is
+                    None => {
+                        current.push(WordChar { ch: '\\', quoting: Quoting::Single });
+                        in_word = true;
+                    }
+                },
                 '\'' => {
                     // single quote
                     state = State::InSingleQuote;
+                    in_word = true;
                 }
                 '"' => {
                     // double quote
                     state = State::InDoubleQuote;
+                    in_word = true;
                 }
                 _ => {
-                    current.push(ch);
+                    current.push(WordChar::unquoted(ch));
+                    in_word = true;
                 }
             },
             State::InSingleQuote => {
                 if ch == '\'' {
                     state = State::Normal;
                 } else {
-                    current.push(ch);
+                    current.push(WordChar { ch, quoting: Quoting::Single });
                 }
             }
             State::InDoubleQuote => {
                 if ch == '"' {
                     state = State::Normal;
                 } else if ch == '\\' {
-                    if let Some(nextch) = chars.next() {
-                        current.push(nextch);
+                    // inside double quotes, backslash only escapes $ ` " \ and newline
+                    match chars.peek().copied() {
+                        Some('\n') => {
+                            chars.next();
+                        }
+                        Some(nextch @ ('$' | '`' | '"' | '\\')) => {
+                            chars.next();
+                            current.push(WordChar { ch: nextch, quoting: Quoting::Single });
+                        }
+                        _ => current.push(WordChar { ch, quoting: Quoting::Double }),
                     }
                 } else {
-                    current.push(ch);
+                    current.push(WordChar { ch, quoting: Quoting::Double });
+                }
+            }
+        }
+    }
+
+    match state {
+        State::InSingleQuote => return Err("unterminated single quote".into()),
+        State::InDoubleQuote => return Err("unterminated double quote".into()),
+        State::Normal => {}
+    }
+    flush_word(&mut current, &mut in_word, &mut tokens, shell)?;
+
+    Ok(tokens)
+}
+
+/// Expands the word collected so far (if any) onto `tokens`.
+fn flush_word(
+    current: &mut Vec<WordChar>,
+    in_word: &mut bool,
+    tokens: &mut Vec<Token>,
+    shell: &Shell,
+) -> Result<(), String> {
+    if *in_word {
+        let expanded = expand_token(current, shell)?;
+        tokens.extend(expanded.into_iter().map(Token::Word));
+        if tokens.len() > MAX_LINE_WORDS {
+            return Err(TOO_MANY_WORDS.into());
+        }
+        current.clear();
+        *in_word = false;
+    }
+    Ok(())
+}
+
+/// Expand one word: braces, tilde, variables, then globbing relative to
+/// the shell's working directory. Quoted characters are never globbed.
+fn expand_token(token: &[WordChar], shell: &Shell) -> Result<Vec<String>, String> {
+    let mut results = Vec::new();
+    let words = expand_braces(token).ok_or(TOO_MANY_WORDS)?;
+    for word in words {
+        // "$@" and "$*" expand to one word per positional argument
+        let text: String = word.iter().map(|c| c.ch).collect();
+        let unquoted_or_double = word.iter().all(|c| c.quoting != Quoting::Single);
+        if (text == "$@" || text == "$*") && unquoted_or_double {
+            results.extend(shell.positional.iter().cloned());
+            continue;
+        }
+
+        let word = expand_vars(&expand_tilde(&word, shell), shell);
+        let literal: String = word.iter().map(|c| c.ch).collect();
+        let has_glob = word
+            .iter()
+            .any(|c| c.quoting == Quoting::Unquoted && matches!(c.ch, '*' | '?' | '['));
+        if has_glob {
+            let pattern: String = word
+                .iter()
+                .map(|c| match c.quoting {
+                    Quoting::Unquoted => c.ch.to_string(),
+                    _ => glob::Pattern::escape(&c.ch.to_string()),
+                })
+                .collect();
+            let matches = do_glob(&pattern, &shell.cwd);
+            if !matches.is_empty() {
+                results.extend(matches);
+                continue;
+            }
+        }
+        results.push(literal);
+    }
+    Ok(results)
+}
+
+/// Brace expansion on unquoted braces: `{a,b,c}`, `{1..5}`, `{a..e}`,
+/// `{01..10..3}`. `${name}` is left for variable expansion. `None` if the
+/// word expands to more than `MAX_LINE_WORDS` words.
+fn expand_braces(word: &[WordChar]) -> Option<Vec<Vec<WordChar>>> {
+    let is_open = |i: usize| {
+        word[i].ch == '{'
+            && word[i].quoting == Quoting::Unquoted
+            && !(i > 0 && word[i - 1].ch == '$' && word[i - 1].quoting == Quoting::Unquoted)
+    };
+
+    for open in (0..word.len()).filter(|&i| is_open(i)) {
+        // find the matching close brace and the top-level commas
+        let mut depth = 0;
+        let mut commas = Vec::new();
+        let mut close = None;
+        for (i, c) in word.iter().enumerate().skip(open + 1) {
+            if c.quoting != Quoting::Unquoted {
+                continue;
+            }
+            match c.ch {
+                '{' => depth += 1,
+                '}' if depth == 0 => {
+                    close = Some(i);
+                    break;
                 }
+                '}' => depth -= 1,
+                ',' if depth == 0 => commas.push(i),
+                _ => {}
+            }
+        }
+        let Some(close) = close else { continue };
+
+        let alternatives: Vec<Vec<WordChar>> = if !commas.is_empty() {
+            let mut bounds = vec![open];
+            bounds.extend(&commas);
+            bounds.push(close);
+            bounds.windows(2).map(|w| word[w[0] + 1..w[1]].to_vec()).collect()
+        } else {
+            let inner: String = word[open + 1..close].iter().map(|c| c.ch).collect();
+            match brace_range(&inner) {
+                Some(items) => items
+                    .into_iter()
+                    .map(|s| s.chars().map(WordChar::unquoted).collect())
+                    .collect(),
+                None => continue,
+            }
+        };
+
+        let mut results = Vec::new();
+        for alt in alternatives {
+            let mut combined = word[..open].to_vec();
+            combined.extend(alt);
+            combined.extend_from_slice(&word[close + 1..]);
+            results.extend(expand_braces(&combined)?);
+            if results.len() > MAX_LINE_WORDS {
+                return None;
+            }
+        }
+        return Some(results);
+    }
+    Some(vec![word.to_vec()])
+}
+
+/// Expands the body of a `{start..end[..step]}` sequence expression.
+fn brace_range(inner: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = inner.split("..").collect();
+    if parts.len() != 2 && parts.len() != 3 {
+        return None;
+    }
+    // i128 so no pair of i64 ends (or step) can overflow the arithmetic
+    let step: i128 = match parts.get(2) {
+        Some(s) => (s.parse::<i64>().ok()? as i128).abs().max(1),
+        None => 1,
+    };
+
+    if let (Ok(start), Ok(end)) = (parts[0].parse::<i64>(), parts[1].parse::<i64>()) {
+        let (start, end) = (start as i128, end as i128);
+        // zero-padded if either end has a leading zero, like bash
+        let padded = |s: &str| s.trim_start_matches('-').len() > 1 && s.trim_start_matches('-').starts_with('0');
+        let width = if padded(parts[0]) || padded(parts[1]) {
+            parts[0].len().max(parts[1].len())
+        } else {
+            0
+        };
+        let count = (start - end).abs() / step + 1;
+        if count > MAX_BRACE_ITEMS as i128 {
+            return None;
+        }
+        let dir = if end >= start { 1 } else { -1 };
+        return Some(
+            (0..count)
+                .map(|k| format!("{:0width$}", start + dir * k * step, width = width))
+                .collect(),
+        );
+    }
+
+    let (mut a, mut b) = (parts[0].chars(), parts[1].chars());
+    match (a.next(), a.next(), b.next(), b.next()) {
+        (Some(start), None, Some(end), None) if start.is_ascii_alphabetic() && end.is_ascii_alphabetic() => {
+            let (s, e) = (start as i128, end as i128);
+            let dir = if e >= s { 1 } else { -1 };
+            let count = (s - e).abs() / step + 1;
+            Some(
+                (0..count)
+                    .filter_map(|k| char::from_u32((s + dir * k * step) as u32))
+                    .map(String::from)
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// `~` and `~user` at the start of a word, up to the first `/`.
+fn expand_tilde(word: &[WordChar], shell: &Shell) -> Vec<WordChar> {
+    if word.first().map(|c| (c.ch, c.quoting)) != Some(('~', Quoting::Unquoted)) {
+        return word.to_vec();
+    }
+    let end = word.iter().position(|c| c.ch == '/').unwrap_or(word.len());
+    if word[1..end].iter().any(|c| c.quoting != Quoting::Unquoted) {
+        return word.to_vec();
+    }
+    let user: String = word[1..end].iter().map(|c| c.ch).collect();
+    let home = if user.is_empty() {
+        shell.var("HOME").or_else(|| shell.var("USERPROFILE"))
+    } else {
+        user_home(&user)
+    };
+    match home {
+        Some(home) => {
+            // the expansion itself is not subject to globbing
+            let mut result: Vec<WordChar> = home
+                .chars()
+                .map(|ch| WordChar { ch, quoting: Quoting::Double })
+                .collect();
+            result.extend_from_slice(&word[end..]);
+            result
+        }
+        None => word.to_vec(),
+    }
+}
+
+#[cfg(unix)]
+fn user_home(user: &str) -> Option<String> {
+    nix::unistd::User::from_name(user)
+        .ok()
+        .flatten()
+        .map(|u| u.dir.to_string_lossy().to_string())
+}
+
+#[cfg(windows)]
+fn user_home(_user: &str) -> Option<String> {
+    None
+}
+
+/// Expands `$name`, `${name}` and positional parameters outside single
+/// quotes. Values keep the quoting of the `$`, so unquoted values may glob.
+fn expand_vars(word: &[WordChar], shell: &Shell) -> Vec<WordChar> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < word.len() {
+        let c = word[i];
+        if c.ch == '$' && c.quoting != Quoting::Single {
+            let rest: Vec<char> = word[i + 1..]
+                .iter()
+                .take_while(|n| n.quoting == c.quoting)
+                .map(|n| n.ch)
+                .collect();
+            if let Some((value, used)) = lookup_var(&rest, shell) {
+                result.extend(value.chars().map(|ch| WordChar { ch, quoting: c.quoting }));
+                i += 1 + used;
+                continue;
+            }
+        }
+        result.push(c);
+        i += 1;
+    }
+    result
+}
+
+/// Resolves the variable reference following a `$`, returning its value
+/// and how many characters of `rest` it used.
+fn lookup_var(rest: &[char], shell: &Shell) -> Option<(String, usize)> {
+    // positional parameters: $0..$9, $#, $@, $*; last status: $?
+    match rest.first().copied() {
+        Some('?') => return Some((shell.last_status.to_string(), 1)),
+        Some(d) if d.is_ascii_digit() => {
+            let idx = d.to_digit(10).unwrap() as usize;
+            let value = if idx == 0 {
+                "rust-sh".to_string()
+            } else {
+                shell.positional.get(idx - 1).cloned().unwrap_or_default()
+            };
+            return Some((value, 1));
+        }
+        Some('#') => return Some((shell.positional.len().to_string(), 1)),
+        Some('@') | Some('*') => return Some((shell.positional.join(" "), 1)),
+        Some('{') => {
+            let close = rest.iter().position(|&c| c == '}')?;
+            let name: String = rest[1..close].iter().collect();
+            if name.is_empty() {
+                return None;
             }
+            // ${PIPESTATUS[n]}, ${PIPESTATUS[@]}
+            if let Some(index) = name.strip_prefix("PIPESTATUS[").and_then(|n| n.strip_suffix(']')) {
+                let value = match index {
+                    "@" | "*" => pipestatus_words(shell),
+                    n => n
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|n| shell.pipestatus.get(n))
+                        .map(|c| c.to_string())
+                        .unwrap_or_default(),
+                };
+                return Some((value, close + 1));
+            }
+            let (value, _) = lookup_var(&name.chars().collect::<Vec<_>>(), shell)?;
+            return Some((value, close + 1));
         }
+        _ => {}
     }
-
-    if !current.is_empty() {
-        let expanded = expand_token(&current)?;
-        tokens.extend(expanded);
+    let varname: String = rest
+        .iter()
+        .take_while(|c| c.is_alphanumeric() || **c == '_')
+        .collect();
+    if varname.is_empty() {
+        return None;
     }
-
-    Ok(tokens)
+    let used = varname.chars().count();
+    if varname == "PIPESTATUS" {
+        return Some((pipestatus_words(shell), used));
+    }
+    Some((shell.var(&varname).unwrap_or_default(), used))
 }
 
-/// Expand environment variables and do simple globbing
-fn expand_token(token: &str) -> Result<Vec<String>, String> {
-    let env_expanded = expand_env(token);
-    let results = do_glob(&env_expanded);
-    if results.is_empty() {
-        Ok(vec![env_expanded])
-    } else {
-        Ok(results)
-    }
+fn pipestatus_words(shell: &Shell) -> String {
+    shell
+        .pipestatus
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-fn expand_env(text: &str) -> String {
+fn expand_env(text: &str, shell: &Shell) -> String {
+    let chars: Vec<char> = text.chars().collect();
     let mut result = String::new();
-    let mut chars = text.chars().peekable();
-    while let Some(ch) = chars.peek().cloned() {
-        if ch == '$' {
-            chars.next();
-            let mut varname = String::new();
-            while let Some(&c2) = chars.peek() {
-                if c2.is_alphanumeric() || c2 == '_' {
-                    varname.push(c2);
-                    chars.next();
-                } else {
-                    break;
-                }
-            }
-            if varname.is_empty() {
-                result.push('$');
-            } else {
-                if let Ok(val) = env::var(&varname) {
-                    result.push_str(&val);
-                }
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '$' {
+            if let Some((value, used)) = lookup_var(&chars[i + 1..], shell) {
+                result.push_str(&value);
+                i += 1 + used;
+                continue;
             }
-        } else {
-            result.push(ch);
-            chars.next();
         }
+        result.push(chars[i]);
+        i += 1;
     }
     result
 }
 
-fn do_glob(text: &str) -> Vec<String> {
-    if text.contains('*') || text.contains('?') || text.contains('[') {
-        if let Ok(paths) = glob(text) {
-            return paths.filter_map(Result::ok)
-                        .filter_map(|p| p.to_str().map(String::from))
-                        .collect();
+/// Globs `pattern` relative to `cwd`. Returned paths stay relative when
+/// the pattern is; hidden files only match a literal leading dot.
+fn do_glob(pattern: &str, cwd: &Path) -> Vec<String> {
+    let options = MatchOptions {
+        require_literal_leading_dot: true,
+        ..MatchOptions::new()
+    };
+    if Path::new(pattern).is_absolute() {
+        return match glob_with(pattern, options) {
+            Ok(paths) => paths
+                .filter_map(Result::ok)
+                .filter_map(|p| p.to_str().map(String::from))
+                .collect(),
+            Err(_) => vec![],
+        };
+    }
+
+    let base = format!("{}/", cwd.to_string_lossy().trim_end_matches(['/', '\\']));
+    let full = format!("{}{}", glob::Pattern::escape(&base), pattern);
+    match glob_with(&full, options) {
+        Ok(paths) => paths
+            .filter_map(Result::ok)
+            .filter_map(|p| p.to_str().and_then(|s| s.strip_prefix(&base)).map(String::from))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Expands a `case` subject or pattern without globbing or splitting.
+/// For patterns, quoted text is escaped so it only matches literally.
+fn expand_case_word(raw: &str, shell: &Shell, as_pattern: bool) -> String {
+    let mut result = String::new();
+    let mut segment = String::new();
+    let mut quote: Option<char> = None;
+
+    let flush = |segment: &mut String, quoted: Option<char>, result: &mut String| {
+        let text = match quoted {
+            Some('\'') => segment.clone(),
+            _ => expand_env(segment, shell),
+        };
+        if as_pattern && quoted.is_some() {
+            result.push_str(&glob::Pattern::escape(&text));
+        } else {
+            result.push_str(&text);
+        }
+        segment.clear();
+    };
+
+    for ch in raw.chars() {
+        match (quote, ch) {
+            (None, '\'') | (None, '"') => {
+                flush(&mut segment, None, &mut result);
+                quote = Some(ch);
+            }
+            (Some(q), c) if c == q => {
+                flush(&mut segment, quote, &mut result);
+                quote = None;
+            }
+            _ => segment.push(ch),
         }
     }
-    vec![]
+    flush(&mut segment, quote, &mut result);
+    result
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 // EXECUTION: pipelines, built-ins, external commands
 ////////////////////////////////////////////////////////////////////////////////
 
-fn run_pipeline(pipeline: &Pipeline, stream: &mut TcpStream) -> io::Result<()> {
+/// How one pipeline stage finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StageStatus {
+    Exited(i32),
+    Signaled(i32),
+}
+
+impl StageStatus {
+    /// The shell-visible status: the exit code, or 128 + signal number.
+    fn code(self) -> i32 {
+        match self {
+            StageStatus::Exited(code) => code,
+            StageStatus::Signaled(sig) => 128 + sig,
+        }
+    }
+}
+
+impl From<std::process::ExitStatus> for StageStatus {
+    fn from(status: std::process::ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(sig) = status.signal() {
+                return StageStatus::Signaled(sig);
+            }
+        }
+        StageStatus::Exited(status.code().unwrap_or(1))
+    }
+}
+
+#[cfg(unix)]
+fn signal_name(sig: i32) -> String {
+    nix::sys::signal::Signal::try_from(sig)
+        .map(|s| s.as_str().to_string())
+        .unwrap_or_else(|_| format!("SIG{}", sig))
+}
+
+#[cfg(windows)]
+fn signal_name(sig: i32) -> String {
+    format!("SIG{}", sig)
+}
+
+#[cfg(unix)]
+fn is_sigpipe(sig: i32) -> bool {
+    sig == nix::sys::signal::Signal::SIGPIPE as i32
+}
+
+#[cfg(windows)]
+fn is_sigpipe(_sig: i32) -> bool {
+    false
+}
+
+/// Where a pipeline stage reads its input from.
+enum StageInput {
+    /// first stage: the shell's own stdin
+    Inherit,
+    /// the previous stage failed or sent its output elsewhere
+    Closed,
+    Pipe(std::process::ChildStdout),
+    /// output of a built-in
+    Bytes(Vec<u8>),
+}
+
+/// Runs a pipeline and returns the status of every stage, in order, and
+/// the bytes sent to the client. A stage that cannot be started gets
+/// status 127 (not found) or 126, and the stage after it reads empty input.
+///
+/// All output is drained before returning, so it always arrives ahead of
+/// the next prompt. The last stage's stdout and every stage's stderr share
+/// one writer and are sent chunk by chunk, never split into each other.
+fn run_pipeline(
+    pipeline: &[CommandSpec],
+    stream: &mut TcpStream,
+    shell: &mut Shell,
+) -> io::Result<(Vec<StageStatus>, u64)> {
+    let stderr_mode = shell.stderr_mode;
+    let mut statuses = vec![StageStatus::Exited(0); pipeline.len()];
     if pipeline.is_empty() {
-        return Ok(());
+        return Ok((statuses, 0));
     }
+    let writer = Arc::new(Mutex::new(ClientWriter { stream: stream.try_clone()?, sent: 0 }));
+    // the shell's own messages about a stage go out as its stderr
+    let report = |message: String| send_output(&writer, message.as_bytes(), Some(stderr_mode));
+    let mut copiers = Vec::new();
 
-    // We'll store the "stdout" from the previous stage
-    let mut prev_stdout: Option<std::process::ChildStdout> = None;
+    // What the previous stage hands to the next one
+    let mut prev_output = StageInput::Inherit;
     let mut children = Vec::new();
 
     for (i, cmdspec) in pipeline.iter().enumerate() {
         let is_last = i == pipeline.len() - 1;
+        let input = std::mem::replace(&mut prev_output, StageInput::Closed);
 
-        if is_builtin(&cmdspec.argv[0]) {
-            // If we had a prev_stdout, read it
-            let input_data = if let Some(mut pipe_out) = prev_stdout.take() {
-                let mut buf = Vec::new();
-                let _ = pipe_out.read_to_end(&mut buf);
-                buf
-            } else {
-                Vec::new()
+        if is_builtin(&cmdspec.argv[0], shell) {
+            // Built-ins run in the shell itself, on buffered output
+            let mut out = Vec::new();
+            let mut err = Vec::new();
+            let code = match run_builtin_stage(shell, cmdspec, input, &mut out, &mut err) {
+                Ok(code) => code,
+                Err(e) => {
+                    writeln!(err, "Error: {}: {}", cmdspec.argv[0], e).ok();
+                    statuses[i] = StageStatus::Exited(1);
+                    send_output(&writer, &err, Some(stderr_mode));
+                    continue;
+                }
             };
-            let output_data = run_builtin(cmdspec, &input_data);
-            if is_last {
-                stream.write_all(&output_data)?;
-            } else {
-                writeln!(stream, "[warn] built-in in the middle of pipeline not piped").ok();
+            statuses[i] = StageStatus::Exited(code);
+
+            // A redirection that fails fails this stage only: stages already
+            // spawned must still be waited on below.
+            match redirect_file(cmdspec, &cmdspec.redirect_err, &cmdspec.redirect_err_append)
+                .and_then(|file| file.map(|mut file| file.write_all(&err)).transpose())
+            {
+                Ok(Some(())) => {}
+                Ok(None) => send_output(&writer, &err, Some(stderr_mode)),
+                Err(e) => {
+                    report(format!("Error: {}: {}\n", cmdspec.argv[0], e));
+                    statuses[i] = StageStatus::Exited(1);
+                    continue;
+                }
+            }
+            match redirect_file(cmdspec, &cmdspec.redirect_out, &cmdspec.redirect_out_append)
+                .and_then(|file| file.map(|mut file| file.write_all(&out)).transpose())
+            {
+                Ok(Some(())) => {}
+                Ok(None) if is_last => send_output(&writer, &out, None),
+                Ok(None) => prev_output = StageInput::Bytes(out),
+                Err(e) => {
+                    report(format!("Error: {}: {}\n", cmdspec.argv[0], e));
+                    statuses[i] = StageStatus::Exited(1);
+                }
             }
         } else {
             // external command
-            let bin_path = match resolve_in_path(&cmdspec.argv[0]) {
+            let bin_path = match shell.resolve(&cmdspec.argv[0]) {
                 Ok(p) => p,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    report(format!("Error: {}: {}\n", cmdspec.argv[0], e));
+                    statuses[i] = StageStatus::Exited(126);
+                    continue;
+                }
                 Err(e) => {
-                    writeln!(stream, "Command not found: {} ({})", cmdspec.argv[0], e)?;
+                    report(format!("Command not found: {} ({})\n", cmdspec.argv[0], e));
+                    statuses[i] = StageStatus::Exited(127);
                     continue;
                 }
             };
             let mut cmd = Command::new(bin_path);
             cmd.args(&cmdspec.argv[1..]);
+            cmdspec.configure(&mut cmd);
 
-            // input redirect or pipeline
-            if let Some(ref infile) = cmdspec.redirect_in {
-                cmd.stdin(Stdio::from(std::fs::File::open(infile)?));
-            } else if prev_stdout.is_some() {
-                cmd.stdin(Stdio::piped());
-            } else {
-                cmd.stdin(Stdio::inherit());
-            }
-
-            // output redirect
-            if let Some(ref outfile) = cmdspec.redirect_out {
-                cmd.stdout(Stdio::from(std::fs::OpenOptions::new()
-                    .write(true).create(true).truncate(true)
-                    .open(outfile)?));
-            } else if let Some(ref outfile) = cmdspec.redirect_out_append {
-                cmd.stdout(Stdio::from(std::fs::OpenOptions::new()
-                    .write(true).create(true).append(true)
-                    .open(outfile)?));
-            } else {
-                cmd.stdout(Stdio::piped());
-            }
-
-            // error redirect
-            if let Some(ref errfile) = cmdspec.redirect_err {
-                cmd.stderr(Stdio::from(std::fs::OpenOptions::new()
-                    .write(true).create(true).truncate(true)
-                    .open(errfile)?));
-            } else if let Some(ref errfile) = cmdspec.redirect_err_append {
-                cmd.stderr(Stdio::from(std::fs::OpenOptions::new()
-                    .write(true).create(true).append(true)
-                    .open(errfile)?));
-            } else {
-                cmd.stderr(Stdio::piped());
-            }
-
-            let mut child = cmd.spawn()?;
-
-            // If we had a prev_stdout, pipe it in
-            if let Some(mut reader_pipe) = prev_stdout.take() {
-                if let Some(child_in) = child.stdin.take() {
-                    thread::spawn(move || {
-                        let _ = io::copy(&mut reader_pipe, &mut io::BufWriter::new(child_in));
+            let spawned = configure_stdio(&mut cmd, cmdspec, &input).and_then(|_| cmd.spawn());
+            let mut child = match spawned {
+                Ok(child) => child,
+                Err(e) => {
+                    report(format!("Error: {}: {}\n", cmdspec.argv[0], e));
+                    statuses[i] = StageStatus::Exited(match e.kind() {
+                        io::ErrorKind::PermissionDenied => 126,
+                        io::ErrorKind::NotFound => 127,
+                        _ => 1,
                     });
+                    continue;
+                }
+            };
+
+            // Feed the previous stage's output in
+            if let Some(child_in) = child.stdin.take() {
+                match input {
+                    StageInput::Pipe(mut reader_pipe) => {
+                        copiers.push(thread::spawn(move || {
+                            let _ = io::copy(&mut reader_pipe, &mut io::BufWriter::new(child_in));
+                        }));
+                    }
+                    StageInput::Bytes(data) => {
+                        copiers.push(thread::spawn(move || {
+                            let mut child_in = child_in;
+                            let _ = child_in.write_all(&data);
+                        }));
+                    }
+                    StageInput::Inherit | StageInput::Closed => {}
                 }
             }
 
-            // If last, forward stdout/err to stream
+            // Every stage's stderr goes to the client
+            if let Some(err) = child.stderr.take() {
+                copiers.push(forward_output(err, Arc::clone(&writer), Some(stderr_mode)));
+            }
+
+            // If last, forward stdout to stream
             // If not last, hold onto stdout for next
-            if is_last {
-                let mut out = child.stdout.take().unwrap();
-                let mut err = child.stderr.take().unwrap();
-                let mut s_clone = stream.try_clone()?;
-                let mut s_clone2 = stream.try_clone()?;
-                thread::spawn(move || {
-                    let _ = io::copy(&mut out, &mut s_clone);
-                });
-                thread::spawn(move || {
-                    let _ = io::copy(&mut err, &mut s_clone2);
-                });
-            } else {
-                if let Some(out) = child.stdout.take() {
-                    prev_stdout = Some(out);
+            if let Some(out) = child.stdout.take() {
+                if is_last {
+                    copiers.push(forward_output(out, Arc::clone(&writer), None));
+                } else {
+                    prev_output = StageInput::Pipe(out);
                 }
             }
-            children.push(child);
+            children.push((i, child));
+        }
+    }
+
+    for (i, mut c) in children {
+        statuses[i] = match c.wait() {
+            Ok(s) => StageStatus::from(s),
+            Err(_) => StageStatus::Exited(1),
+        };
+    }
+    for copier in copiers {
+        let _ = copier.join();
+    }
+
+    let sent = writer.lock().unwrap().sent;
+    Ok((statuses, sent))
+}
+
+/// Runs a built-in stage with its stdin taken from `<` or the pipeline.
+fn run_builtin_stage(
+    shell: &mut Shell,
+    cmdspec: &CommandSpec,
+    input: StageInput,
+    out: &mut Vec<u8>,
+    err: &mut Vec<u8>,
+) -> io::Result<i32> {
+    let mut reader: Box<dyn Read> = match (&cmdspec.redirect_in, input) {
+        (Some(infile), _) => Box::new(fs::File::open(cmdspec.path(infile))?),
+        (None, StageInput::Pipe(pipe)) => Box::new(pipe),
+        (None, StageInput::Bytes(data)) => Box::new(io::Cursor::new(data)),
+        (None, StageInput::Inherit) | (None, StageInput::Closed) => Box::new(io::empty()),
+    };
+    Ok(run_builtin(shell, &cmdspec.argv, &mut reader, out, err))
+}
+
+/// The connection to the client as a pipeline's stages share it, counting
+/// the bytes sent.
+struct ClientWriter {
+    stream: TcpStream,
+    sent: u64,
+}
+
+impl Write for ClientWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        self.sent += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Copies a child's stdout (`stderr_mode` is `None`) or stderr to the
+/// shared client writer, holding the lock for each whole chunk. Tagged
+/// stderr is sent as complete `[stderr] ` lines.
+fn forward_output<R: Read + Send + 'static>(
+    mut src: R,
+    writer: Arc<Mutex<ClientWriter>>,
+    stderr_mode: Option<StderrMode>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        let mut pending: Vec<u8> = Vec::new();
+        loop {
+            let n = match src.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let mut out = writer.lock().unwrap();
+            if write_chunk(&mut *out, &buf[..n], stderr_mode, &mut pending).is_err() {
+                return;
+            }
+        }
+        let _ = finish_chunks(&mut *writer.lock().unwrap(), &pending);
+    })
+}
+
+/// Sends a whole buffer, e.g. a built-in's output, like `forward_output`.
+fn send_output(writer: &Mutex<ClientWriter>, data: &[u8], stderr_mode: Option<StderrMode>) {
+    let _ = write_output(&mut *writer.lock().unwrap(), data, stderr_mode);
+}
+
+/// Writes a whole buffer as stdout (`stderr_mode` is `None`) or stderr.
+fn write_output(out: &mut dyn Write, data: &[u8], stderr_mode: Option<StderrMode>) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let mut pending = Vec::new();
+    write_chunk(out, data, stderr_mode, &mut pending)?;
+    finish_chunks(out, &pending)
+}
+
+/// Writes one chunk of output. Tagged stderr keeps an unfinished line in
+/// `pending` until its newline arrives.
+fn write_chunk(
+    out: &mut dyn Write,
+    chunk: &[u8],
+    stderr_mode: Option<StderrMode>,
+    pending: &mut Vec<u8>,
+) -> io::Result<()> {
+    match stderr_mode {
+        None | Some(StderrMode::Plain) => out.write_all(chunk),
+        Some(StderrMode::Color) => out
+            .write_all(b"\x1b[31m")
+            .and_then(|_| out.write_all(chunk))
+            .and_then(|_| out.write_all(b"\x1b[0m")),
+        Some(StderrMode::Tagged) => {
+            pending.extend_from_slice(chunk);
+            while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=pos).collect();
+                out.write_all(b"[stderr] ")?;
+                out.write_all(&line)?;
+            }
+            Ok(())
         }
     }
+}
+
+/// Sends the unterminated last line of tagged stderr, if any.
+fn finish_chunks(out: &mut dyn Write, pending: &[u8]) -> io::Result<()> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    out.write_all(b"[stderr] ")?;
+    out.write_all(pending)?;
+    out.write_all(b"\n")
+}
+
+/// Opens the target of `>`/`2>` (truncate) or `>>`/`2>>` (append) of
+/// `cmdspec`, if any.
+fn redirect_file(cmdspec: &CommandSpec, truncate: &Option<String>, append: &Option<String>) -> io::Result<Option<fs::File>> {
+    if let Some(ref path) = truncate {
+        fs::OpenOptions::new().write(true).create(true).truncate(true).open(cmdspec.path(path)).map(Some)
+    } else if let Some(ref path) = append {
+        fs::OpenOptions::new().create(true).append(true).open(cmdspec.path(path)).map(Some)
+    } else {
+        Ok(None)
+    }
+}
 
-    for mut c in children {
-        let _ = c.wait();
+/// Sets up stdin/stdout/stderr of a pipeline stage from its redirections
+/// and whatever the previous stage left for it.
+fn configure_stdio(cmd: &mut Command, cmdspec: &CommandSpec, input: &StageInput) -> io::Result<()> {
+    // input redirect or pipeline
+    if let Some(ref infile) = cmdspec.redirect_in {
+        cmd.stdin(Stdio::from(fs::File::open(cmdspec.path(infile))?));
+    } else {
+        match input {
+            StageInput::Pipe(_) | StageInput::Bytes(_) => cmd.stdin(Stdio::piped()),
+            StageInput::Closed => cmd.stdin(Stdio::null()),
+            StageInput::Inherit => cmd.stdin(Stdio::inherit()),
+        };
     }
 
+    // output redirect
+    match redirect_file(cmdspec, &cmdspec.redirect_out, &cmdspec.redirect_out_append)? {
+        Some(file) => cmd.stdout(Stdio::from(file)),
+        None => cmd.stdout(Stdio::piped()),
+    };
+
+    // error redirect
+    match redirect_file(cmdspec, &cmdspec.redirect_err, &cmdspec.redirect_err_append)? {
+        Some(file) => cmd.stderr(Stdio::from(file)),
+        None => cmd.stderr(Stdio::piped()),
+    };
     Ok(())
 }
 
-fn is_builtin(cmd: &str) -> bool {
-    matches!(cmd, "cd" | "pwd" | "set" | "unset" | "env" | "help")
+/// True if `cmd` runs in the shell: a shell built-in, or a built-in
+/// utility with no program of that name on PATH. Only looks, so the hash
+/// table is left as it is.
+fn is_builtin(cmd: &str, shell: &Shell) -> bool {
+    is_shell_builtin(cmd) || (is_utility(cmd) && shell.find_in_path(cmd).is_empty())
+}
+
+fn is_shell_builtin(cmd: &str) -> bool {
+    matches!(cmd, "cd" | "pwd" | "set" | "unset" | "env" | "help" | "true" | "false" | "builtin")
+}
+
+/// Built-in utilities (the `coreutils` feature) stand in for commands
+/// missing from PATH; `builtin <name>` uses them even when present.
+#[cfg(feature = "coreutils")]
+fn is_utility(cmd: &str) -> bool {
+    coreutils::is_utility(cmd)
+}
+
+#[cfg(not(feature = "coreutils"))]
+fn is_utility(_cmd: &str) -> bool {
+    false
 }
 
-fn run_builtin(cmdspec: &CommandSpec, _input_data: &[u8]) -> Vec<u8> {
-    let argv = &cmdspec.argv;
+/// Runs a built-in against the given stdin, stdout and stderr and
+/// returns its exit status. `cd`, `set` and `unset` change the shell's
+/// own cwd and environment, never the process's.
+#[cfg_attr(not(feature = "coreutils"), allow(unused_variables))]
+fn run_builtin(shell: &mut Shell, argv: &[String], input: &mut dyn Read, out: &mut dyn Write, err: &mut dyn Write) -> i32 {
+    let mut argv = argv;
+    // `builtin name args...` runs the built-in `name`
+    while argv[0] == "builtin" {
+        match argv.get(1) {
+            None => return 0,
+            Some(name) if is_shell_builtin(name) || is_utility(name) => argv = &argv[1..],
+            Some(name) => {
+                writeln!(err, "builtin: {}: not a shell builtin", name).ok();
+                return 1;
+            }
+        }
+    }
     let cmd = &argv[0];
     let args = &argv[1..];
-    let mut out = Vec::new();
+    let mut status = 0;
 
     match cmd.as_str() {
         "cd" => {
             if args.is_empty() {
-                writeln!(out, "Usage: cd <dir>").ok();
+                writeln!(err, "Usage: cd <dir>").ok();
+                status = 1;
             } else {
-                let dir = &args[0];
-                if let Err(e) = env::set_current_dir(dir) {
-                    writeln!(out, "cd error: {}", e).ok();
+                // resolved the way chdir(2) and getcwd(3) would
+                let dir = fs::canonicalize(shell.path(&args[0])).and_then(|dir| {
+                    if dir.is_dir() {
+                        Ok(dir)
+                    } else {
+                        Err(io::Error::other("Not a directory"))
+                    }
+                });
+                match dir {
+                    Ok(dir) => {
+                        shell.vars.insert("PWD".into(), dir.clone().into());
+                        shell.cwd = dir;
+                    }
+                    Err(e) => {
+                        writeln!(err, "cd error: {}", e).ok();
+                        status = 1;
+                    }
                 }
             }
         }
         "pwd" => {
-            match env::current_dir() {
-                Ok(d) => {
-                    writeln!(out, "{}", d.display()).ok();
-                }
-                Err(e) => {
-                    writeln!(out, "pwd error: {}", e).ok();
-                }
-            }
+            writeln!(out, "{}", shell.cwd.display()).ok();
         }
         "set" => {
             for assignment in args {
                 if let Some(eqpos) = assignment.find('=') {
                     let var = &assignment[..eqpos];
                     let val = &assignment[eqpos + 1..];
-                    env::set_var(var, val);
+                    shell.set_var(var, val);
                 } else {
-                    writeln!(out, "Invalid format: {}", assignment).ok();
+                    writeln!(err, "Invalid format: {}", assignment).ok();
+                    status = 1;
                 }
             }
         }
         "unset" => {
             for var in args {
-                env::remove_var(var);
+                shell.vars.remove(OsStr::new(var));
             }
         }
         "env" => {
-            for (k, v) in env::vars() {
-                writeln!(out, "{}={}", k, v).ok();
+            for (k, v) in &shell.vars {
+                writeln!(out, "{}={}", k.to_string_lossy(), v.to_string_lossy()).ok();
             }
         }
+        "true" => {}
+        "false" => {
+            status = 1;
+        }
+        #[cfg(feature = "coreutils")]
+        name if coreutils::is_utility(name) => {
+            status = coreutils::run(
+                argv,
+                &mut Streams {
+                    stdin: input,
+                    stdout: out,
+                    stderr: err,
+                    cwd: &shell.cwd,
+                    search_path: shell.vars.get(OsStr::new("PATH")).map(OsString::as_os_str),
+                },
+            );
+        }
         "help" => {
-            writeln!(out, "Built-ins: cd, pwd, set, unset, env, help").ok();
-            writeln!(out, "Use '|' for pipelines, e.g. `ls | grep foo`.").ok();
-            writeln!(out, "Use redirections < > >> 2> 2>> etc.").ok();
+            writeln!(out, "Built-ins: cd, pwd, set, unset, env, help, true, false, builtin").ok();
+            #[cfg(feature = "coreutils")]
+            writeln!(out, "Utilities, used when missing from PATH (or via `builtin`): {}.", coreutils::UTILITIES.join(", ")).ok();
+            writeln!(out, "Control flow: break, continue, return, shift, exit").ok();
+            writeln!(out, "Scripts: source <file> [args], run [-e] [-x] <file> [args].").ok();
+            writeln!(out, "Options: set -e (stop on failure), set -x (trace), set -o pipefail, set -o.").ok();
+            writeln!(out, "Status: $? is the last exit status, $PIPESTATUS every stage's.").ok();
+            writeln!(out, "Stderr: set -o stderrcolor (red) or set -o stderrtag ([stderr] lines).").ok();
+            writeln!(out, "Aliases: alias [name[=value] ...], unalias [-a] name...").ok();
+            writeln!(out, "Lookup: type [-t] name, command [-v|-V] name, hash [-r | -d name | name].").ok();
+            writeln!(out, "PTY: pty <cmd>, nopty <cmd>, ptycmds [-r | -d name | name...], set -o ptyauto.").ok();
+            writeln!(out, "Sessions: detach leaves this session running; reconnect and 'attach <token>' to resume.").ok();
+            writeln!(out, "Use '|' for pipelines, e.g. `ls | grep foo`, and '&&' / '||' to chain commands.").ok();
+            writeln!(out, "Use redirections < > >> 2> 2>> etc.; built-ins can be piped and redirected too.").ok();
             writeln!(out, "Supports quotes, environment expansions, etc.").ok();
+            writeln!(out, "Scripts: if/elif/else/fi, while/until, for x in ..., case ... esac,").ok();
+            writeln!(out, "and functions: name() {{ ...; }} with $1..$9, $#, $@.").ok();
+            writeln!(out, "Not supported: NAME=value assignments (use `set NAME=value`), and pipes or").ok();
+            writeln!(out, "redirections on if/while/for/case/{{ }} (pipe or redirect the commands inside).").ok();
             writeln!(out, "Type 'exit' to quit.").ok();
         }
         _ => {
-            writeln!(out, "[builtin] not implemented?").ok();
+            writeln!(err, "[builtin] not implemented?").ok();
+            status = 1;
         }
     }
-    out
+    status
 }
 
-fn resolve_in_path(cmd: &str) -> io::Result<String> {
-    // if contains / or \, check directly
-    if cmd.contains('/') || cmd.contains('\\') {
-        if Path::new(cmd).exists() {
-            return Ok(cmd.to_string());
-        } else {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "No such file"));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Runs `src` in `shell` as if typed by a client and returns all the
+    /// client was sent.
+    fn run(shell: &mut Shell, src: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let program = script::parse(src).unwrap();
+        exec_nodes(shell, &program, &mut stream).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        out
     }
 
-    if let Ok(path_var) = env::var("PATH") {
-        let sep = if cfg!(windows) { ';' } else { ':' };
-        for dir in path_var.split(sep) {
-            let mut candidate = PathBuf::from(dir);
-            candidate.push(cmd);
-            if candidate.exists() {
-                return Ok(candidate.to_string_lossy().to_string());
-            }
+    /// A fresh, empty directory under the system temp dir.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("net_shell_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// argv of the first command of `line`, fully expanded.
+    fn words(shell: &Shell, line: &str) -> Vec<String> {
+        handle_line(line, shell).unwrap().remove(0).argv
+    }
+
+    #[test]
+    fn brace_ranges_expand() {
+        let shell = Shell::new();
+        assert_eq!(words(&shell, "echo {1..3}"), ["echo", "1", "2", "3"]);
+        assert_eq!(words(&shell, "echo {3..1}"), ["echo", "3", "2", "1"]);
+        assert_eq!(words(&shell, "echo {0..10..5}"), ["echo", "0", "5", "10"]);
+        assert_eq!(words(&shell, "echo {01..03}"), ["echo", "01", "02", "03"]);
+        assert_eq!(words(&shell, "echo {a..c}"), ["echo", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn extreme_brace_ranges_do_not_expand() {
+        for inner in [
+            "-9223372036854775808..9223372036854775807",
+            "-9223372036854775807..9223372036854775807",
+            "9223372036854775807..-9223372036854775808",
+            "0..100001",
+        ] {
+            assert_eq!(brace_range(inner), None, "{}", inner);
         }
+        assert_eq!(brace_range("9223372036854775806..9223372036854775807").map(|r| r.len()), Some(2));
+        assert_eq!(brace_range("1..5..-9223372036854775808"), Some(vec!["1".to_string()]));
+    }
+
+    #[test]
+    fn huge_brace_expansions_fail_with_status_1() {
+        let mut shell = Shell::new();
+        let out = run(&mut shell, "echo {1..100000}{1..100000}\necho $?\n");
+        assert_eq!(out, "brace expansion: too many words\n1\n");
+        let out = run(&mut shell, "echo {1..100000} {1..100000} {1..100000} > /dev/null\necho $?\n");
+        assert_eq!(out, "brace expansion: too many words\n1\n");
+        let out = run(&mut shell, "for i in {1..100000}{1..100000}; do echo $i; done\necho $?\n");
+        assert_eq!(out, "brace expansion: too many words\n1\n");
+        assert_eq!(run(&mut shell, "echo {1..100000} | wc -w\n").trim(), "100000");
+    }
+
+    #[test]
+    fn globs_match_in_the_shell_cwd() {
+        let dir = scratch_dir("glob");
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::write(dir.join("b.txt"), "").unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub").join("c.txt"), "").unwrap();
+
+        let mut shell = Shell::new();
+        shell.cwd = dir.clone();
+        assert_eq!(words(&shell, "ls *.txt"), ["ls", "a.txt", "b.txt"]);
+        assert_eq!(words(&shell, "ls sub/*.txt"), ["ls", "sub/c.txt"]);
+        // no match leaves the pattern as typed
+        assert_eq!(words(&shell, "ls *.none"), ["ls", "*.none"]);
+
+        shell.cwd = dir.join("sub");
+        assert_eq!(words(&shell, "ls *.txt"), ["ls", "c.txt"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_redirect_write_fails_only_its_stage() {
+        let mut shell = Shell::new();
+        let out = run(&mut shell, "sleep 0.2 | pwd > /dev/full\necho $PIPESTATUS\n");
+        assert!(out.contains("Error: pwd: "), "{}", out);
+        // the earlier stage was still waited for
+        assert!(out.ends_with("0 1\n"), "{}", out);
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn functions_see_their_positional_parameters() {
+        let mut shell = Shell::new();
+        let out = run(
+            &mut shell,
+            "f() { echo $# \"$1\" $2; echo $@; for a in \"$@\"; do echo \"[$a]\"; done; }\nf a 'b c' d\necho $#\n",
+        );
+        assert_eq!(out, "3 a b c\na b c d\n[a]\n[b c]\n[d]\n0\n");
+
+        let out = run(&mut shell, "s() { shift; echo $1 $#; }\ns a b c\nouter() { s x y; echo $1; }\nouter z\n");
+        assert_eq!(out, "b 2\ny 1\nz\n");
+        assert!(shell.positional.is_empty());
+    }
+
+    #[test]
+    fn return_sets_the_function_status() {
+        let mut shell = Shell::new();
+        let out = run(
+            &mut shell,
+            "h() { return 3; echo no; }\nh; echo $?\n\
+             g() { false; return; }\ng; echo $?\n\
+             k() { for i in 1 2 3; do if [ $i = 2 ]; then return $i; fi; done; echo no; }\nk; echo $?\n\
+             t() { h; echo in t $?; }\nt; echo $?\n",
+        );
+        assert_eq!(out, "3\n1\n2\nin t 3\n0\n");
+    }
+
+    #[test]
+    fn runaway_recursion_is_stopped() {
+        let mut shell = Shell::new();
+        let out = run(&mut shell, "r() { r; }\nr\necho $?\n");
+        assert!(out.contains("maximum function nesting depth"), "{}", out);
+        assert_eq!(shell.depth, 0);
+    }
+
+    #[test]
+    fn backslash_escapes_outside_quotes() {
+        let shell = Shell::new();
+        assert_eq!(words(&shell, r"ls my\ file"), ["ls", "my file"]);
+        assert_eq!(words(&shell, r"echo \| \> \$HOME \\ \'"), ["echo", "|", ">", "$HOME", "\\", "'"]);
+        assert_eq!(handle_line(r"echo a \| b", &shell).unwrap().len(), 1);
+        assert_eq!(words(&shell, r"echo trailing\"), ["echo", "trailing\\"]);
+    }
+
+    #[test]
+    fn backslash_escapes_inside_quotes() {
+        let shell = Shell::new();
+        // in double quotes only $ ` " \ and newline are escaped
+        assert_eq!(words(&shell, r#"echo "a\"b" "\$x" "\\" "\a""#), ["echo", "a\"b", "$x", "\\", "\\a"]);
+        assert_eq!(words(&shell, r"echo 'x\y' 'a\'"), ["echo", "x\\y", "a\\"]);
+        assert_eq!(words(&shell, "echo \"\" ''"), ["echo", "", ""]);
+    }
+
+    #[test]
+    fn escaped_and_quoted_globs_stay_literal() {
+        let dir = scratch_dir("escaped_glob");
+        fs::write(dir.join("a.txt"), "").unwrap();
+        let mut shell = Shell::new();
+        shell.cwd = dir.clone();
+        assert_eq!(words(&shell, r#"ls \*.txt "*.txt" '*.txt' *.txt"#), ["ls", "*.txt", "*.txt", "*.txt", "a.txt"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lines_continue_after_a_backslash() {
+        let shell = Shell::new();
+        assert_eq!(words(&shell, "echo a \\\n  b\\\nc"), ["echo", "a", "bc"]);
+        assert_eq!(words(&shell, "echo \"a\\\nb\""), ["echo", "ab"]);
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "echo one \\\n two\n"), "one two\n");
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        let shell = Shell::new();
+        assert_eq!(handle_line("echo 'abc", &shell).err().as_deref(), Some("unterminated single quote"));
+        assert_eq!(handle_line("echo \"abc", &shell).err().as_deref(), Some("unterminated double quote"));
+    }
+
+    #[test]
+    fn status_of_simple_commands() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "false\necho $?\necho $?\n"), "1\n0\n");
+        assert_eq!(run(&mut shell, "true\necho $? $PIPESTATUS\n"), "0 0\n");
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipestatus_keeps_every_stage() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "false | true\necho $? $PIPESTATUS\n"), "0 1 0\n");
+        assert_eq!(run(&mut shell, "true | sh -c 'exit 3' | true\necho $PIPESTATUS\n"), "0 3 0\n");
+        let out = run(&mut shell, "nosuch-command-xyz | true\necho $PIPESTATUS\n");
+        assert!(out.contains("Command not found"), "{}", out);
+        assert!(out.ends_with("127 0\n"), "{}", out);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipefail_reports_the_rightmost_failure() {
+        let mut shell = Shell::new();
+        let out = run(&mut shell, "set -o pipefail\nsh -c 'exit 2' | sh -c 'exit 3' | true\necho $? $PIPESTATUS\n");
+        assert_eq!(out, "3 2 3 0\n");
+        assert_eq!(run(&mut shell, "set +o pipefail\nfalse | true\necho $?\n"), "0\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signals_show_as_128_plus_the_number() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "sh -c 'kill -9 $$'\necho $?\n"), "137\n");
+    }
+
+    #[test]
+    fn errexit_stops_at_the_first_failure_outside_conditions() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "set -e\nif false; then echo no; fi\necho yes\nfalse\necho unreachable\n"), "yes\n");
+    }
+
+    #[test]
+    fn and_or_lists_run_by_the_status_so_far() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "true && echo yes || echo no\n"), "yes\n");
+        assert_eq!(run(&mut shell, "false && echo yes || echo no\n"), "no\n");
+        assert_eq!(run(&mut shell, "false || false && echo yes\necho $?\n"), "1\n");
+        assert_eq!(run(&mut shell, "true ||\n  echo no\necho $?\n"), "0\n");
+        assert_eq!(run(&mut shell, "if false || true; then echo a; fi && echo b\n"), "a\nb\n");
+    }
+
+    #[test]
+    fn errexit_applies_only_to_the_last_command_of_an_and_or_list() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "set -e\nfalse && echo no\nfalse || true\necho yes\n"), "yes\n");
+        assert_eq!(run(&mut shell, "set -e\ntrue && false\necho unreachable\n"), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn builtins_feed_pipelines() {
+        let mut shell = Shell::new();
+        shell.set_var("NET_SHELL_TEST", "piped");
+        assert_eq!(run(&mut shell, "env | grep ^NET_SHELL_TEST=\n"), "NET_SHELL_TEST=piped\n");
+        // in the middle of a pipeline too
+        assert_eq!(run(&mut shell, "true | env | grep ^NET_SHELL_TEST= | cat\n"), "NET_SHELL_TEST=piped\n");
+        let help = run(&mut shell, "help\n");
+        assert_eq!(run(&mut shell, "help | head -n 1\n"), format!("{}\n", help.lines().next().unwrap()));
+    }
+
+    #[test]
+    fn builtins_honour_redirections() {
+        let dir = scratch_dir("builtin_redirects");
+        let mut shell = Shell::new();
+        shell.cwd = dir.clone();
+        assert_eq!(run(&mut shell, "pwd > out.txt\npwd >> out.txt\n"), "");
+        let line = format!("{}\n", dir.display());
+        assert_eq!(fs::read_to_string(dir.join("out.txt")).unwrap(), line.repeat(2));
+        assert_eq!(run(&mut shell, "pwd > out.txt\n"), "");
+        assert_eq!(fs::read_to_string(dir.join("out.txt")).unwrap(), line);
+
+        assert_eq!(run(&mut shell, "cd no-such-dir 2> err.txt\necho $?\n"), "1\n");
+        assert!(fs::read_to_string(dir.join("err.txt")).unwrap().starts_with("cd error: "));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "coreutils")]
+    #[test]
+    fn utilities_read_redirected_and_piped_input() {
+        let dir = scratch_dir("builtin_input");
+        fs::write(dir.join("in.txt"), "b\na\nc\n").unwrap();
+        let mut shell = Shell::new();
+        shell.cwd = dir.clone();
+        assert_eq!(run(&mut shell, "builtin cat < in.txt\n"), "b\na\nc\n");
+        assert_eq!(run(&mut shell, "builtin cat < in.txt | builtin wc -l\n"), "3\n");
+        assert_eq!(run(&mut shell, "builtin head -n 2 < in.txt > head.txt\n"), "");
+        assert_eq!(fs::read_to_string(dir.join("head.txt")).unwrap(), "b\na\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(all(feature = "coreutils", unix))]
+    #[test]
+    fn each_run_of_a_utility_on_path_is_one_hash_hit() {
+        let mut shell = Shell::new();
+        let out = run(&mut shell, "head -n 0 /dev/null\nhead -n 0 /dev/null\nhash\n");
+        assert!(out.lines().any(|l| l.starts_with("   2\t") && l.ends_with("/head")), "{:?}", out);
+    }
+
+    #[cfg(all(feature = "partial_pty", unix))]
+    #[test]
+    fn interactive_commands_get_a_pty_of_the_client_size() {
+        let mut shell = Shell::new();
+        shell.terminal = Some(Terminal { rows: 30, cols: 100 });
+        let script = "pty sh -c 'stty size; [ -t 1 ] && echo on a tty; exit 3'\necho status $?\n";
+        let out = run(&mut shell, script);
+        assert!(out.contains("30 100\r\n"), "{:?}", out);
+        assert!(out.contains("on a tty\r\n"), "{:?}", out);
+        assert!(out.ends_with("status 3\n"), "{:?}", out);
+
+        // listed commands get one too; everything else runs on pipes
+        assert_eq!(run(&mut shell, "ptycmds -d sh\nsh -c '[ -t 1 ] || echo no tty'\n"), "no tty\n");
+        assert_eq!(run(&mut shell, "ptycmds sh\nsh -c '[ -t 1 ] && echo on a tty'\n"), "on a tty\r\n");
+        assert_eq!(run(&mut shell, "nopty sh -c '[ -t 1 ] || echo no tty'\n"), "no tty\n");
+    }
+
+    #[cfg(all(feature = "full_pty", unix))]
+    #[test]
+    fn login_shell_exit_status_and_output() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut vars: BTreeMap<OsString, OsString> = env::vars_os().collect();
+        vars.insert("TERM".into(), "xterm-test".into());
+        let cmdspec = CommandSpec {
+            argv: vec![login_shell(), "-l".to_string()],
+            redirect_in: None,
+            redirect_out: None,
+            redirect_out_append: None,
+            redirect_err: None,
+            redirect_err_append: None,
+            cwd: None,
+            env: Some(vars),
+        };
+        let input = "stty size; echo TERM=$TERM; exit 5\n";
+        client.write_all(input.as_bytes()).unwrap();
+        let exit = unix_pty::run_in_pty_sized(&cmdspec, &mut stream, 30, 100).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert_eq!(exit.status.code(), Some(5));
+        assert!(out.contains("30 100\r\n"), "{:?}", out);
+        assert!(out.contains("TERM=xterm-test\r\n"), "{:?}", out);
+        assert_eq!(exit.bytes_in, input.len() as u64);
+        assert_eq!(exit.bytes_out, out.len() as u64);
+    }
+
+    #[cfg(all(feature = "full_pty", unix))]
+    #[test]
+    fn sessions_run_the_login_shell_at_the_requested_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client.write_all(b"TERMINAL xterm-test 40 120\nstty size; echo TERM=$TERM; exit\n").unwrap();
+        let session = thread::spawn(move || run_session(stream, None));
+        // the session closes the connection once the shell exits
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        session.join().unwrap().unwrap();
+        assert!(out.contains("40 120\r\n"), "{:?}", out);
+        assert!(out.contains("TERM=xterm-test\r\n"), "{:?}", out);
+        assert!(!out.contains("TERMINAL"), "{:?}", out);
+    }
+
+    #[test]
+    fn run_starts_scripts_in_the_shell_cwd_and_environment() {
+        let dir = scratch_dir("run_cwd");
+        fs::write(dir.join("s.sh"), "pwd\necho $FOO\ncd /\nset FOO=2\n").unwrap();
+        let mut shell = Shell::new();
+        shell.cwd = dir.clone();
+        let out = run(&mut shell, "set FOO=1\nrun s.sh\npwd\necho $FOO\n");
+        let here = dir.display();
+        assert_eq!(out, format!("{}\n1\n{}\n1\n", here, here));
+        assert_eq!(run(&mut shell, "source s.sh\n"), format!("{}\n1\n", here));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn set_rejects_bad_options_without_panicking() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "set -o pipefail \u{e9}\necho $?\n"), "set: invalid option: \u{e9}\n2\n");
+        assert!(shell.pipefail);
+        assert_eq!(run(&mut shell, "set +\u{e9}x\necho $?\n"), "set: unknown option: +\u{e9}\n2\n");
+        assert!(!shell.xtrace);
+        assert_eq!(run(&mut shell, "set -q\necho $?\n"), "set: unknown option: -q\n2\n");
+        assert_eq!(run(&mut shell, "set -e\nset +e\necho $?\n"), "0\n");
+        assert!(!shell.errexit);
+    }
+
+    #[test]
+    fn aliases_expand_in_command_position() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "alias hi='echo hello' e='echo ' w=world\necho $?\n"), "0\n");
+        assert_eq!(words(&shell, "hi there"), ["echo", "hello", "there"]);
+        assert_eq!(words(&shell, "echo hi"), ["echo", "hi"]);
+        // a value ending in a blank makes the next word an alias too
+        assert_eq!(words(&shell, "e w"), ["echo", "world"]);
+        assert_eq!(handle_line("echo a | hi", &shell).unwrap()[1].argv, ["echo", "hello"]);
+        assert_eq!(run(&mut shell, "hi there\n"), "hello there\n");
+    }
+
+    #[test]
+    fn aliases_do_not_expand_inside_themselves() {
+        let mut shell = Shell::new();
+        run(&mut shell, "alias ls='ls -F' a=b b=a\n");
+        assert_eq!(words(&shell, "ls x"), ["ls", "-F", "x"]);
+        assert_eq!(words(&shell, "a"), ["a"]);
+        assert_eq!(words(&shell, "b"), ["b"]);
+    }
+
+    #[test]
+    fn alias_lists_shows_and_rejects_names() {
+        let mut shell = Shell::new();
+        run(&mut shell, "alias zz='echo z' ll='ls -l'\n");
+        assert_eq!(run(&mut shell, "alias\n"), "alias ll='ls -l'\nalias zz='echo z'\n");
+        assert_eq!(run(&mut shell, "alias ll\necho $?\n"), "alias ll='ls -l'\n0\n");
+        assert_eq!(run(&mut shell, "alias nope\necho $?\n"), "alias: nope: not found\n1\n");
+        assert_eq!(run(&mut shell, "alias 'a b=c'\necho $?\n"), "alias: 'a b': invalid alias name\n1\n");
+    }
+
+    #[test]
+    fn unalias_removes_one_or_all() {
+        let mut shell = Shell::new();
+        run(&mut shell, "alias a=x b=y c=z\n");
+        assert_eq!(run(&mut shell, "unalias a\necho $?\nalias\n"), "0\nalias b='y'\nalias c='z'\n");
+        assert_eq!(run(&mut shell, "unalias a\necho $?\n"), "unalias: a: not found\n1\n");
+        assert_eq!(run(&mut shell, "unalias\necho $?\n"), "Usage: unalias [-a] name [name ...]\n2\n");
+        assert_eq!(run(&mut shell, "unalias -a\necho $?\nalias\n"), "0\n");
+        assert!(shell.aliases.is_empty());
+    }
+
+    #[test]
+    fn stderr_is_shown_as_its_mode_says_and_stdout_as_is() {
+        let written = |data: &[u8], mode: Option<StderrMode>| {
+            let mut out = Vec::new();
+            write_output(&mut out, data, mode).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(written(b"a\nb", None), "a\nb");
+        assert_eq!(written(b"a\nb", Some(StderrMode::Plain)), "a\nb");
+        assert_eq!(written(b"a\nb", Some(StderrMode::Color)), "\x1b[31ma\nb\x1b[0m");
+        assert_eq!(written(b"a\nb", Some(StderrMode::Tagged)), "[stderr] a\n[stderr] b\n");
+        assert_eq!(written(b"", Some(StderrMode::Tagged)), "");
+    }
+
+    #[test]
+    fn tagged_lines_may_span_chunks() {
+        let mut out = Vec::new();
+        let mut pending = Vec::new();
+        write_chunk(&mut out, b"par", Some(StderrMode::Tagged), &mut pending).unwrap();
+        assert!(out.is_empty());
+        write_chunk(&mut out, b"tial\nnext", Some(StderrMode::Tagged), &mut pending).unwrap();
+        finish_chunks(&mut out, &pending).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[stderr] partial\n[stderr] next\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stderr_modes_apply_to_commands_and_builtins() {
+        let mut shell = Shell::new();
+        let script = "nopty sh -c 'echo out; echo err >&2'\ncd no-such-dir\n";
+        let lines = |out: String| {
+            let mut lines: Vec<String> = out.lines().map(|l| l.split(": ").next().unwrap().to_string()).collect();
+            lines.sort_unstable();
+            lines
+        };
+        run(&mut shell, "set -o stderrtag\n");
+        assert_eq!(lines(run(&mut shell, script)), ["[stderr] cd error", "[stderr] err", "out"]);
+        run(&mut shell, "set -o stderrcolor\n");
+        let out = run(&mut shell, script);
+        assert!(out.contains("\x1b[31merr\n\x1b[0m"), "{:?}", out);
+        assert!(out.contains("\x1b[31mcd error: "), "{:?}", out);
+        assert!(out.starts_with("out\n") || out.contains("\x1b[0mout\n"), "{:?}", out);
+        run(&mut shell, "set +o stderrcolor\n");
+        assert_eq!(lines(run(&mut shell, script)), ["cd error", "err", "out"]);
+    }
+
+    /// A shell whose PATH starts with a scratch dir holding the script
+    /// `tool`, followed by `rest`.
+    #[cfg(unix)]
+    fn shell_with_tool(name: &str, rest: &str) -> (Shell, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir(name);
+        let tool = dir.join("tool");
+        fs::write(&tool, "#!/bin/sh\necho tool ran\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut shell = Shell::new();
+        shell.set_var("PATH", &format!("{}{}", dir.display(), rest));
+        (shell, tool)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn type_tells_what_each_name_is() {
+        let (mut shell, tool) = shell_with_tool("type", &format!(":{}", env::var("PATH").unwrap_or_default()));
+        run(&mut shell, "f() { echo; }\nalias ll='ls -l'\n");
+        let out = run(&mut shell, "type f ll cd alias if tool\necho $?\n");
+        let expected = format!(
+            "f is a function\nll is aliased to `ls -l'\ncd is a shell builtin\nalias is a shell builtin\n\
+             if is a shell keyword\ntool is {}\n0\n",
+            tool.display()
+        );
+        assert_eq!(out, expected);
+        assert_eq!(run(&mut shell, "type -t f ll cd if tool nope\necho $?\n"), "function\nalias\nbuiltin\nkeyword\nfile\n1\n");
+        assert_eq!(run(&mut shell, "type nope\necho $?\n"), "type: nope: not found\n1\n");
+
+        assert_eq!(run(&mut shell, "tool\ntype tool\n"), format!("tool ran\ntool is hashed ({})\n", tool.display()));
+        let _ = fs::remove_dir_all(tool.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_v_prints_what_would_run() {
+        let (mut shell, tool) = shell_with_tool("command_v", &format!(":{}", env::var("PATH").unwrap_or_default()));
+        run(&mut shell, "f() { echo; }\nalias ll='ls -l'\n");
+        let out = run(&mut shell, "command -v ll f cd tool\necho $?\n");
+        assert_eq!(out, format!("alias ll='ls -l'\nf\ncd\n{}\n0\n", tool.display()));
+        assert_eq!(run(&mut shell, "command -v nope\necho $?\n"), "1\n");
+        assert_eq!(run(&mut shell, "command -v cd nope\necho $?\n"), "cd\n1\n");
+        assert_eq!(run(&mut shell, "command -V cd\n"), "cd is a shell builtin\n");
+        assert_eq!(
+            run(&mut shell, "command -x\necho $?\n"),
+            "command: -x: invalid option\nUsage: command [-v | -V] name [arg ...]\n2\n"
+        );
+        // `command` skips functions and aliases
+        assert_eq!(run(&mut shell, "tool() { echo function; }\ncommand tool\n"), "tool ran\n");
+        let _ = fs::remove_dir_all(tool.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hash_remembers_and_forgets_commands() {
+        let (mut shell, tool) = shell_with_tool("hash", "");
+        assert_eq!(run(&mut shell, "hash\n"), "hash: hash table empty\n");
+        run(&mut shell, "tool\ntool\n");
+        assert_eq!(run(&mut shell, "hash\n"), format!("hits\tcommand\n   2\t{}\n", tool.display()));
+        // PATH holds only `tool`, so statuses are checked here, not echoed
+        assert_eq!(run(&mut shell, "hash -r\nhash\n"), "hash: hash table empty\n");
+
+        assert_eq!(run(&mut shell, "hash tool cd\n"), "");
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(run(&mut shell, "hash\n"), format!("hits\tcommand\n   0\t{}\n", tool.display()));
+        assert_eq!(run(&mut shell, "hash nope\n"), "hash: nope: not found\n");
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(run(&mut shell, "hash -d tool nope\n"), "hash: nope: not found\n");
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(run(&mut shell, "hash\n"), "hash: hash table empty\n");
+
+        // a new PATH empties the table
+        run(&mut shell, "tool\n");
+        shell.set_var("PATH", "/nonexistent");
+        assert_eq!(run(&mut shell, "hash\n"), "hash: hash table empty\n");
+        let _ = fs::remove_dir_all(tool.parent().unwrap());
     }
-    Err(io::Error::new(
-        io::ErrorKind::NotFound, 
-        format!("{} not found in PATH", cmd)
-    ))
 }
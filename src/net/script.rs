@@ -0,0 +1,766 @@
+//! Parser for multi-line shell input: `if`/`elif`/`else`, `while`/`until`,
+//! `for`, `case`, brace groups, function definitions and `&&`/`||` lists.
+//!
+//! Simple commands are kept as raw source text. Expansion, globbing and
+//! pipeline parsing happen when they are executed, so loop variables and
+//! positional arguments see their current values.
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// A pipeline with its redirections, e.g. `ls -l | grep foo > out.txt`.
+    Simple(String),
+    /// `{ list; }`
+    Group(Vec<Node>),
+    /// `if c; then b; [elif c; then b;] [else b;] fi`
+    If {
+        branches: Vec<(Vec<Node>, Vec<Node>)>,
+        else_body: Option<Vec<Node>>,
+    },
+    /// `while c; do b; done`, or `until c; do b; done` when `until` is set.
+    While {
+        cond: Vec<Node>,
+        body: Vec<Node>,
+        until: bool,
+    },
+    /// `for var [in words]; do b; done`. Without `in`, loops over `$@`.
+    For {
+        var: String,
+        words: Option<String>,
+        body: Vec<Node>,
+    },
+    /// `case word in pat|pat) b;; esac`
+    Case { word: String, arms: Vec<CaseArm> },
+    /// `name() { b; }` or `function name { b; }`
+    Function { name: String, body: Arc<Vec<Node>> },
+    /// `a && b || c`: each later command runs only if the status so far
+    /// is zero (`&&`) or non-zero (`||`).
+    AndOr { first: Box<Node>, rest: Vec<(Connector, Node)> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseArm {
+    pub patterns: Vec<String>,
+    pub body: Vec<Node>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The input ends inside a compound command; more lines are needed.
+    Incomplete,
+    /// The input ends inside a `'` or `"` quoted string. Interactively
+    /// more lines are needed; in a file it is an error.
+    UnterminatedQuote(char),
+    Syntax(String),
+}
+
+impl ParseError {
+    /// True if more input could complete the script.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, ParseError::Incomplete | ParseError::UnterminatedQuote(_))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "unexpected end of input"),
+            ParseError::UnterminatedQuote('\'') => write!(f, "unterminated single quote"),
+            ParseError::UnterminatedQuote(_) => write!(f, "unterminated double quote"),
+            ParseError::Syntax(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Parses a complete script. Returns `ParseError::Incomplete` if a compound
+/// command is still open, or `ParseError::UnterminatedQuote` if a quote
+/// is, so an interactive caller can ask for more lines; a quoted string
+/// may span lines.
+pub fn parse(src: &str) -> Result<Vec<Node>, ParseError> {
+    let mut parser = Parser {
+        chars: src.chars().collect(),
+        pos: 0,
+        unterminated: Cell::new(None),
+    };
+    let result = parser.parse_list(&[]);
+    // an open quote swallowed the rest of the input, whatever else failed
+    if let Some(quote) = parser.unterminated.get() {
+        return Err(ParseError::UnterminatedQuote(quote));
+    }
+    let (nodes, _) = result?;
+    Ok(nodes)
+}
+
+/// True if `s` is a valid variable or function name.
+pub fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Set when a quote runs to the end of input.
+    unterminated: Cell<Option<char>>,
+}
+
+fn is_word_end(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ';' | '|' | '&' | '<' | '>' | '(' | ')')
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn at_eof(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    /// Skips spaces, tabs and `\<newline>` line continuations.
+    fn skip_blanks(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') => self.pos += 1,
+                Some('\\') if self.peek_at(1) == Some('\n') => self.pos += 2,
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skips blanks, comments, newlines and single `;` (but not `;;`).
+    fn skip_separators(&mut self) {
+        loop {
+            self.skip_blanks();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') => self.pos += 1,
+                Some(';') if self.peek_at(1) != Some(';') => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    /// Skips over a quoted section starting at `i`, returning the index
+    /// just past the closing quote (or the end of input).
+    fn skip_quoted(&self, mut i: usize) -> usize {
+        let quote = self.chars[i];
+        i += 1;
+        while i < self.chars.len() {
+            let c = self.chars[i];
+            if c == quote {
+                return i + 1;
+            }
+            if quote == '"' && c == '\\' {
+                i += 1;
+            }
+            i += 1;
+        }
+        self.unterminated.set(Some(quote));
+        i
+    }
+
+    /// Scans one raw word (quotes kept) starting at the current position.
+    fn scan_word(&self) -> (String, usize) {
+        let mut i = self.pos;
+        while i < self.chars.len() {
+            let c = self.chars[i];
+            if c == '\'' || c == '"' {
+                i = self.skip_quoted(i);
+            } else if c == '\\' {
+                i += 2;
+            } else if is_word_end(c) {
+                break;
+            } else {
+                i += 1;
+            }
+        }
+        let end = i.min(self.chars.len());
+        (self.chars[self.pos..end].iter().collect(), end)
+    }
+
+    fn peek_word(&self) -> Option<String> {
+        let (word, _) = self.scan_word();
+        if word.is_empty() {
+            None
+        } else {
+            Some(word)
+        }
+    }
+
+    fn next_word(&mut self) -> String {
+        let (word, end) = self.scan_word();
+        self.pos = end;
+        word
+    }
+
+    /// Scans raw text up to the next unquoted `;`, newline, comment,
+    /// `&&` or `||`.
+    fn scan_statement(&mut self) -> String {
+        let start = self.pos;
+        let mut i = self.pos;
+        while i < self.chars.len() {
+            let c = self.chars[i];
+            match c {
+                '\'' | '"' => i = self.skip_quoted(i),
+                '\\' => i += 2,
+                ';' | '\n' => break,
+                '&' | '|' if self.chars.get(i + 1) == Some(&c) => break,
+                '#' if i == start || self.chars[i - 1].is_whitespace() => break,
+                _ => i += 1,
+            }
+        }
+        self.pos = i.min(self.chars.len());
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+
+    fn unexpected(&self, what: &str) -> ParseError {
+        ParseError::Syntax(format!("syntax error near unexpected '{}'", what))
+    }
+
+    /// Consumes the keyword `kw`, allowing separators before it.
+    fn expect_keyword(&mut self, kw: &str) -> Result<(), ParseError> {
+        self.skip_separators();
+        match self.peek_word() {
+            Some(w) if w == kw => {
+                self.next_word();
+                Ok(())
+            }
+            Some(w) => Err(ParseError::Syntax(format!(
+                "syntax error: expected '{}' but found '{}'",
+                kw, w
+            ))),
+            None if self.at_eof() => Err(ParseError::Incomplete),
+            None => Err(self.unexpected(&self.peek().unwrap_or(' ').to_string())),
+        }
+    }
+
+    /// Parses commands until one of `terms` appears at the start of a
+    /// command, returning the list and the terminator that ended it.
+    /// With no terminators, parses until end of input.
+    fn parse_list(
+        &mut self,
+        terms: &[&'static str],
+    ) -> Result<(Vec<Node>, &'static str), ParseError> {
+        let mut nodes = Vec::new();
+        loop {
+            self.skip_separators();
+            if self.at_eof() {
+                if terms.is_empty() {
+                    return Ok((nodes, ""));
+                }
+                return Err(ParseError::Incomplete);
+            }
+            if terms.contains(&";;") && self.peek() == Some(';') && self.peek_at(1) == Some(';') {
+                self.pos += 2;
+                return Ok((nodes, ";;"));
+            }
+            if let Some(word) = self.peek_word() {
+                if let Some(term) = terms.iter().find(|t| **t == word) {
+                    self.next_word();
+                    return Ok((nodes, term));
+                }
+            }
+            nodes.push(self.parse_and_or()?);
+        }
+    }
+
+    /// Parses commands joined by `&&` and `||`. A newline may follow
+    /// either operator.
+    fn parse_and_or(&mut self) -> Result<Node, ParseError> {
+        let first = self.parse_command()?;
+        let mut rest = Vec::new();
+        loop {
+            self.skip_blanks();
+            let connector = match (self.peek(), self.peek_at(1)) {
+                (Some('&'), Some('&')) => Connector::And,
+                (Some('|'), Some('|')) => Connector::Or,
+                _ => break,
+            };
+            self.pos += 2;
+            loop {
+                self.skip_blanks();
+                self.skip_comment();
+                if self.peek() != Some('\n') {
+                    break;
+                }
+                self.pos += 1;
+            }
+            if self.at_eof() {
+                return Err(ParseError::Incomplete);
+            }
+            rest.push((connector, self.parse_command()?));
+        }
+        if rest.is_empty() {
+            return Ok(first);
+        }
+        Ok(Node::AndOr {
+            first: Box::new(first),
+            rest,
+        })
+    }
+
+    fn parse_command(&mut self) -> Result<Node, ParseError> {
+        let word = self.peek_word().unwrap_or_default();
+        match word.as_str() {
+            "if" => self.parse_if(),
+            "while" => self.parse_while(false),
+            "until" => self.parse_while(true),
+            "for" => self.parse_for(),
+            "case" => self.parse_case(),
+            "function" => {
+                self.next_word();
+                self.skip_blanks();
+                let name = self.next_word();
+                if !is_name(&name) {
+                    return Err(ParseError::Syntax(format!("'{}' is not a valid function name", name)));
+                }
+                self.skip_blanks();
+                if self.peek() == Some('(') {
+                    self.expect_parens()?;
+                }
+                self.parse_function_body(name)
+            }
+            "{" => {
+                self.next_word();
+                let (body, _) = self.parse_list(&["}"])?;
+                self.end_compound()?;
+                Ok(Node::Group(body))
+            }
+            "then" | "elif" | "else" | "fi" | "do" | "done" | "esac" | "}" => {
+                Err(self.unexpected(&word))
+            }
+            _ if is_name(&word) && self.is_function_def() => {
+                self.next_word();
+                self.expect_parens()?;
+                self.parse_function_body(word)
+            }
+            _ => self.parse_simple(),
+        }
+    }
+
+    fn parse_simple(&mut self) -> Result<Node, ParseError> {
+        let text = self.scan_statement();
+        if text.is_empty() {
+            let c = self.peek().unwrap_or(' ');
+            let what = if c == ';' && self.peek_at(1) == Some(';') {
+                ";;".to_string()
+            } else {
+                c.to_string()
+            };
+            return Err(self.unexpected(&what));
+        }
+        Ok(Node::Simple(text))
+    }
+
+    /// After `fi`, `done`, `esac` or `}`, only a separator, `&&`, `||` or
+    /// another command may follow; pipes and redirections are not supported.
+    fn end_compound(&mut self) -> Result<(), ParseError> {
+        self.skip_blanks();
+        match self.peek() {
+            Some(c) if matches!(c, '|' | '&') && self.peek_at(1) == Some(c) => Ok(()),
+            Some(c) if matches!(c, '|' | '&' | '<' | '>') => Err(self.unexpected(&c.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Looks ahead for `name ()` after the current word.
+    fn is_function_def(&self) -> bool {
+        let (_, mut i) = self.scan_word();
+        while matches!(self.chars.get(i), Some(' ') | Some('\t')) {
+            i += 1;
+        }
+        self.chars.get(i) == Some(&'(')
+    }
+
+    fn expect_parens(&mut self) -> Result<(), ParseError> {
+        self.skip_blanks();
+        if self.peek() != Some('(') {
+            return Err(self.unexpected(&self.peek().unwrap_or(' ').to_string()));
+        }
+        self.pos += 1;
+        self.skip_blanks();
+        match self.peek() {
+            Some(')') => {
+                self.pos += 1;
+                Ok(())
+            }
+            None => Err(ParseError::Incomplete),
+            Some(c) => Err(self.unexpected(&c.to_string())),
+        }
+    }
+
+    fn parse_function_body(&mut self, name: String) -> Result<Node, ParseError> {
+        self.expect_keyword("{")?;
+        let (body, _) = self.parse_list(&["}"])?;
+        self.end_compound()?;
+        Ok(Node::Function {
+            name,
+            body: Arc::new(body),
+        })
+    }
+
+    fn parse_if(&mut self) -> Result<Node, ParseError> {
+        self.next_word();
+        let mut branches = Vec::new();
+        let mut else_body = None;
+        loop {
+            let (cond, _) = self.parse_list(&["then"])?;
+            if cond.is_empty() {
+                return Err(self.unexpected("then"));
+            }
+            let (body, term) = self.parse_list(&["elif", "else", "fi"])?;
+            branches.push((cond, body));
+            match term {
+                "elif" => continue,
+                "else" => {
+                    let (body, _) = self.parse_list(&["fi"])?;
+                    else_body = Some(body);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        self.end_compound()?;
+        Ok(Node::If { branches, else_body })
+    }
+
+    fn parse_while(&mut self, until: bool) -> Result<Node, ParseError> {
+        self.next_word();
+        let (cond, _) = self.parse_list(&["do"])?;
+        if cond.is_empty() {
+            return Err(self.unexpected("do"));
+        }
+        let (body, _) = self.parse_list(&["done"])?;
+        self.end_compound()?;
+        Ok(Node::While { cond, body, until })
+    }
+
+    fn parse_for(&mut self) -> Result<Node, ParseError> {
+        self.next_word();
+        self.skip_blanks();
+        let var = self.next_word();
+        if var.is_empty() && self.at_eof() {
+            return Err(ParseError::Incomplete);
+        }
+        if !is_name(&var) {
+            return Err(ParseError::Syntax(format!("'{}' is not a valid identifier", var)));
+        }
+        self.skip_blanks();
+        let words = if self.peek_word().as_deref() == Some("in") {
+            self.next_word();
+            Some(self.scan_statement())
+        } else {
+            None
+        };
+        self.expect_keyword("do")?;
+        let (body, _) = self.parse_list(&["done"])?;
+        self.end_compound()?;
+        Ok(Node::For { var, words, body })
+    }
+
+    fn parse_case(&mut self) -> Result<Node, ParseError> {
+        self.next_word();
+        self.skip_blanks();
+        let word = self.next_word();
+        if word.is_empty() {
+            if self.at_eof() {
+                return Err(ParseError::Incomplete);
+            }
+            return Err(self.unexpected(&self.peek().unwrap_or(' ').to_string()));
+        }
+        self.expect_keyword("in")?;
+
+        let mut arms = Vec::new();
+        loop {
+            self.skip_separators();
+            if self.at_eof() {
+                return Err(ParseError::Incomplete);
+            }
+            if self.peek_word().as_deref() == Some("esac") {
+                self.next_word();
+                break;
+            }
+            if self.peek() == Some('(') {
+                self.pos += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                self.skip_blanks();
+                let pattern = self.next_word();
+                if pattern.is_empty() {
+                    return match self.peek() {
+                        None => Err(ParseError::Incomplete),
+                        Some(c) => Err(self.unexpected(&c.to_string())),
+                    };
+                }
+                patterns.push(pattern);
+                self.skip_blanks();
+                match self.peek() {
+                    Some('|') => self.pos += 1,
+                    Some(')') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    None => return Err(ParseError::Incomplete),
+                    Some(c) => return Err(self.unexpected(&c.to_string())),
+                }
+            }
+            let (body, term) = self.parse_list(&[";;", "esac"])?;
+            arms.push(CaseArm { patterns, body });
+            if term == "esac" {
+                break;
+            }
+        }
+        self.end_compound()?;
+        Ok(Node::Case { word, arms })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simple(text: &str) -> Node {
+        Node::Simple(text.to_string())
+    }
+
+    fn simples(texts: &[&str]) -> Vec<Node> {
+        texts.iter().map(|t| simple(t)).collect()
+    }
+
+    fn syntax_error(src: &str) -> String {
+        match parse(src) {
+            Err(ParseError::Syntax(msg)) => msg,
+            other => panic!("{:?}: expected a syntax error, got {:?}", src, other),
+        }
+    }
+
+    #[test]
+    fn simple_commands_keep_their_source_text() {
+        let nodes = parse("ls -l | grep 'a;b' > out.txt; echo \"x # y\" # comment\n\n  pwd\n").unwrap();
+        assert_eq!(nodes, simples(&["ls -l | grep 'a;b' > out.txt", "echo \"x # y\"", "pwd"]));
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert_eq!(parse("echo a \\\n  b").unwrap(), simples(&["echo a \\\n  b"]));
+    }
+
+    #[test]
+    fn if_elif_else() {
+        let nodes = parse("if test -f a; then echo a; elif false\nthen echo b; echo c\nelse echo d; fi").unwrap();
+        assert_eq!(
+            nodes,
+            vec![Node::If {
+                branches: vec![
+                    (simples(&["test -f a"]), simples(&["echo a"])),
+                    (simples(&["false"]), simples(&["echo b", "echo c"])),
+                ],
+                else_body: Some(simples(&["echo d"])),
+            }]
+        );
+        assert_eq!(
+            parse("if true; then :; fi").unwrap(),
+            vec![Node::If { branches: vec![(simples(&["true"]), simples(&[":"]))], else_body: None }]
+        );
+    }
+
+    #[test]
+    fn while_and_until() {
+        assert_eq!(
+            parse("while read x; do echo $x; done").unwrap(),
+            vec![Node::While { cond: simples(&["read x"]), body: simples(&["echo $x"]), until: false }]
+        );
+        assert_eq!(
+            parse("until false\ndo\n  break\ndone").unwrap(),
+            vec![Node::While { cond: simples(&["false"]), body: simples(&["break"]), until: true }]
+        );
+    }
+
+    #[test]
+    fn for_loops() {
+        assert_eq!(
+            parse("for f in *.txt 'a b'; do cat $f; done").unwrap(),
+            vec![Node::For { var: "f".into(), words: Some("*.txt 'a b'".into()), body: simples(&["cat $f"]) }]
+        );
+        assert_eq!(
+            parse("for arg\ndo echo $arg; done").unwrap(),
+            vec![Node::For { var: "arg".into(), words: None, body: simples(&["echo $arg"]) }]
+        );
+    }
+
+    #[test]
+    fn case_arms() {
+        let nodes = parse("case $x in\n  a|b) echo ab;;\n  (c*) echo c; echo cc ;;\n  *) ;;\nesac").unwrap();
+        assert_eq!(
+            nodes,
+            vec![Node::Case {
+                word: "$x".into(),
+                arms: vec![
+                    CaseArm { patterns: vec!["a".into(), "b".into()], body: simples(&["echo ab"]) },
+                    CaseArm { patterns: vec!["c*".into()], body: simples(&["echo c", "echo cc"]) },
+                    CaseArm { patterns: vec!["*".into()], body: vec![] },
+                ],
+            }]
+        );
+        // the last arm may end at esac without ;;
+        assert_eq!(
+            parse("case x in x) echo x\nesac").unwrap(),
+            vec![Node::Case { word: "x".into(), arms: vec![CaseArm { patterns: vec!["x".into()], body: simples(&["echo x"]) }] }]
+        );
+    }
+
+    #[test]
+    fn functions_and_groups() {
+        let body = Arc::new(simples(&["echo $1", "return 3"]));
+        assert_eq!(
+            parse("greet() { echo $1; return 3; }").unwrap(),
+            vec![Node::Function { name: "greet".into(), body: body.clone() }]
+        );
+        assert_eq!(
+            parse("function greet {\n echo $1\n return 3\n}").unwrap(),
+            vec![Node::Function { name: "greet".into(), body: body.clone() }]
+        );
+        assert_eq!(parse("function greet () { echo $1; return 3; }").unwrap(), vec![Node::Function { name: "greet".into(), body }]);
+        assert_eq!(parse("{ echo a; echo b; }; echo c").unwrap(), vec![Node::Group(simples(&["echo a", "echo b"])), simple("echo c")]);
+    }
+
+    #[test]
+    fn compound_commands_nest() {
+        let src = "f() {\n  for x in 1 2; do\n    if [ $x = 1 ]; then\n      while false; do :; done\n    else\n      case $x in 2) { echo two; };; esac\n    fi\n  done\n}\nf";
+        let nodes = parse(src).unwrap();
+        let inner_while = Node::While { cond: simples(&["false"]), body: simples(&[":"]), until: false };
+        let inner_case = Node::Case {
+            word: "$x".into(),
+            arms: vec![CaseArm { patterns: vec!["2".into()], body: vec![Node::Group(simples(&["echo two"]))] }],
+        };
+        let inner_if = Node::If { branches: vec![(simples(&["[ $x = 1 ]"]), vec![inner_while])], else_body: Some(vec![inner_case]) };
+        let for_loop = Node::For { var: "x".into(), words: Some("1 2".into()), body: vec![inner_if] };
+        assert_eq!(nodes, vec![Node::Function { name: "f".into(), body: Arc::new(vec![for_loop]) }, simple("f")]);
+    }
+
+    #[test]
+    fn and_or_lists() {
+        assert_eq!(
+            parse("a && b || 'c||d' | e; f &&\n  g").unwrap(),
+            vec![
+                Node::AndOr {
+                    first: Box::new(simple("a")),
+                    rest: vec![(Connector::And, simple("b")), (Connector::Or, simple("'c||d' | e"))],
+                },
+                Node::AndOr {
+                    first: Box::new(simple("f")),
+                    rest: vec![(Connector::And, simple("g"))],
+                },
+            ]
+        );
+        assert_eq!(
+            parse("if a || b; then c; fi && d").unwrap(),
+            vec![Node::AndOr {
+                first: Box::new(Node::If {
+                    branches: vec![(
+                        vec![Node::AndOr {
+                            first: Box::new(simple("a")),
+                            rest: vec![(Connector::Or, simple("b"))],
+                        }],
+                        simples(&["c"]),
+                    )],
+                    else_body: None,
+                }),
+                rest: vec![(Connector::And, simple("d"))],
+            }]
+        );
+        assert_eq!(parse("echo a | b & c").unwrap(), simples(&["echo a | b & c"]));
+    }
+
+    #[test]
+    fn open_compounds_are_incomplete() {
+        for src in [
+            "if true; then",
+            "if true; then echo; else",
+            "while true",
+            "while true; do echo",
+            "for x in a b",
+            "for",
+            "case x in",
+            "case x in a) echo",
+            "f() {",
+            "function f",
+            "{ echo",
+            "true &&",
+            "false ||\n# comment\n",
+        ] {
+            assert_eq!(parse(src), Err(ParseError::Incomplete), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(syntax_error("fi"), "syntax error near unexpected 'fi'");
+        assert_eq!(syntax_error("echo a; done"), "syntax error near unexpected 'done'");
+        assert_eq!(syntax_error("if then echo; fi"), "syntax error near unexpected 'then'");
+        assert_eq!(syntax_error("while do echo; done"), "syntax error near unexpected 'do'");
+        assert_eq!(syntax_error("for 1x in a; do :; done"), "'1x' is not a valid identifier");
+        assert_eq!(syntax_error("function 9f { :; }"), "'9f' is not a valid function name");
+        assert_eq!(syntax_error("for x in a; echo; done"), "syntax error: expected 'do' but found 'echo'");
+        assert_eq!(syntax_error("if true; then :; fi | cat"), "syntax error near unexpected '|'");
+        assert_eq!(syntax_error("case x in a echo;; esac"), "syntax error near unexpected 'e'");
+        assert_eq!(syntax_error(";; echo"), "syntax error near unexpected ';;'");
+        assert_eq!(syntax_error("f( ) x"), "syntax error: expected '{' but found 'x'");
+        assert_eq!(syntax_error("{ :; } > out"), "syntax error near unexpected '>'");
+        assert_eq!(syntax_error("&& echo"), "syntax error near unexpected '&'");
+        assert_eq!(syntax_error("true && || echo"), "syntax error near unexpected '|'");
+        assert_eq!(syntax_error("true && fi"), "syntax error near unexpected 'fi'");
+    }
+
+    #[test]
+    fn quotes_may_span_lines() {
+        assert_eq!(parse("echo 'a\nb'; echo c").unwrap(), simples(&["echo 'a\nb'", "echo c"]));
+        assert_eq!(parse("echo \"a;\n\\\"b\"").unwrap(), simples(&["echo \"a;\n\\\"b\""]));
+        assert_eq!(parse("echo 'it''s'").unwrap(), simples(&["echo 'it''s'"]));
+    }
+
+    #[test]
+    fn unterminated_quotes_need_more_input() {
+        assert_eq!(parse("echo 'abc"), Err(ParseError::UnterminatedQuote('\'')));
+        assert_eq!(parse("echo \"abc\ndef"), Err(ParseError::UnterminatedQuote('"')));
+        assert_eq!(parse("if true; then echo \"x"), Err(ParseError::UnterminatedQuote('"')));
+        assert_eq!(parse("echo \"a\\\"").unwrap_err().to_string(), "unterminated double quote");
+        assert_eq!(parse("echo 'a").unwrap_err().to_string(), "unterminated single quote");
+        assert!(parse("echo 'a").unwrap_err().is_incomplete());
+        assert!(!parse("fi").unwrap_err().is_incomplete());
+    }
+
+    #[test]
+    fn names() {
+        assert!(is_name("_a1") && is_name("PATH"));
+        assert!(!is_name("") && !is_name("1a") && !is_name("a-b") && !is_name("a.b"));
+    }
+}
@@ -0,0 +1,487 @@
+//! Detachable shell sessions for net_shell. Each session's shell runs on
+//! its own thread against one end of a loopback TCP pair, so it outlives
+//! the network connection. A connection attaches to a session; while none
+//! is attached, the session's output is kept (up to a limit) and replayed
+//! on the next `attach`, much like tmux or screen. Only so many sessions
+//! run at once, and one left detached too long is ended.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::audit::{self, AuditRecord};
+
+/// Longest wait between checks for sessions detached too long.
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a client has to pick a session.
+const CHOOSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest line a client may send while picking a session.
+const MAX_LINE: usize = 4096;
+
+/// A shell session that connections come and go from.
+pub struct Session {
+    token: String,
+    started: Instant,
+    /// Our end of the loopback pair; input from the client is written here.
+    input: Mutex<TcpStream>,
+    state: Mutex<Attachment>,
+}
+
+struct Attachment {
+    /// The connection currently attached, if any.
+    client: Option<TcpStream>,
+    /// Counts attaches, so a stale connection cannot detach a newer one.
+    generation: u64,
+    /// Output produced while detached, oldest first.
+    backlog: VecDeque<u8>,
+    /// Bytes dropped from the front of `backlog` to respect the limit.
+    lost: usize,
+    /// The shell has exited.
+    finished: bool,
+    /// Bytes sent to the current client, backlog included.
+    sent: u64,
+    /// When the last client left (or the session started), while none is
+    /// attached.
+    detached_since: Option<Instant>,
+}
+
+impl Session {
+    /// The token that identifies this session to `attach`.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Address of the attached client, if any.
+    pub fn peer(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.client.as_ref().and_then(|c| c.peer_addr().ok()).map(|a| a.to_string())
+    }
+
+    /// Disconnects the attached client, leaving the session running.
+    pub fn detach(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(mut client) = state.client.take() {
+            state.detached_since = Some(Instant::now());
+            let _ = writeln!(client, "[session] detached from {}", self.token);
+            let _ = client.shutdown(Shutdown::Both);
+            info!("Session {} detached", self.token);
+        }
+    }
+
+    /// Closes the shell's input, which ends the session.
+    fn end(&self) {
+        let _ = self.input.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+/// All sessions of one net_shell process.
+pub struct SessionManager {
+    sessions: Mutex<Vec<Arc<Session>>>,
+    replay_limit: usize,
+    max_sessions: usize,
+}
+
+impl SessionManager {
+    /// Sessions buffer at most `replay_limit` bytes of output while
+    /// detached; older output is dropped first. At most `max_sessions` run
+    /// at once, and a session detached for `detached_timeout` is ended.
+    pub fn new(replay_limit: usize, max_sessions: usize, detached_timeout: Option<Duration>) -> Arc<Self> {
+        let manager = Arc::new(SessionManager { sessions: Mutex::new(Vec::new()), replay_limit, max_sessions });
+        if let Some(timeout) = detached_timeout {
+            let weak = Arc::downgrade(&manager);
+            thread::spawn(move || reap_detached(weak, timeout));
+        }
+        manager
+    }
+
+    /// Sessions with no connection attached.
+    pub fn detached(&self) -> Vec<Arc<Session>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.state.lock().unwrap().client.is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Serves one connection until it closes or its session ends. With
+    /// detached sessions around, the client first picks one by token with
+    /// `attach <token>`, or asks for a `new` one.
+    /// A new session runs `start` on its own thread with the shell's end
+    /// of the loopback pair.
+    pub fn serve<F>(self: &Arc<Self>, mut client: TcpStream, start: F) -> io::Result<()>
+    where
+        F: FnOnce(TcpStream, Arc<Session>) + Send + 'static,
+    {
+        let mut early_lines = Vec::new();
+        let session = match self.choose(&mut client, &mut early_lines)? {
+            Some(session) => session,
+            None => {
+                let Some(session) = self.spawn(start)? else {
+                    writeln!(client, "[session] {} sessions already running; attach to one or try again later", self.max_sessions)?;
+                    return Ok(());
+                };
+                writeln!(client, "[session] {}", session.token)?;
+                // A new shell still wants the TERMINAL line the client
+                // sent ahead of the menu.
+                session.input.lock().unwrap().write_all(&early_lines)?;
+                session
+            }
+        };
+        self.attach(&session, client)
+    }
+
+    /// Offers the detached sessions; `None` means start a new one. The
+    /// client has `CHOOSE_TIMEOUT` to answer.
+    fn choose(&self, client: &mut TcpStream, early_lines: &mut Vec<u8>) -> io::Result<Option<Arc<Session>>> {
+        let detached = self.detached();
+        if detached.is_empty() {
+            return Ok(None);
+        }
+        client.set_read_timeout(Some(CHOOSE_TIMEOUT))?;
+        let chosen = self.offer(client, &detached, early_lines);
+        client.set_read_timeout(None)?;
+        chosen
+    }
+
+    /// Lists `detached` and reads the client's choice.
+    fn offer(
+        &self,
+        client: &mut TcpStream,
+        detached: &[Arc<Session>],
+        early_lines: &mut Vec<u8>,
+    ) -> io::Result<Option<Arc<Session>>> {
+        writeln!(client, "[session] Detached sessions:")?;
+        for session in detached {
+            let state = session.state.lock().unwrap();
+            writeln!(
+                client,
+                "[session]   {}  up {}s, {} bytes waiting",
+                session.token,
+                session.started.elapsed().as_secs(),
+                state.backlog.len()
+            )?;
+        }
+        writeln!(client, "[session] 'attach <token>' to resume one, 'new' for a new session")?;
+
+        loop {
+            let line = read_line(client).map_err(|e| match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                    io::Error::new(io::ErrorKind::TimedOut, "client did not choose a session in time")
+                }
+                _ => e,
+            })?;
+            let Some(line) = line else {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "client left before choosing a session"));
+            };
+            let line = line.trim();
+            if line.starts_with("TERMINAL ") {
+                early_lines.extend_from_slice(format!("{}\n", line).as_bytes());
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (None, _, _) => continue,
+                (Some("new"), None, _) => return Ok(None),
+                (Some("attach"), Some(token), None) => match self.find(token) {
+                    Some(session) => return Ok(Some(session)),
+                    None => writeln!(client, "[session] no session {}", token)?,
+                },
+                _ => writeln!(client, "[session] 'attach <token>' or 'new'")?,
+            }
+        }
+    }
+
+    fn find(&self, token: &str) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().iter().find(|s| s.token == token).cloned()
+    }
+
+    /// Starts a session: a loopback pair, the shell thread on one end and
+    /// an output pump on the other. `None` if `max_sessions` are running.
+    fn spawn<F>(self: &Arc<Self>, start: F) -> io::Result<Option<Arc<Session>>>
+    where
+        F: FnOnce(TcpStream, Arc<Session>) + Send + 'static,
+    {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= self.max_sessions {
+            return Ok(None);
+        }
+        let (ours, theirs) = loopback_pair()?;
+        let session = Arc::new(Session {
+            token: new_token(),
+            started: Instant::now(),
+            input: Mutex::new(ours.try_clone()?),
+            state: Mutex::new(Attachment {
+                client: None,
+                generation: 0,
+                backlog: VecDeque::new(),
+                lost: 0,
+                finished: false,
+                sent: 0,
+                detached_since: Some(Instant::now()),
+            }),
+        });
+        sessions.push(Arc::clone(&session));
+        drop(sessions);
+        info!("Session {} started", session.token);
+        audit::record(AuditRecord { session: Some(session.token.clone()), ..AuditRecord::new("net_shell", "session_start") });
+
+        let handle = Arc::clone(&session);
+        thread::spawn(move || start(theirs, handle));
+
+        let manager = Arc::clone(self);
+        let pumped = Arc::clone(&session);
+        thread::spawn(move || manager.pump_output(ours, pumped));
+        Ok(Some(session))
+    }
+
+    /// Copies the shell's output to the attached client, or into the
+    /// backlog while there is none. Ends the session when the shell exits.
+    fn pump_output(&self, mut output: TcpStream, session: Arc<Session>) {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = match output.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let mut state = session.state.lock().unwrap();
+            if let Some(client) = state.client.as_mut() {
+                if client.write_all(&buf[..n]).is_ok() {
+                    state.sent += n as u64;
+                    continue;
+                }
+                info!("Session {} detached", session.token);
+                state.client = None;
+                state.detached_since = Some(Instant::now());
+            }
+            state.backlog.extend(&buf[..n]);
+            let excess = state.backlog.len().saturating_sub(self.replay_limit);
+            if excess > 0 {
+                state.backlog.drain(..excess);
+                state.lost += excess;
+            }
+        }
+
+        info!("Session {} ended", session.token);
+        audit::record(AuditRecord {
+            session: Some(session.token.clone()),
+            duration: Some(session.started.elapsed()),
+            ..AuditRecord::new("net_shell", "session_end")
+        });
+        self.sessions.lock().unwrap().retain(|s| !Arc::ptr_eq(s, &session));
+        let mut state = session.state.lock().unwrap();
+        state.finished = true;
+        if let Some(client) = state.client.take() {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Replays what the session missed, then relays the client's input to
+    /// it until either side goes away.
+    fn attach(&self, session: &Arc<Session>, mut client: TcpStream) -> io::Result<()> {
+        let generation = {
+            let mut state = session.state.lock().unwrap();
+            if state.finished {
+                writeln!(client, "[session] {} has ended", session.token)?;
+                return Ok(());
+            }
+            if state.lost > 0 {
+                writeln!(client, "[warn] {} bytes of earlier output were dropped", state.lost)?;
+            }
+            let backlog: Vec<u8> = state.backlog.drain(..).collect();
+            client.write_all(&backlog)?;
+            client.flush()?;
+            state.lost = 0;
+            state.sent = backlog.len() as u64;
+            state.client = Some(client.try_clone()?);
+            state.detached_since = None;
+            state.generation += 1;
+            state.generation
+        };
+        info!("Attached to session {}", session.token);
+        let peer = client.peer_addr().ok().map(|a| a.to_string());
+        let attached = Instant::now();
+        audit::record(AuditRecord {
+            peer: peer.clone(),
+            session: Some(session.token.clone()),
+            ..AuditRecord::new("net_shell", "attach")
+        });
+
+        let mut input = session.input.lock().unwrap().try_clone()?;
+        let mut buf = [0u8; 4096];
+        let mut received = 0;
+        loop {
+            match client.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if input.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                    received += n as u64;
+                }
+            }
+        }
+
+        // Only detach if no later connection has taken over already
+        let mut state = session.state.lock().unwrap();
+        let current = state.generation == generation;
+        if current && state.client.is_some() {
+            state.client = None;
+            state.detached_since = Some(Instant::now());
+            info!("Session {} detached", session.token);
+        }
+        audit::record(AuditRecord {
+            peer,
+            session: Some(session.token.clone()),
+            duration: Some(attached.elapsed()),
+            bytes_in: Some(received),
+            // After a takeover, `sent` counts for the newer connection
+            bytes_out: current.then_some(state.sent),
+            ..AuditRecord::new("net_shell", "detach")
+        });
+        Ok(())
+    }
+}
+
+/// Ends the sessions of `manager` that stay detached for `timeout`, for
+/// as long as the manager is around.
+fn reap_detached(manager: Weak<SessionManager>, timeout: Duration) {
+    loop {
+        thread::sleep(timeout.min(REAP_INTERVAL));
+        let Some(manager) = manager.upgrade() else {
+            return;
+        };
+        for session in manager.detached() {
+            let since = session.state.lock().unwrap().detached_since;
+            if since.is_some_and(|since| since.elapsed() >= timeout) {
+                info!("Session {} detached for {}s; ending it", session.token, timeout.as_secs());
+                session.end();
+            }
+        }
+    }
+}
+
+/// A connected pair of loopback sockets, checked to be each other's peer.
+fn loopback_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (theirs, addr) = listener.accept()?;
+        if addr == ours.local_addr()? {
+            return Ok((ours, theirs));
+        }
+    }
+}
+
+fn new_token() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Reads one line of up to `MAX_LINE` bytes a byte at a time, so nothing
+/// after it is consumed.
+fn read_line(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        match stream.read(&mut byte)? {
+            0 if line.is_empty() => return Ok(None),
+            0 => break,
+            _ if byte[0] == b'\n' => break,
+            _ if line.len() == MAX_LINE => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+            }
+            _ => line.push(byte[0]),
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves one new connection from `manager` with a shell that just
+    /// waits for its input to end; returns the client's end.
+    fn connect(manager: &Arc<SessionManager>) -> TcpStream {
+        let (client, server) = loopback_pair().unwrap();
+        let manager = Arc::clone(manager);
+        thread::spawn(move || {
+            manager.serve(server, |mut shell, _| {
+                let _ = io::copy(&mut shell, &mut io::sink());
+            })
+        });
+        client
+    }
+
+    #[test]
+    fn sessions_past_the_limit_are_refused() {
+        let manager = SessionManager::new(1024, 1, None);
+        let mut first = connect(&manager);
+        assert!(read_line(&mut first).unwrap().unwrap().starts_with("[session] "));
+
+        let mut second = connect(&manager);
+        let mut reply = String::new();
+        second.read_to_string(&mut reply).unwrap();
+        assert!(reply.contains("1 sessions already running"), "{:?}", reply);
+    }
+
+    #[test]
+    fn only_attach_with_a_token_resumes_a_session() {
+        let manager = SessionManager::new(1024, 4, None);
+        let mut first = connect(&manager);
+        let token = read_line(&mut first).unwrap().unwrap().replace("[session] ", "");
+        drop(first);
+        while manager.detached().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = connect(&manager);
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"\nattach\n").unwrap();
+        let replies: Vec<String> = (0..4).map(|_| read_line(&mut client).unwrap().unwrap()).collect();
+        assert!(replies[1].contains(&token), "{:?}", replies);
+        assert_eq!(replies[3], "[session] 'attach <token>' or 'new'");
+        assert_eq!(manager.detached().len(), 1);
+
+        writeln!(client, "attach {}", token).unwrap();
+        let started = Instant::now();
+        while !manager.detached().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "session was not attached");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn long_lines_are_refused() {
+        let (mut client, mut server) = loopback_pair().unwrap();
+        let writer = thread::spawn(move || {
+            let _ = client.write_all(&[b'x'; MAX_LINE + 1]);
+            client
+        });
+        let err = read_line(&mut server).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        drop(writer.join());
+    }
+
+    #[test]
+    fn sessions_detached_too_long_are_ended() {
+        let manager = SessionManager::new(1024, 4, Some(Duration::from_millis(200)));
+        let mut client = connect(&manager);
+        read_line(&mut client).unwrap();
+        drop(client);
+
+        let started = Instant::now();
+        while !manager.sessions.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "session was not ended");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
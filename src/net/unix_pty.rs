@@ -3,30 +3,60 @@ use std::io::{self, Read, Write};
 #[cfg(unix)]
 use std::net::TcpStream;
 #[cfg(unix)]
-use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::fd::AsRawFd;
 #[cfg(unix)]
 use std::fs::File;
 #[cfg(unix)]
-use std::process::Command;
+use std::process::{Command, ExitStatus};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::Arc;
 #[cfg(unix)]
 use std::thread;
 
 #[cfg(unix)]
-use nix::fcntl::OFlag;
+use log::{debug, info};
+#[cfg(unix)]
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
 #[cfg(unix)]
 use nix::pty::{openpty, Winsize};
 #[cfg(unix)]
 use nix::sys::termios;
+
+#[cfg(unix)]
+use crate::common::{hang_up, set_controlling_tty, wait_readable};
 #[cfg(unix)]
-use nix::unistd::{dup, close};
+use crate::exports::CommandSpec;
 
+/// How long the bridge threads wait for data before checking whether the
+/// child has exited.
 #[cfg(unix)]
-use crate::exports::{CommandSpec, CURRENT_CHILD};
+const POLL_MS: u16 = 100;
+
+/// How a PTY command ended, and the bytes it exchanged with the client.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+pub struct PtyExit {
+    pub status: ExitStatus,
+    /// Bytes from the client to the command.
+    pub bytes_in: u64,
+    /// Bytes from the command to the client.
+    pub bytes_out: u64,
+}
 
 /// Spawns the given command in a fresh PTY on Unix-like systems,
 /// then bridges I/O between that PTY and the given `TcpStream`.
+/// Returns the child's exit status.
+#[cfg(unix)]
+pub fn run_in_pty(cmdspec: &CommandSpec, stream: &mut TcpStream) -> io::Result<ExitStatus> {
+    run_in_pty_sized(cmdspec, stream, 24, 80).map(|exit| exit.status)
+}
+
+/// Like `run_in_pty`, with a PTY of `rows` x `cols`, also counting the
+/// bytes passed each way.
 #[cfg(unix)]
-pub fn run_in_pty(cmdspec: &CommandSpec, stream: &mut TcpStream) -> io::Result<()> {
+pub fn run_in_pty_sized(cmdspec: &CommandSpec, stream: &mut TcpStream, rows: u16, cols: u16) -> io::Result<PtyExit> {
     // Convert CommandSpec into command line
     let program = &cmdspec.argv[0];
     let args = &cmdspec.argv[1..];
@@ -34,138 +64,234 @@ pub fn run_in_pty(cmdspec: &CommandSpec, stream: &mut TcpStream) -> io::Result<(
     // Create a new PTY with default settings
     let pty = openpty(
         Some(&Winsize {
-            ws_row: 24,
-            ws_col: 80,
+            ws_row: rows,
+            ws_col: cols,
             ws_xpixel: 0,
             ws_ypixel: 0,
         }),
         None, // no special termios here
-    ).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    ).map_err(io::Error::other)?;
 
     // pty.master and pty.slave are now `OwnedFd`s in nix 0.29+
     // Configure termios if needed
     {
-        let mut term = termios::tcgetattr(&pty.slave)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let term = termios::tcgetattr(&pty.slave)
+            .map_err(io::Error::other)?;
         // Adjust any terminal modes if you want
         termios::tcsetattr(&pty.slave, termios::SetArg::TCSANOW, &term)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            .map_err(io::Error::other)?;
+    }
+
+    // Neither end may leak into the child past exec; it gets the slave
+    // only as its stdio and controlling terminal.
+    for fd in [pty.master.as_raw_fd(), pty.slave.as_raw_fd()] {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
     }
 
     // Spawn child with slave as stdio
     let mut child_cmd = Command::new(program);
     child_cmd.args(args);
+    cmdspec.configure(&mut child_cmd);
 
-    // If you want to honor < > redirects for "interactive" commands, do so.
-    // But many interactive programs ignore them. Example:
+    // Redirections replace the terminal on their stream. The copies of
+    // the slave are made close-on-exec (F_DUPFD_CLOEXEC) so a child another
+    // session spawns meanwhile cannot inherit them and keep this PTY from
+    // hanging up.
     if let Some(ref infile) = cmdspec.redirect_in {
-        child_cmd.stdin(File::open(infile)?);
+        child_cmd.stdin(File::open(cmdspec.path(infile))?);
     } else {
-        // Convert the `OwnedFd` into a File for stdin
-        // We do "into_raw_fd()" because we only want one copy for child stdin.
-        let slave_fd = pty.slave.as_raw_fd();
-        // SAFE: We transfer ownership to Stdio
-        let slave_file = unsafe { File::from_raw_fd(dup(slave_fd)?) };
-        child_cmd.stdin(slave_file);
+        child_cmd.stdin(File::from(pty.slave.try_clone()?));
     }
 
     if let Some(ref outfile) = cmdspec.redirect_out {
-        child_cmd.stdout(File::create(outfile)?);
+        child_cmd.stdout(File::create(cmdspec.path(outfile))?);
     } else {
-        let slave_fd = pty.slave.as_raw_fd();
-        let slave_file = unsafe { File::from_raw_fd(dup(slave_fd)?) };
-        child_cmd.stdout(slave_file);
+        child_cmd.stdout(File::from(pty.slave.try_clone()?));
     }
 
     if let Some(ref errfile) = cmdspec.redirect_err {
-        child_cmd.stderr(File::create(errfile)?);
+        child_cmd.stderr(File::create(cmdspec.path(errfile))?);
     } else {
-        let slave_fd = pty.slave.as_raw_fd();
-        let slave_file = unsafe { File::from_raw_fd(dup(slave_fd)?) };
-        child_cmd.stderr(slave_file);
+        child_cmd.stderr(File::from(pty.slave.try_clone()?));
     }
 
-    let child = child_cmd.spawn()?;
-
-    {
-        let mut guard = CURRENT_CHILD.lock().unwrap();
-        *guard = Some(child);
-    }
+    set_controlling_tty(&mut child_cmd, pty.slave.as_raw_fd());
+    let mut child = child_cmd.spawn()?;
+    let pid = child.id();
 
-    // Close the slave in the parent so we don't hold it open
-    // (The child has it already.)
-    // We just drop the `pty.slave` OwnedFd:
+    // Close every copy of the slave in the parent: the ones `child_cmd`
+    // holds for the child's stdio, and our own
+    drop(child_cmd);
     drop(pty.slave);
 
-    // Now set up bridging from pty.master <-> TCP stream
-
-    // We'll read from one cloned FD in one thread, and write to a second clone in another thread.
-    // That means we need two duplicates of the master fd.
-    let master_read_fd = dup(pty.master.as_raw_fd())?;
-    let master_write_fd = dup(pty.master.as_raw_fd())?;
-
-    // Convert them to File for convenient .read() and .write().
-    let mut master_for_read = unsafe { File::from_raw_fd(master_read_fd) };
-    let mut master_for_write = unsafe { File::from_raw_fd(master_write_fd) };
+    // Now set up bridging from pty.master <-> TCP stream. Both threads stop
+    // once the child has exited, leaving the connection to the shell.
+    let child_done = Arc::new(AtomicBool::new(false));
+    let mut master_for_read = File::from(pty.master.try_clone()?);
+    let mut master_for_write = File::from(pty.master.try_clone()?);
 
-    // Child => Network
+    // Child => Network: runs until the PTY reports EOF/EIO, or until the
+    // child is gone and nothing is left to read (a background job may
+    // still hold the slave open).
     let mut stream_writer = stream.try_clone()?;
-    thread::spawn(move || {
+    let done = Arc::clone(&child_done);
+    let output = thread::spawn(move || {
         let mut buf = [0u8; 1024];
+        let mut sent = 0u64;
         loop {
-            match master_for_read.read(&mut buf) {
-                Ok(0) => {
-                    // EOF from child
-                    let _ = stream_writer.shutdown(std::net::Shutdown::Write);
+            if !wait_readable(&master_for_read, POLL_MS) {
+                if done.load(Ordering::SeqCst) {
                     break;
                 }
+                continue;
+            }
+            match master_for_read.read(&mut buf) {
+                Ok(0) | Err(_) => break,
                 Ok(n) => {
                     if stream_writer.write_all(&buf[..n]).is_err() {
                         break;
                     }
+                    sent += n as u64;
                 }
-                Err(_) => break,
             }
         }
-        // optional close
-        let _ = close(master_read_fd);
+        let _ = stream_writer.flush();
+        sent
     });
 
-    // Network => Child
+    // Network => Child: stops reading as soon as the child exits so the
+    // next line goes to the shell. A dropped connection hangs up the child.
     let mut stream_reader = stream.try_clone()?;
-    thread::spawn(move || {
+    let done = Arc::clone(&child_done);
+    let input = thread::spawn(move || {
         let mut buf = [0u8; 1024];
+        let mut received = 0u64;
         loop {
+            let ready = wait_readable(&stream_reader, POLL_MS);
+            if done.load(Ordering::SeqCst) {
+                break;
+            }
+            if !ready {
+                continue;
+            }
             match stream_reader.read(&mut buf) {
-                Ok(0) => {
-                    // network closed
-                    let _ = close(master_write_fd);
+                Ok(0) | Err(_) => {
+                    info!("Connection dropped; hanging up PTY child {}", pid);
+                    hang_up(pid);
                     break;
                 }
                 Ok(n) => {
                     if master_for_write.write_all(&buf[..n]).is_err() {
                         break;
                     }
+                    received += n as u64;
                 }
-                Err(_) => break,
             }
         }
-        let _ = close(master_write_fd);
+        received
     });
 
-    // We can wait for the child or let the caller handle that
-    let status = {
-        let mut guard = CURRENT_CHILD.lock().unwrap();
-        if let Some(child) = guard.as_mut() {
-            child.wait()?
-        } else {
-            return Ok(());
+    let status = child.wait();
+    child_done.store(true, Ordering::SeqCst);
+    let bytes_in = input.join().unwrap_or(0);
+    let bytes_out = output.join().unwrap_or(0);
+    drop(pty.master);
+
+    let status = status?;
+    debug!("PTY child exited with: {} ({} bytes in, {} out)", status, bytes_in, bytes_out);
+    Ok(PtyExit { status, bytes_in, bytes_out })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener};
+
+    fn command(argv: &[&str], cwd: Option<&std::path::Path>) -> CommandSpec {
+        CommandSpec {
+            argv: argv.iter().map(|a| a.to_string()).collect(),
+            redirect_in: None,
+            redirect_out: None,
+            redirect_out_append: None,
+            redirect_err: None,
+            redirect_err_append: None,
+            cwd: cwd.map(|p| p.to_path_buf()),
+            env: None,
         }
-    };
-    eprintln!("(shell) PTY child exited with: {}", status);
+    }
 
-    // Also close the main pty.master if you like:
-    drop(pty.master);
+    /// Runs `cmdspec` in a PTY with `input` already sent; returns how it
+    /// ended and everything the client received.
+    fn run(cmdspec: &CommandSpec, input: &str) -> (PtyExit, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        client.write_all(input.as_bytes()).unwrap();
+        let exit = run_in_pty_sized(cmdspec, &mut stream, 30, 100).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        (exit, out)
+    }
+
+    #[test]
+    fn bridges_a_command_and_counts_bytes() {
+        let script = "stty size; [ -t 0 ] && [ -t 1 ] && echo tty; read line; echo got $line; exit 7";
+        let (exit, out) = run(&command(&["sh", "-c", script], None), "hello\n");
+        assert_eq!(exit.status.code(), Some(7));
+        // the terminal echoes typed-ahead input whenever it arrives
+        let mut lines: Vec<&str> = out.split_terminator("\r\n").collect();
+        lines.sort_unstable();
+        assert_eq!(lines, ["30 100", "got hello", "hello", "tty"], "{:?}", out);
+        assert_eq!(exit.bytes_in, 6);
+        assert_eq!(exit.bytes_out, out.len() as u64);
+    }
+
+    #[test]
+    fn redirections_bypass_the_terminal() {
+        let dir = std::env::temp_dir().join(format!("unix_pty_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("in.txt"), "from file\n").unwrap();
+        let mut cmdspec = command(&["sh", "-c", "read line; echo $line; [ -t 1 ] || echo piped >&2"], Some(&dir));
+        cmdspec.redirect_in = Some("in.txt".to_string());
+        cmdspec.redirect_out = Some("out.txt".to_string());
+        let (exit, out) = run(&cmdspec, "");
+        assert!(exit.status.success());
+        assert_eq!(out, "piped\r\n");
+        assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(), "from file\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-    Ok(())
+    #[test]
+    fn signals_are_reported() {
+        use std::os::unix::process::ExitStatusExt;
+        let (exit, _) = run(&command(&["sh", "-c", "kill -TERM $$"], None), "");
+        assert_eq!(exit.status.signal(), Some(15));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn the_parent_keeps_no_copy_of_the_slave() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let cmdspec = command(&["sh", "-c", "tty; sleep 1"], None);
+        let running = thread::spawn(move || run_in_pty_sized(&cmdspec, &mut stream, 24, 80).unwrap());
+
+        let mut name = Vec::new();
+        let mut byte = [0u8];
+        while client.read(&mut byte).unwrap() == 1 && byte[0] != b'\r' {
+            name.push(byte[0]);
+        }
+        let tty = std::path::PathBuf::from(String::from_utf8(name).unwrap());
+        assert!(tty.starts_with("/dev/pts"), "{}", tty.display());
+        let open: Vec<_> = std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .flatten()
+            .filter(|fd| std::fs::read_link(fd.path()).ok().as_ref() == Some(&tty))
+            .collect();
+        assert!(open.is_empty(), "{} still open in the parent", tty.display());
+        assert!(running.join().unwrap().status.success());
+    }
 }
@@ -199,7 +199,7 @@ pub fn run_in_pty(shell_path: &str, shell_args: &[&str], stream: &mut TcpStream)
             WaitForSingleObject(pi.hProcess, INFINITE);
             let mut code: u32 = 0;
             GetExitCodeProcess(pi.hProcess, &mut code);
-            eprintln!("(shell) ConPTY child exited with code: {}", code);
+            log::debug!("ConPTY child exited with code: {}", code);
 
             // Cleanup
             ClosePseudoConsole(hpc);
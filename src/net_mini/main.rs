@@ -0,0 +1,44 @@
+//! net_mini in one binary: listens like net_mini_listener or connects out
+//! like net_mini_client, following `--mode`, which defaults to the mode
+//! built in (CARGO_PKG_METADATA_PRECOMPILED_MODE).
+
+use std::io;
+
+use net_utils::cli::{Cli, Mode, Opt, MODE_OPT};
+use net_utils::net_mini::serve::{self, LISTEN_OPTS, SESSION_OPTS};
+use net_utils::reconnect::{ReconnectPolicy, RECONNECT_OPTS};
+
+fn main() -> io::Result<()> {
+    let matches = Cli::new("net_mini_shell", "serves a system shell in a PTY, listening or connecting out")
+        .opts(&[
+            MODE_OPT,
+            Opt::value(
+                "address",
+                "ADDR",
+                concat!(
+                    "Address to listen on (default 0.0.0.0:8080), or to connect to, with comma-separated fallbacks (default ",
+                    env!("CARGO_PKG_METADATA_PRECOMPILED_ADDRESS"),
+                    ")"
+                ),
+            )
+            .short('a')
+            .env("NET_MINI_ADDRESS"),
+            Opt::value("port", "PORT", "Port to listen on or connect to").short('p'),
+        ])
+        .opts(SESSION_OPTS)
+        .opts(LISTEN_OPTS)
+        .opts(RECONNECT_OPTS)
+        .parse_env_or_exit();
+    let mode = matches.get::<Mode>("mode").unwrap_or(Mode::Connect);
+    matches.init_logging(if mode == Mode::Bind { "(listener)" } else { "(client)" });
+
+    let options = serve::session_options(&matches);
+    match mode {
+        Mode::Bind => {
+            let address = matches.address_or(Mode::Bind.default_address());
+            let max_sessions = matches.get::<usize>("max-sessions").unwrap_or(1).max(1);
+            serve::listen(&address, max_sessions, &options)
+        }
+        Mode::Connect => serve::connect(&ReconnectPolicy::from_matches(&matches), &options),
+    }
+}
@@ -1,4 +1,5 @@
 pub mod net_mini_shell;
+pub mod serve;
 
 #[cfg(unix)]
 pub mod unix_pty;
@@ -1,21 +1,21 @@
-use std::net::TcpStream;
 use std::io;
-use std::env;
 
-use net_utils::net_mini::net_mini_shell::spawn_system_shell;
+use net_utils::cli::{Cli, Opt, PRECOMPILED_ADDRESS};
+use net_utils::net_mini::serve::{self, SESSION_OPTS};
+use net_utils::reconnect::{ReconnectPolicy, RECONNECT_OPTS};
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let address: String = env::var("CONNECT_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
-
-    eprintln!("(client) Connecting to {}", address);
-    let mut stream = TcpStream::connect(address)?;
-    eprintln!("(client) Connected. Spawning shell...");
-
-    match spawn_system_shell(&mut stream) {
-        Ok(_) => eprintln!("(client) Shell session ended."),
-        Err(e) => eprintln!("(client) Error: {}", e),
-    }
-
-    Ok(())
+    let matches = Cli::new("net_mini_client", "connects out and serves a system shell in a PTY")
+        .opts(&[
+            Opt::value("address", "ADDR", "Listener to connect to; comma-separated fallbacks")
+                .short('a')
+                .env("CONNECT_ADDRESS")
+                .default(PRECOMPILED_ADDRESS),
+            Opt::value("port", "PORT", "Connect to this port at every address").short('p'),
+        ])
+        .opts(SESSION_OPTS)
+        .opts(RECONNECT_OPTS)
+        .parse_env_or_exit();
+    matches.init_logging("(client)");
+    serve::connect(&ReconnectPolicy::from_matches(&matches), &serve::session_options(&matches))
 }
@@ -1,23 +1,19 @@
-use std::net::{TcpListener, TcpStream};
 use std::io;
-use std::env;
 
-use  net_utils::net_mini::net_mini_shell::spawn_system_shell;
+use net_utils::cli::{Cli, Opt, DEFAULT_LISTEN_ADDRESS};
+use net_utils::net_mini::serve::{self, LISTEN_OPTS, SESSION_OPTS};
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let address: String = env::var("LISTENER_ADDRESS").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-
-    eprintln!("(listener) Listening on {}", address);
-    let listener = TcpListener::bind(address)?;
-
-    loop {
-        let (mut stream, remote) = listener.accept()?;
-        eprintln!("(listener) Connection from {:?}", remote);
-
-        match spawn_system_shell(&mut stream) {
-            Ok(_) => eprintln!("(listener) Shell session ended."),
-            Err(e) => eprintln!("(listener) Error: {}", e),
-        }
-    }
+    let matches = Cli::new("net_mini_listener", "serves a system shell in a PTY to each connection")
+        .opts(&[
+            Opt::value("address", "ADDR", "Address to listen on").short('a').env("LISTENER_ADDRESS").default(DEFAULT_LISTEN_ADDRESS),
+            Opt::value("port", "PORT", "Listen on this port").short('p'),
+        ])
+        .opts(LISTEN_OPTS)
+        .opts(SESSION_OPTS)
+        .parse_env_or_exit();
+    matches.init_logging("(listener)");
+    let address = matches.address().unwrap_or_default();
+    let max_sessions = matches.get::<usize>("max-sessions").unwrap_or(1).max(1);
+    serve::listen(&address, max_sessions, &serve::session_options(&matches))
 }
@@ -1,36 +1,165 @@
-use std::io;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::net::TcpStream;
-use std::sync::Mutex;
-use lazy_static::lazy_static;
-use std::process::Child;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-lazy_static! {
-    pub static ref CURRENT_CHILD: Mutex<Option<Child>> = Mutex::new(None);
+use log::warn;
+
+use crate::audit::{self, AuditRecord};
+use crate::common::{find_in_path, is_executable};
+use crate::recording::Recorder;
+
+/// Shell to run, by path or by name on PATH; wins over `$SHELL`.
+pub const SHELL_ENV_VAR: &str = "NET_MINI_SHELL";
+/// Extra shell arguments, split on whitespace (`-l -i`, `--norc`).
+pub const SHELL_ARGS_ENV_VAR: &str = "NET_MINI_SHELL_ARGS";
+/// `NAME=VALUE` overrides for the shell's environment, split on
+/// whitespace (`TERM=xterm-256color LANG=C.UTF-8`).
+pub const SHELL_ENV_OVERRIDES_VAR: &str = "NET_MINI_SHELL_ENV";
+
+/// How long to wait for the rest of a handshake line whose first bytes
+/// have arrived. Connections that start any other way are not delayed.
+const HANDSHAKE_WAIT: Duration = Duration::from_millis(300);
+/// How often to look for the rest of a partial handshake line.
+const HANDSHAKE_POLL: Duration = Duration::from_millis(10);
+
+/// Settings for one connection's shell. Each session owns its PTY and
+/// child, so any number can run side by side.
+#[derive(Debug, Clone, Default)]
+pub struct SessionOptions {
+    /// Hang up the shell after this long without traffic either way.
+    pub idle_timeout: Option<Duration>,
+    /// Shell to try first, by path or by name on PATH.
+    pub shell: Option<String>,
+    /// Arguments passed to the shell.
+    pub shell_args: Vec<String>,
+    /// Variables set in the shell's environment, such as `TERM`.
+    pub env: Vec<(String, String)>,
+    /// Where to record the session, if anywhere.
+    pub recorder: Option<Arc<Recorder>>,
+    /// Bytes relayed so far; `spawn_system_shell_with` starts each session
+    /// with fresh counters.
+    pub traffic: Arc<Traffic>,
+}
+
+/// Bytes relayed between a connection and its shell.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    /// From the connection to the shell.
+    pub bytes_in: AtomicU64,
+    /// From the shell to the connection.
+    pub bytes_out: AtomicU64,
+}
+
+impl SessionOptions {
+    /// Shell, arguments and environment overrides from `NET_MINI_SHELL`,
+    /// `NET_MINI_SHELL_ARGS` and `NET_MINI_SHELL_ENV`.
+    pub fn from_env() -> Self {
+        let shell = env::var(SHELL_ENV_VAR).ok().filter(|s| !s.is_empty());
+        let shell_args = env::var(SHELL_ARGS_ENV_VAR)
+            .map(|v| v.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        let env = env::var(SHELL_ENV_OVERRIDES_VAR)
+            .map(|v| v.split_whitespace().filter_map(parse_assignment).collect())
+            .unwrap_or_default();
+        SessionOptions { idle_timeout: None, shell, shell_args, env, recorder: None, traffic: Arc::default() }
+    }
 }
 
-pub fn set_current_child(child: Child) {
-    let mut guard = CURRENT_CHILD.lock().unwrap();
-    *guard = Some(child);
+fn parse_assignment(word: &str) -> Option<(String, String)> {
+    let (name, value) = word.split_once('=')?;
+    (!name.is_empty()).then(|| (name.to_string(), value.to_string()))
 }
 
+/// Shells to try, best first: the configured or requested one, `$SHELL`,
+/// the user's passwd entry, then the usual system shells.
 #[cfg(unix)]
-fn candidate_shells() -> Vec<&'static str> {
-    vec!["/bin/zsh", "/bin/bash", "/bin/sh", "/bin/ksh"]
+fn candidate_shells(options: &SessionOptions) -> Vec<String> {
+    let mut shells: Vec<String> = Vec::new();
+    shells.extend(options.shell.clone());
+    shells.extend(env::var("SHELL").ok().filter(|s| !s.is_empty()));
+    if let Ok(Some(user)) = nix::unistd::User::from_uid(nix::unistd::getuid()) {
+        shells.push(user.shell.to_string_lossy().into_owned());
+    }
+    shells.extend(["/bin/zsh", "/bin/bash", "/bin/sh", "/bin/ksh"].map(String::from));
+    dedup_shells(shells)
 }
 
 #[cfg(windows)]
-fn candidate_shells() -> Vec<&'static str> {
-    vec!["powershell.exe", "cmd.exe"]
+fn candidate_shells(options: &SessionOptions) -> Vec<String> {
+    let mut shells: Vec<String> = Vec::new();
+    shells.extend(options.shell.clone());
+    shells.extend(["powershell.exe", "cmd.exe"].map(String::from));
+    dedup_shells(shells)
 }
 
-pub fn spawn_system_shell(stream: &mut TcpStream) -> io::Result<()> {
-    let shells = candidate_shells();
-
+/// Resolves names on PATH and drops missing and repeated shells.
+fn dedup_shells(shells: Vec<String>) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
     for shell in shells {
-        match try_spawn_shell(shell, stream) {
-            Ok(_) => return Ok(()),
+        let Some(path) = find_in_path(&shell, false).into_iter().next() else {
+            continue;
+        };
+        let path = path.to_string_lossy().into_owned();
+        if !found.contains(&path) {
+            found.push(path);
+        }
+    }
+    found
+}
+
+/// Runs the first shell that starts, bridged to `stream`, and returns its
+/// exit status.
+pub fn spawn_system_shell(stream: &mut TcpStream) -> io::Result<ExitStatus> {
+    spawn_system_shell_with(stream, &SessionOptions::from_env())
+}
+
+/// Like `spawn_system_shell`, with per-session `options`. Handshake lines
+/// from the client may adjust them first (see `read_handshake`). Each
+/// shell started is audited (see `audit`).
+pub fn spawn_system_shell_with(stream: &mut TcpStream, options: &SessionOptions) -> io::Result<ExitStatus> {
+    let mut options = options.clone();
+    options.traffic = Arc::default();
+    read_handshake(stream, &mut options)?;
+
+    let shells = candidate_shells(&options);
+    let args: Vec<&str> = options.shell_args.iter().map(String::as_str).collect();
+    let peer = stream.peer_addr().ok().map(|a| a.to_string());
+
+    for shell in &shells {
+        let argv: Vec<String> = std::iter::once(shell.clone()).chain(options.shell_args.iter().cloned()).collect();
+        let cwd = env::current_dir().ok();
+        audit::record(AuditRecord {
+            peer: peer.clone(),
+            argv: vec![argv.clone()],
+            cwd: cwd.clone(),
+            ..AuditRecord::new("net_mini", "session_start")
+        });
+        let started = Instant::now();
+        let result = try_spawn_shell(shell, &args, stream, &options);
+        let bytes_in = options.traffic.bytes_in.swap(0, Ordering::Relaxed);
+        audit::record(AuditRecord {
+            peer: peer.clone(),
+            argv: vec![argv],
+            cwd,
+            status: result.as_ref().ok().map(exit_code),
+            duration: Some(started.elapsed()),
+            bytes_in: Some(bytes_in),
+            bytes_out: Some(options.traffic.bytes_out.swap(0, Ordering::Relaxed)),
+            ..AuditRecord::new("net_mini", "session_end")
+        });
+        match result {
+            Ok(status) if could_not_exec(&status) && bytes_in == 0 => {
+                warn!("Shell `{}` could not run ({}); trying the next one", shell, status);
+            }
+            Ok(status) => return Ok(status),
             Err(e) => {
-                eprintln!("Failed to spawn shell `{}`: {}", shell, e);
+                warn!("Failed to spawn shell `{}`: {}", shell, e);
             }
         }
     }
@@ -38,12 +167,204 @@ pub fn spawn_system_shell(stream: &mut TcpStream) -> io::Result<()> {
     Err(io::Error::new(io::ErrorKind::NotFound, "No shell found"))
 }
 
+/// True for the statuses of a program that could not be executed (126) or
+/// found (127), as wrappers like `env` and `sh -c` report them. Only
+/// those, before the client has sent anything, make the next shell be
+/// tried; any other exit ends the session as the shell left it.
+fn could_not_exec(status: &ExitStatus) -> bool {
+    matches!(status.code(), Some(126 | 127))
+}
+
+/// The exit code, or 128 + the signal number for a shell killed by one.
+fn exit_code(status: &ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(sig) = status.signal() {
+            return 128 + sig;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Applies the optional handshake a client sends right after connecting,
+/// one line each, before any shell input:
+///
+/// - `SHELL <shell> [args...]` asks for a shell (it must be listed in
+///   `/etc/shells` where that file exists) and, if given, its arguments;
+/// - `ENV <NAME>=<VALUE>` sets a variable in the shell's environment.
+///
+/// Only lines already arriving are read: a connection whose first bytes
+/// are not the start of one goes straight to the shell. Anything else is
+/// left unread for the shell.
+pub fn read_handshake(stream: &mut TcpStream, options: &mut SessionOptions) -> io::Result<()> {
+    while let Some(line) = take_handshake_line(stream)? {
+        if let Some(rest) = line.strip_prefix("SHELL ") {
+            let mut words = rest.split_whitespace();
+            let Some(shell) = words.next() else {
+                continue;
+            };
+            if !is_login_shell(shell) {
+                warn!("Client asked for shell `{}`, which is not allowed", shell);
+                writeln!(stream, "[warn] shell {} is not allowed\r", shell)?;
+                continue;
+            }
+            options.shell = Some(shell.to_string());
+            let args: Vec<String> = words.map(String::from).collect();
+            if !args.is_empty() {
+                options.shell_args = args;
+            }
+        } else if let Some(assignment) = line.strip_prefix("ENV ").and_then(parse_assignment) {
+            options.env.retain(|(name, _)| *name != assignment.0);
+            options.env.push(assignment);
+        }
+    }
+    Ok(())
+}
+
+/// Consumes the next line if it is a `SHELL` or `ENV` line. Waits up to
+/// `HANDSHAKE_WAIT` for the rest of one only once its first bytes are in.
+fn take_handshake_line(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut buf = [0u8; 1024];
+    let deadline = Instant::now() + HANDSHAKE_WAIT;
+    loop {
+        let peeked = peek_now(stream, &mut buf)?;
+        let data = &buf[..peeked];
+        if !could_be_handshake(data) {
+            return Ok(None);
+        }
+        if let Some(end) = data.iter().position(|&b| b == b'\n') {
+            let mut line = vec![0u8; end + 1];
+            stream.read_exact(&mut line)?;
+            return Ok(Some(String::from_utf8_lossy(&line).trim_end().to_string()));
+        }
+        if data.len() == buf.len() || Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(HANDSHAKE_POLL);
+    }
+}
+
+/// Peeks at whatever has already arrived, without waiting for more.
+fn peek_now(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    stream.set_nonblocking(true)?;
+    let peeked = stream.peek(buf);
+    stream.set_nonblocking(false)?;
+    match peeked {
+        Ok(n) => Ok(n),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// True if `data` is, or may yet become, a `SHELL` or `ENV` line.
+fn could_be_handshake(data: &[u8]) -> bool {
+    !data.is_empty()
+        && [&b"SHELL "[..], b"ENV "]
+            .iter()
+            .any(|magic| data.starts_with(magic) || magic.starts_with(data))
+}
+
+/// True if `shell` resolves to a program listed in `/etc/shells`, or to
+/// any executable where there is no such file.
+fn is_login_shell(shell: &str) -> bool {
+    let Some(path) = find_in_path(shell, false).into_iter().next() else {
+        return false;
+    };
+    match fs::read_to_string("/etc/shells") {
+        Ok(list) => list.lines().map(str::trim).any(|l| Some(l) == path.to_str()),
+        Err(_) => is_executable(&path),
+    }
+}
+
 #[cfg(unix)]
-fn try_spawn_shell(shell_path: &str, stream: &mut TcpStream) -> io::Result<()> {
-    crate::net_mini::unix_pty::run_in_pty(shell_path, &[], stream)
+fn try_spawn_shell(shell_path: &str, args: &[&str], stream: &mut TcpStream, options: &SessionOptions) -> io::Result<ExitStatus> {
+    crate::net_mini::unix_pty::run_in_pty(shell_path, args, stream, options)
 }
 
 #[cfg(windows)]
-fn try_spawn_shell(shell_path: &str, stream: &mut TcpStream) -> io::Result<()> {
-    crate::net_mini::win_pty::run_in_pty(shell_path, &[], stream)
+fn try_spawn_shell(shell_path: &str, args: &[&str], stream: &mut TcpStream, options: &SessionOptions) -> io::Result<ExitStatus> {
+    crate::net_mini::win_pty::run_in_pty(shell_path, args, stream, options)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    /// Both ends of a fresh loopback connection: (client, server).
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    /// An executable `sh` script named `name` in a scratch directory.
+    fn script(name: &str, body: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("net_mini_shell_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    /// Serves `shell` to a client that, once it sees any output, asks
+    /// whichever shell is running to `exit 5`.
+    fn run_shell(shell: &Path) -> ExitStatus {
+        let (mut client, mut server) = connection();
+        thread::spawn(move || {
+            let mut byte = [0u8; 1];
+            if client.read(&mut byte).is_ok_and(|n| n > 0) {
+                let _ = client.write_all(b"exit 5\n");
+                let _ = io::copy(&mut client, &mut io::sink());
+            }
+        });
+        let options = SessionOptions { shell: Some(shell.to_string_lossy().into_owned()), ..SessionOptions::default() };
+        spawn_system_shell_with(&mut server, &options).unwrap()
+    }
+
+    #[test]
+    fn connections_without_a_handshake_are_not_delayed() {
+        let (_client, mut server) = connection();
+        let started = Instant::now();
+        let mut options = SessionOptions::default();
+        read_handshake(&mut server, &mut options).unwrap();
+        assert!(started.elapsed() < HANDSHAKE_WAIT, "{:?}", started.elapsed());
+        assert!(options.env.is_empty());
+    }
+
+    #[test]
+    fn handshake_lines_are_applied_and_the_rest_left_for_the_shell() {
+        let (mut client, mut server) = connection();
+        client.write_all(b"EN").unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            client.write_all(b"V TERM=dumb\necho hi\n").unwrap();
+            client
+        });
+        let mut options = SessionOptions::default();
+        read_handshake(&mut server, &mut options).unwrap();
+        assert_eq!(options.env, [("TERM".to_string(), "dumb".to_string())]);
+
+        let _client = writer.join().unwrap();
+        let mut rest = [0u8; 8];
+        server.read_exact(&mut rest).unwrap();
+        assert_eq!(&rest, b"echo hi\n");
+    }
+
+    #[test]
+    fn a_shell_that_exits_with_an_error_is_not_replaced() {
+        let status = run_shell(&script("exit1", "exit 1"));
+        assert_eq!(status.code(), Some(1));
+    }
+
+    #[test]
+    fn a_shell_that_cannot_exec_is_replaced_by_the_next() {
+        let status = run_shell(&script("exit127", "exit 127"));
+        assert_eq!(status.code(), Some(5));
+    }
 }
@@ -0,0 +1,106 @@
+// src/net_mini/serve.rs
+//! The two ways net_mini meets the operator: listening for connections
+//! (net_mini_listener) or connecting out (net_mini_client). net_mini_shell
+//! does either, as its mode says.
+
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::addr;
+use crate::cli::{Matches, Opt};
+use crate::net_mini::net_mini_shell::{spawn_system_shell_with, SessionOptions};
+use crate::reconnect::ReconnectPolicy;
+use crate::recording::Recorder;
+
+/// Options of every session, in either mode.
+pub const SESSION_OPTS: &[Opt] = &[Opt::value("idle-timeout", "SECS", "Hang up a session idle this long; 0 for never")
+    .env("SESSION_IDLE_TIMEOUT")
+    .default("0")];
+
+/// Options of listening mode.
+pub const LISTEN_OPTS: &[Opt] = &[Opt::value("max-sessions", "N", "Sessions served at once").env("MAX_SESSIONS").default("16")];
+
+/// Session settings from `SESSION_OPTS`, the rest from the environment.
+pub fn session_options(matches: &Matches) -> SessionOptions {
+    SessionOptions {
+        idle_timeout: matches.get("idle-timeout").filter(|&secs| secs > 0).map(Duration::from_secs),
+        ..SessionOptions::from_env()
+    }
+}
+
+/// Holds one of the `max_sessions` slots; gives it back when dropped.
+struct SessionSlot(Arc<AtomicUsize>);
+
+impl SessionSlot {
+    fn acquire(active: &Arc<AtomicUsize>, max: usize) -> Option<SessionSlot> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < max).then_some(n + 1))
+            .ok()
+            .map(|_| SessionSlot(Arc::clone(active)))
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Listens on `address` and serves each connection its own shell, up to
+/// `max_sessions` at once. Runs until accepting fails for good.
+pub fn listen(address: &str, max_sessions: usize, options: &SessionOptions) -> io::Result<()> {
+    let listener = addr::bind(address)?;
+    // The bound address, so that port 0 shows the port picked
+    let bound = listener.local_addr().map_or_else(|_| address.to_string(), |a| a.to_string());
+    info!("Listening on {} (up to {} sessions)", bound, max_sessions);
+    let active = Arc::new(AtomicUsize::new(0));
+
+    loop {
+        let (mut stream, remote) = match addr::accept(&listener) {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Accept failed: {}", e);
+                continue;
+            }
+        };
+        info!("Connection from {:?}", remote);
+
+        let Some(slot) = SessionSlot::acquire(&active, max_sessions) else {
+            warn!("{}: refused, {} sessions already running", remote, max_sessions);
+            let _ = stream.write_all(b"Too many sessions; try again later.\r\n");
+            continue;
+        };
+
+        let mut options = options.clone();
+        // Each session gets its own recording when RECORD_DIR is set
+        options.recorder = Recorder::from_env("net_mini_listener", &remote.to_string(), 80, 24);
+        thread::spawn(move || {
+            let _slot = slot;
+            serve(&mut stream, &options, remote.to_string());
+        });
+    }
+}
+
+/// Connects out as `policy` says and serves a shell on each connection.
+pub fn connect(policy: &ReconnectPolicy, options: &SessionOptions) -> io::Result<()> {
+    policy.run(|mut stream| {
+        info!("Connected. Spawning shell...");
+        let status = spawn_system_shell_with(&mut stream, options)?;
+        info!("Shell session ended ({}).", status);
+        Ok(())
+    })
+}
+
+/// Runs one connection's shell on its own thread.
+fn serve(stream: &mut TcpStream, options: &SessionOptions, remote: String) {
+    match spawn_system_shell_with(stream, options) {
+        Ok(status) => info!("{}: Shell session ended ({}).", remote, status),
+        Err(e) => error!("{}: Error: {}", remote, e),
+    }
+}
@@ -3,27 +3,56 @@ use std::io::{self, Read, Write};
 #[cfg(unix)]
 use std::net::TcpStream;
 #[cfg(unix)]
-use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::fd::AsRawFd;
 #[cfg(unix)]
 use std::fs::File;
 #[cfg(unix)]
-use std::process::{Command, Stdio};
+use std::process::Command;
+#[cfg(unix)]
+use std::process::ExitStatus;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::{Arc, Mutex};
 #[cfg(unix)]
 use std::thread;
+#[cfg(unix)]
+use std::time::{Duration, Instant};
 
+#[cfg(unix)]
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
 #[cfg(unix)]
 use nix::pty::{openpty, Winsize};
 #[cfg(unix)]
 use nix::sys::termios;
+
+#[cfg(unix)]
+use crate::common::{hang_up, kill_group, set_controlling_tty, wait_readable};
 #[cfg(unix)]
-use nix::unistd::{dup, close};
+use crate::net_mini::net_mini_shell::SessionOptions;
 
+/// How long the bridge threads wait for data before checking whether the
+/// shell has exited.
 #[cfg(unix)]
-use crate::net_mini::net_mini_shell::set_current_child;
+const POLL_MS: u16 = 100;
 
+/// How long a shell that was hung up has to exit before its process group
+/// is killed.
+#[cfg(unix)]
+const HANGUP_GRACE: Duration = Duration::from_secs(2);
 
+/// Runs `shell_path` in a new PTY bridged to `stream`, and returns once the
+/// shell has exited and both directions of the bridge are done. The shell
+/// is hung up when the connection closes or stays idle past
+/// `options.idle_timeout`, and killed with its process group if it is
+/// still running `HANGUP_GRACE` later.
 #[cfg(unix)]
-pub fn run_in_pty(shell_path: &str, shell_args: &[&str], stream: &mut TcpStream) -> io::Result<()> {
+pub fn run_in_pty(
+    shell_path: &str,
+    shell_args: &[&str],
+    stream: &mut TcpStream,
+    options: &SessionOptions,
+) -> io::Result<ExitStatus> {
     let pty = openpty(
         Some(&Winsize {
             ws_row: 24,
@@ -32,82 +61,172 @@ pub fn run_in_pty(shell_path: &str, shell_args: &[&str], stream: &mut TcpStream)
             ws_ypixel: 0,
         }),
         None,
-    ).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    ).map_err(io::Error::other)?;
 
     // (pty.master, pty.slave) are OwnedFd in nix 0.29+
     // Optionally set up termios on pty.slave
     {
-        let mut term = termios::tcgetattr(&pty.slave)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let term = termios::tcgetattr(&pty.slave)
+            .map_err(io::Error::other)?;
         termios::tcsetattr(&pty.slave, termios::SetArg::TCSANOW, &term)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            .map_err(io::Error::other)?;
+    }
+
+    // Keep both ends out of the shell past exec; it gets the slave only as
+    // its stdio and controlling terminal.
+    for fd in [pty.master.as_raw_fd(), pty.slave.as_raw_fd()] {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
     }
 
     // Spawn the child
     let mut cmd = Command::new(shell_path);
     cmd.args(shell_args);
+    cmd.envs(options.env.iter().map(|(k, v)| (k, v)));
 
-    // Child stdin => slave
-    // We do a dup of pty.slave FD so we can wrap it in Stdio.
+    // The shell's stdin, stdout and stderr are copies of the slave, made
+    // close-on-exec (F_DUPFD_CLOEXEC) so a child another session spawns
+    // meanwhile cannot inherit them and keep this PTY from hanging up.
     let slave_fd = pty.slave.as_raw_fd();
-    let slave_in = unsafe { File::from_raw_fd(dup(slave_fd)?) };
-    cmd.stdin(slave_in);
-
-    // Child stdout => slave
-    let slave_out = unsafe { File::from_raw_fd(dup(slave_fd)?) };
-    cmd.stdout(slave_out);
-
-    // Child stderr => slave
-    let slave_err = unsafe { File::from_raw_fd(dup(slave_fd)?) };
-    cmd.stderr(slave_err);
-
-    let child = cmd.spawn()?;
-    set_current_child(child);
-
-    // Close slave in parent
+    cmd.stdin(File::from(pty.slave.try_clone()?));
+    cmd.stdout(File::from(pty.slave.try_clone()?));
+    cmd.stderr(File::from(pty.slave.try_clone()?));
+
+    // Session leader with the PTY as its controlling terminal, so job
+    // control and ^C work in the shell
+    set_controlling_tty(&mut cmd, slave_fd);
+    let mut child = cmd.spawn()?;
+    let pid = child.id();
+
+    // Close every copy of the slave in the parent: the ones `cmd` holds
+    // for the child's stdio, and our own
+    drop(cmd);
     drop(pty.slave);
 
-    // Bridge PTY master <--> TCP Stream
-    let master_read_fd = dup(pty.master.as_raw_fd())?;
-    let master_write_fd = dup(pty.master.as_raw_fd())?;
+    // Bridge PTY master <--> TCP Stream. Each thread owns its own copy of
+    // the master, closed when the thread ends.
+    let child_done = Arc::new(AtomicBool::new(false));
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+    let mut master_for_read = File::from(pty.master.try_clone()?);
+    let mut master_for_write = File::from(pty.master.try_clone()?);
+    drop(pty.master);
 
-    let mut master_for_read = unsafe { File::from_raw_fd(master_read_fd) };
-    let mut master_for_write = unsafe { File::from_raw_fd(master_write_fd) };
-
-    // 1) PTY => Network
+    // 1) PTY => Network, until the PTY reports EOF/EIO or the shell is gone
+    // and nothing is left to read (a background job may hold the slave)
     let mut stream_writer = stream.try_clone()?;
-    thread::spawn(move || {
+    let done = Arc::clone(&child_done);
+    let activity = Arc::clone(&last_activity);
+    let recorder = options.recorder.clone();
+    let traffic = Arc::clone(&options.traffic);
+    let output = thread::spawn(move || {
         let mut buf = [0u8; 1024];
-        while let Ok(n) = master_for_read.read(&mut buf) {
-            if n == 0 {
-                let _ = stream_writer.shutdown(std::net::Shutdown::Write);
-                break;
+        loop {
+            if !wait_readable(&master_for_read, POLL_MS) {
+                if done.load(Ordering::SeqCst) {
+                    break;
+                }
+                continue;
             }
-            if stream_writer.write_all(&buf[..n]).is_err() {
-                break;
+            match master_for_read.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if stream_writer.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                    traffic.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                    if let Some(recorder) = &recorder {
+                        recorder.output(&buf[..n]);
+                    }
+                    *activity.lock().unwrap() = Instant::now();
+                }
             }
         }
-        let _ = close(master_read_fd);
+        let _ = stream_writer.flush();
     });
 
-    // 2) Network => PTY
+    // 2) Network => PTY, until the connection closes or the shell exits
     let mut stream_reader = stream.try_clone()?;
-    thread::spawn(move || {
+    let done = Arc::clone(&child_done);
+    let activity = Arc::clone(&last_activity);
+    let idle_timeout = options.idle_timeout;
+    let recorder = options.recorder.clone();
+    let traffic = Arc::clone(&options.traffic);
+    let input = thread::spawn(move || {
         let mut buf = [0u8; 1024];
-        while let Ok(n) = stream_reader.read(&mut buf) {
-            if n == 0 {
-                // network closed
-                let _ = close(master_write_fd);
+        loop {
+            let ready = wait_readable(&stream_reader, POLL_MS);
+            if done.load(Ordering::SeqCst) {
                 break;
             }
-            if master_for_write.write_all(&buf[..n]).is_err() {
-                break;
+            if !ready {
+                let idle = activity.lock().unwrap().elapsed();
+                if idle_timeout.is_some_and(|limit| idle >= limit) {
+                    log::info!("Session idle for {}s; hanging up shell {}", idle.as_secs(), pid);
+                    let _ = stream_reader.shutdown(std::net::Shutdown::Both);
+                    hang_up_or_kill(pid, &done);
+                    break;
+                }
+                continue;
+            }
+            match stream_reader.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    // network closed: hang up the shell as a terminal would
+                    hang_up_or_kill(pid, &done);
+                    break;
+                }
+                Ok(n) => {
+                    if master_for_write.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                    traffic.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+                    if let Some(recorder) = &recorder {
+                        recorder.input(&buf[..n]);
+                    }
+                    *activity.lock().unwrap() = Instant::now();
+                }
             }
         }
-        let _ = close(master_write_fd);
     });
 
-    // If you want to wait on the child here, do so; or let the main code handle it
-    // (We skip waiting, so the shell runs until the network is closed).
-    Ok(())
+    // Reap the shell, then let both directions drain and finish
+    let status = child.wait();
+    child_done.store(true, Ordering::SeqCst);
+    let _ = input.join();
+    let _ = output.join();
+    status
+}
+
+/// Hangs up the shell `pid`; if it has not exited (`done`) within
+/// `HANGUP_GRACE`, kills its process group.
+#[cfg(unix)]
+fn hang_up_or_kill(pid: u32, done: &AtomicBool) {
+    hang_up(pid);
+    let deadline = Instant::now() + HANGUP_GRACE;
+    while !done.load(Ordering::SeqCst) {
+        if Instant::now() >= deadline {
+            log::warn!("Shell {} ignored the hangup; killing it", pid);
+            kill_group(pid);
+            return;
+        }
+        thread::sleep(Duration::from_millis(POLL_MS.into()));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn idle_shells_that_ignore_the_hangup_are_killed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let options = SessionOptions { idle_timeout: Some(Duration::from_millis(300)), ..SessionOptions::default() };
+
+        let started = Instant::now();
+        let status = run_in_pty("/bin/sh", &["-c", "trap '' HUP; sleep 30"], &mut stream, &options).unwrap();
+        assert_eq!(status.signal(), Some(9));
+        assert!(started.elapsed() < Duration::from_secs(10), "{:?}", started.elapsed());
+    }
 }
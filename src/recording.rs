@@ -0,0 +1,174 @@
+// src/recording.rs
+//! Session recording in asciicast v2 format: a JSON header line, then one
+//! `[time, "o" | "i", data]` line per output or input event. The terminal
+//! size is the one in the header; resizes are not recorded.
+//! Play recordings back with the `replay` binary (or asciinema).
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::common::json_string;
+
+/// Directory to record every session into; unset for no recording.
+pub const RECORD_DIR_ENV_VAR: &str = "RECORD_DIR";
+
+/// Writes one session's asciicast file; shared by the threads of a session.
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    file: BufWriter<File>,
+    start: Instant,
+    /// Trailing bytes of an unfinished UTF-8 sequence, for output and input.
+    pending_output: Vec<u8>,
+    pending_input: Vec<u8>,
+}
+
+impl Recorder {
+    /// Starts a recording of a `cols` x `rows` terminal at `path`.
+    pub fn create(path: &Path, cols: u16, rows: u16, title: &str) -> io::Result<Recorder> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let term = env::var("TERM").unwrap_or_else(|_| "xterm".to_string());
+        let shell = env::var("SHELL").unwrap_or_default();
+        writeln!(
+            file,
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}, \"title\": {}, \"env\": {{\"TERM\": {}, \"SHELL\": {}}}}}",
+            cols,
+            rows,
+            timestamp,
+            json_string(title),
+            json_string(&term),
+            json_string(&shell)
+        )?;
+        file.flush()?;
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            inner: Mutex::new(Inner {
+                file,
+                start: Instant::now(),
+                pending_output: Vec::new(),
+                pending_input: Vec::new(),
+            }),
+        })
+    }
+
+    /// A recording in `RECORD_DIR`, named after `tool`, the time and `peer`,
+    /// if that variable is set. Failures are reported and recording skipped.
+    pub fn from_env(tool: &str, peer: &str, cols: u16, rows: u16) -> Option<Arc<Recorder>> {
+        let dir = env::var_os(RECORD_DIR_ENV_VAR)?;
+        let dir = PathBuf::from(dir);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let peer_name: String = peer.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' }).collect();
+        let path = dir.join(format!("{}-{}-{}.cast", tool, timestamp, peer_name));
+        let title = format!("{} session with {}", tool, peer);
+        match fs::create_dir_all(&dir).and_then(|_| Recorder::create(&path, cols, rows, &title)) {
+            Ok(recorder) => {
+                log::info!("Recording session to {}", path.display());
+                Some(Arc::new(recorder))
+            }
+            Err(e) => {
+                log::error!("Cannot record to {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Where the recording is written.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records data shown on the terminal.
+    pub fn output(&self, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        let text = take_utf8(&mut inner.pending_output, data);
+        inner.event("o", &text);
+    }
+
+    /// Records data typed by the operator.
+    pub fn input(&self, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        let text = take_utf8(&mut inner.pending_input, data);
+        inner.event("i", &text);
+    }
+}
+
+impl Inner {
+    fn event(&mut self, kind: &str, data: &str) {
+        if data.is_empty() {
+            return;
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        // A failed write must not take the session down with it
+        let _ = writeln!(self.file, "[{:.6}, \"{}\", {}]", time, kind, json_string(data));
+        let _ = self.file.flush();
+    }
+}
+
+/// Appends `data` to `pending` and takes the longest prefix that is
+/// complete UTF-8, keeping a trailing partial character for next time.
+fn take_utf8(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let complete = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..complete]).into_owned();
+    pending.drain(..complete);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters_split_across_reads_are_kept_whole() {
+        let mut pending = Vec::new();
+        let smile = "😀".as_bytes();
+        assert_eq!(take_utf8(&mut pending, b"caf\xc3"), "caf");
+        assert_eq!(take_utf8(&mut pending, b"\xa9 "), "é ");
+        assert_eq!(take_utf8(&mut pending, &smile[..1]), "");
+        assert_eq!(take_utf8(&mut pending, &smile[1..3]), "");
+        assert_eq!(take_utf8(&mut pending, &smile[3..]), "😀");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced_not_held() {
+        let mut pending = Vec::new();
+        assert_eq!(take_utf8(&mut pending, b"a\xffb"), "a\u{fffd}b");
+        assert_eq!(take_utf8(&mut pending, b"\xc3("), "\u{fffd}(");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn events_are_asciicast_lines() {
+        let dir = env::temp_dir().join(format!("recording_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session.cast");
+        let recorder = Recorder::create(&path, 100, 30, "test \"session\"").unwrap();
+        recorder.output(b"hi\r\n\xe2\x82");
+        recorder.input(b"ls\n");
+        recorder.output(b"\xac");
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with(r#"{"version": 2, "width": 100, "height": 30, "#), "{}", lines[0]);
+        assert!(lines[0].contains(r#""title": "test \"session\"""#), "{}", lines[0]);
+        assert_eq!(lines.len(), 4, "{}", text);
+        assert!(lines[1].ends_with(r#", "o", "hi\r\n"]"#), "{}", lines[1]);
+        assert!(lines[2].ends_with(r#", "i", "ls\n"]"#), "{}", lines[2]);
+        assert!(lines[3].ends_with(r#", "o", "€"]"#), "{}", lines[3]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,177 @@
+//! Plays an asciicast v2 recording (from `RECORD_DIR`) back in the
+//! terminal: `replay [-s <speed>] [-i <max_idle>] <file.cast>`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use log::error;
+
+use net_utils::cli::{usage_error, Cli, Opt};
+
+struct Options {
+    /// 1.0 plays in real time, 2.0 twice as fast, ...
+    speed: f64,
+    /// Pauses longer than this are shortened to it.
+    max_idle: Option<f64>,
+    path: String,
+}
+
+fn parse_args() -> Options {
+    let matches = Cli::new("replay", "plays an asciicast v2 recording back in the terminal")
+        .positional("<file.cast>")
+        .opts(&[
+            Opt::value("speed", "FACTOR", "Playback speed; 2 plays twice as fast").short('s').default("1"),
+            Opt::value("idle", "SECS", "Shorten longer pauses to SECS").short('i'),
+        ])
+        .parse_env_or_exit();
+    matches.init_logging("(replay)");
+    let speed = matches.get("speed").filter(|&s: &f64| s > 0.0).unwrap_or_else(|| usage_error("the speed must be above 0"));
+    let max_idle = matches.get::<f64>("idle");
+    if max_idle.is_some_and(|s| s < 0.0) {
+        usage_error("the idle limit cannot be negative");
+    }
+    let path = match matches.positional() {
+        [path] => path.clone(),
+        [] => usage_error("no recording given"),
+        _ => usage_error("only one recording can be played"),
+    };
+    Options { speed, max_idle, path }
+}
+
+fn main() {
+    let options = parse_args();
+    if let Err(e) = replay(&options) {
+        error!("{}: {}", options.path, e);
+        process::exit(1);
+    }
+}
+
+fn replay(options: &Options) -> io::Result<()> {
+    let mut lines = BufReader::new(File::open(&options.path)?).lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    if !header.contains("\"version\": 2") && !header.contains("\"version\":2") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not an asciicast v2 recording"));
+    }
+
+    let mut stdout = io::stdout().lock();
+    let mut last = 0.0;
+    for line in lines {
+        let line = line?;
+        let Some((time, kind, data)) = parse_event(&line) else {
+            continue;
+        };
+        let mut pause = (time - last).max(0.0) / options.speed;
+        if let Some(max_idle) = options.max_idle {
+            pause = pause.min(max_idle);
+        }
+        last = time;
+        // Input is echoed in the output already; only output is shown
+        if kind != "o" {
+            continue;
+        }
+        if pause > 0.0 {
+            thread::sleep(Duration::from_secs_f64(pause));
+        }
+        stdout.write_all(data.as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// Splits `[<time>, "<kind>", "<data>"]`.
+fn parse_event(line: &str) -> Option<(f64, String, String)> {
+    let rest = line.trim().strip_prefix('[')?;
+    let (time, rest) = rest.split_once(',')?;
+    let time = time.trim().parse().ok()?;
+    let (kind, rest) = parse_string(rest.trim_start())?;
+    let rest = rest.trim_start().strip_prefix(',')?;
+    let (data, _) = parse_string(rest.trim_start())?;
+    Some((time, kind, data))
+}
+
+/// Parses a JSON string literal at the start of `s`, returning it and
+/// what follows.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut out = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 2..])),
+            '\\' => {
+                let (_, escaped) = chars.next()?;
+                match escaped {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let mut code = hex4(&mut chars)?;
+                        // A surrogate pair spells one character
+                        if (0xd800..0xdc00).contains(&code) {
+                            let (_, backslash) = chars.next()?;
+                            let (_, u) = chars.next()?;
+                            if backslash != '\\' || u != 'u' {
+                                return None;
+                            }
+                            let low = hex4(&mut chars)?;
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low.checked_sub(0xdc00)?);
+                        }
+                        out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    other => out.push(other),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+fn hex4(chars: &mut std::str::CharIndices) -> Option<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+        let (_, c) = chars.next()?;
+        code = code * 16 + c.to_digit(16)?;
+    }
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(time: f64, kind: &str, data: &str) -> Option<(f64, String, String)> {
+        Some((time, kind.to_string(), data.to_string()))
+    }
+
+    #[test]
+    fn parses_events() {
+        assert_eq!(parse_event(r#"[0.5, "o", "hi\r\n"]"#), event(0.5, "o", "hi\r\n"));
+        assert_eq!(parse_event(r#"  [1,"i","ls\n"]  "#), event(1.0, "i", "ls\n"));
+        assert_eq!(parse_event(r#"[2.25, "o", "say \"\\hi\"\t"]"#), event(2.25, "o", "say \"\\hi\"\t"));
+        assert_eq!(parse_event(r#"[3, "o", "caf\u00e9 \ud83d\ude00 \u001b[0m"]"#), event(3.0, "o", "café 😀 \u{1b}[0m"));
+        assert_eq!(parse_event(r#"[4, "o", "€ raw"]"#), event(4.0, "o", "€ raw"));
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        for line in [
+            "",
+            "{\"version\": 2}",
+            r#"0.5, "o", "hi"]"#,
+            r#"[soon, "o", "hi"]"#,
+            r#"[0.5, o, "hi"]"#,
+            r#"[0.5, "o"]"#,
+            r#"[0.5, "o", "unterminated]"#,
+            r#"[0.5, "o", "bad \u00zz escape"]"#,
+            r#"[0.5, "o", "lone \ud83d surrogate"]"#,
+            r#"[0.5, "o", "truncated \u00"#,
+        ] {
+            assert_eq!(parse_event(line), None, "{}", line);
+        }
+    }
+}
@@ -1,7 +1,13 @@
-use std::env;
 use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpStream;
 use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+
+use net_utils::addr;
+use net_utils::cli::{usage_error, with_port, Cli, Opt};
+use net_utils::reconnect::CONNECT_TIMEOUT_ENV_VAR;
 
 fn process_data(data: &[u8]) -> Vec<u8> {
     // if let Ok(text) = std::str::from_utf8(data) {
@@ -17,8 +23,8 @@ fn process_data(data: &[u8]) -> Vec<u8> {
 }
 
 
-fn handle_client(mut client: TcpStream, remote_addr: &str, show: bool) -> io::Result<()> {
-    let mut remote = TcpStream::connect(remote_addr)?;
+fn handle_client(client: TcpStream, remote_addr: &str, timeout: Duration, show: bool) -> io::Result<()> {
+    let remote = addr::connect(remote_addr, timeout)?;
     
     let client_to_remote = thread::spawn({
         let mut client = client.try_clone()?;
@@ -62,33 +68,48 @@ fn handle_client(mut client: TcpStream, remote_addr: &str, show: bool) -> io::Re
 }
 
 fn main() -> io::Result<()> {
-    // Usage: tcp_proxy [show] <local_addr> <remote_addr>
-
-    let args: Vec<String> = env::args().collect();
-
-    let (show, local_addr, remote_addr) = if args.len() == 4 && args[1] == "show" {
-        (true, args[2].clone(), args[3].clone())
-    } else if args.len() == 3 {
-        (false, args[1].clone(), args[2].clone())
-    } else {
-        eprintln!("Usage: {} [show] <local_addr> <remote_addr>", args[0]);
-        std::process::exit(1);
-    };
+    let matches = Cli::new("tcp_proxy", "forwards TCP connections to another address")
+        .positional("[[show] <local_addr> <remote_addr>]")
+        .opts(&[
+            Opt::value("address", "ADDR", "Address to listen on").short('a').env("PROXY_LISTEN_ADDRESS"),
+            Opt::value("port", "PORT", "Listen on this port").short('p'),
+            Opt::value("remote", "ADDR", "Address to forward to").short('r').env("PROXY_REMOTE_ADDRESS"),
+            Opt::switch("show", "Print the data passing through").short('s').env("PROXY_SHOW"),
+            Opt::value("connect-timeout", "MS", "Limit on connecting to the remote").env(CONNECT_TIMEOUT_ENV_VAR).default("10000"),
+        ])
+        .parse_env_or_exit();
+    matches.init_logging("(tcp_proxy)");
+    // The older `[show] <local_addr> <remote_addr>` form still works
+    let mut legacy = matches.positional().to_vec();
+    let legacy_show = legacy.len() == 3 && legacy[0] == "show";
+    if legacy_show {
+        legacy.remove(0);
+    }
+    if !legacy.is_empty() && legacy.len() != 2 {
+        usage_error("expected <local_addr> <remote_addr>");
+    }
+    let show = legacy_show || matches.is_set("show");
+    let mut local_addr = legacy.first().cloned().or_else(|| matches.value("address")).unwrap_or_else(|| usage_error("no address to listen on"));
+    if let Some(port) = matches.get("port") {
+        local_addr = with_port(&local_addr, port);
+    }
+    let remote_addr = legacy.get(1).cloned().or_else(|| matches.value("remote")).unwrap_or_else(|| usage_error("no remote address"));
+    let timeout = Duration::from_millis(matches.get("connect-timeout").filter(|&ms| ms > 0).unwrap_or(10_000));
 
-    let listener = TcpListener::bind(&local_addr)?;
-    println!("TCP proxy listening on {} forwarding to {}", local_addr, remote_addr);
+    let listener = addr::bind(&local_addr)?;
+    info!("TCP proxy listening on {} forwarding to {}", local_addr, remote_addr);
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 let remote_addr = remote_addr.clone();
                 thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, &remote_addr, show) {
-                        eprintln!("Connection error: {}", e);
+                    if let Err(e) = handle_client(stream, &remote_addr, timeout, show) {
+                        error!("Connection error: {}", e);
                     }
                 });
             }
             Err(e) => {
-                eprintln!("Accept error: {}", e);
+                error!("Accept error: {}", e);
             }
         }
     }
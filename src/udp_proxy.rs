@@ -1,8 +1,12 @@
-use std::env;
-use std::net::{UdpSocket, SocketAddr};
+use std::net::SocketAddr;
 use std::io;
 use std::time::Duration;
 
+use log::{error, info};
+
+use net_utils::addr;
+use net_utils::cli::{usage_error, with_port, Cli, Opt};
+
 fn process_data(data: &[u8]) -> Vec<u8> {
         // if let Ok(text) = std::str::from_utf8(data) {
     //     Replace "foo" with "bar" in the text.
@@ -18,21 +22,36 @@ fn process_data(data: &[u8]) -> Vec<u8> {
 }
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    // Usage: udp_proxy [show] <local_addr> <remote_addr>
-    let (show, local_addr, remote_addr_str) = if args.len() == 4 && args[1] == "show" {
-        (true, args[2].clone(), args[3].clone())
-    } else if args.len() == 3 {
-        (false, args[1].clone(), args[2].clone())
-    } else {
-        eprintln!("Usage: {} [show] <local_addr> <remote_addr>", args[0]);
-        std::process::exit(1);
-    };
+    let matches = Cli::new("udp_proxy", "forwards UDP datagrams to another address")
+        .positional("[[show] <local_addr> <remote_addr>]")
+        .opts(&[
+            Opt::value("address", "ADDR", "Address to listen on").short('a').env("PROXY_LISTEN_ADDRESS"),
+            Opt::value("port", "PORT", "Listen on this port").short('p'),
+            Opt::value("remote", "ADDR", "Address to forward to").short('r').env("PROXY_REMOTE_ADDRESS"),
+            Opt::switch("show", "Print the data passing through").short('s').env("PROXY_SHOW"),
+        ])
+        .parse_env_or_exit();
+    matches.init_logging("(udp_proxy)");
+    // The older `[show] <local_addr> <remote_addr>` form still works
+    let mut legacy = matches.positional().to_vec();
+    let legacy_show = legacy.len() == 3 && legacy[0] == "show";
+    if legacy_show {
+        legacy.remove(0);
+    }
+    if !legacy.is_empty() && legacy.len() != 2 {
+        usage_error("expected <local_addr> <remote_addr>");
+    }
+    let show = legacy_show || matches.is_set("show");
+    let mut local_addr = legacy.first().cloned().or_else(|| matches.value("address")).unwrap_or_else(|| usage_error("no address to listen on"));
+    if let Some(port) = matches.get("port") {
+        local_addr = with_port(&local_addr, port);
+    }
+    let remote_addr_str = legacy.get(1).cloned().or_else(|| matches.value("remote")).unwrap_or_else(|| usage_error("no remote address"));
 
-    let remote_addr: SocketAddr = remote_addr_str.parse().expect("Invalid remote address");
-    let socket = UdpSocket::bind(&local_addr)?;
+    let socket = addr::bind_udp(&local_addr)?;
+    let remote_addr = addr::udp_peer(&socket, &remote_addr_str)?;
     socket.set_read_timeout(Some(Duration::from_secs(1)))?;
-    println!("UDP proxy listening on {} forwarding to {}", local_addr, remote_addr);
+    info!("UDP proxy listening on {} forwarding to {} ({})", local_addr, remote_addr_str, remote_addr);
 
     let mut last_client: Option<SocketAddr> = None;
     let mut buffer = [0u8; 4096];
@@ -61,7 +80,7 @@ fn main() -> io::Result<()> {
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
             }
             Err(e) => {
-                eprintln!("Error receiving UDP packet: {}", e);
+                error!("Error receiving UDP packet: {}", e);
             }
         }
     }
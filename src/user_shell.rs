@@ -56,7 +56,7 @@ pub fn capture_until_prompt(stream: &mut impl Read) -> io::Result<String> {
 /// sends "SIGNAL SIGINT" or "SIGNAL SIGTSTP" over the TCP stream.
 pub fn setup_signal_handler(stream: &TcpStream) -> io::Result<()> {
     let mut stream_clone = stream.try_clone()?;
-    let mut signals = Signals::new(&[SIGINT, SIGTSTP])?;
+    let mut signals = Signals::new([SIGINT, SIGTSTP])?;
     thread::spawn(move || {
         for signal in signals.forever() {
             match signal {
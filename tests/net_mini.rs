@@ -0,0 +1,42 @@
+// tests/net_mini.rs
+//
+// Drives the net_mini_shell binary in bind mode over a real connection.
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+#[test]
+fn bind_mode_serves_a_shell() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_net_mini_shell"))
+        .args(["--mode", "bind", "--address", "127.0.0.1:0"])
+        .env("NET_MINI_SHELL", "/bin/sh")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // port 0 picks a free port; the log says which
+    let mut log = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    let address = loop {
+        line.clear();
+        assert!(log.read_line(&mut line).unwrap() > 0, "net_mini_shell exited before listening");
+        if let Some(rest) = line.split("Listening on ").nth(1) {
+            break rest.split_whitespace().next().unwrap().to_string();
+        }
+    };
+
+    let mut stream = TcpStream::connect(&address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"echo net_mini_$((6 * 7))\nexit\n").unwrap();
+    let mut out = Vec::new();
+    let read = stream.read_to_end(&mut out);
+    let _ = child.kill();
+    child.wait().unwrap();
+    read.unwrap();
+    let out = String::from_utf8_lossy(&out);
+    assert!(out.contains("net_mini_42\r\n"), "{:?}", out);
+}
@@ -0,0 +1,92 @@
+// tests/net_shell.rs
+//
+// Drives the net_shell binary over a real connection: it connects out to
+// a listener the test opens. These need net_shell's own shell, so they
+// run in partial_pty builds.
+#![cfg(feature = "partial_pty")]
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// A fresh, empty directory under the system temp dir.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("net_shell_it_{}_{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Starts net_shell in connect mode with `envs`, and returns it with its
+/// accepted connection.
+fn connect_shell(envs: &[(&str, &PathBuf)]) -> (Child, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_net_shell"))
+        .args(["--mode", "connect", "--address", &listener.local_addr().unwrap().to_string()])
+        .envs(envs.iter().map(|(k, v)| (*k, *v)))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    (child, stream)
+}
+
+/// Sends `script`, which must end the session, and returns all it
+/// printed. net_shell would reconnect, so it is stopped then.
+fn converse(mut child: Child, mut stream: TcpStream, script: &str) -> String {
+    stream.write_all(script.as_bytes()).unwrap();
+    let mut out = String::new();
+    let read = stream.read_to_string(&mut out);
+    let _ = child.kill();
+    child.wait().unwrap();
+    read.unwrap();
+    out
+}
+
+#[test]
+fn every_command_is_audited() {
+    let dir = scratch_dir("audit");
+    let log = dir.join("audit.log");
+    let (child, stream) = connect_shell(&[("AUDIT_LOG", &log)]);
+    let out = converse(child, stream, "alias ll='ls -l'\nunalias ll\nset -o pipefail\nf() { echo in f; }\nf\ntype f\nhash -r\nexit 3\n");
+    assert!(out.contains("in f"), "{}", out);
+
+    let commands: Vec<String> = fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .filter(|l| l.contains(r#""event": "command""#))
+        .map(String::from)
+        .collect();
+    let argv: Vec<&str> = commands
+        .iter()
+        .map(|l| {
+            let start = l.find(r#""argv": "#).unwrap() + r#""argv": "#.len();
+            let end = l[start..].find("]]").unwrap() + start + 2;
+            &l[start..end]
+        })
+        .collect();
+    assert_eq!(
+        argv,
+        [
+            r#"[["alias", "ll=ls -l"]]"#,
+            r#"[["unalias", "ll"]]"#,
+            r#"[["set", "-o", "pipefail"]]"#,
+            r#"[["echo", "in", "f"]]"#,
+            r#"[["f"]]"#,
+            r#"[["type", "f"]]"#,
+            r#"[["hash", "-r"]]"#,
+            r#"[["exit", "3"]]"#,
+        ]
+    );
+    assert!(commands.last().unwrap().contains(r#""status": 3"#));
+    // programs count their bytes; the shell's own builtins do not
+    assert!(commands[3].contains(r#""bytes_out": 5"#), "{}", commands[3]);
+    assert!(!commands[0].contains("bytes_out"), "{}", commands[0]);
+    let _ = fs::remove_dir_all(&dir);
+}